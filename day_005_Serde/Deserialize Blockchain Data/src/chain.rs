@@ -0,0 +1,901 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use blockchain_traits::fork_choice::{BlockTree, ForkChoice, work_for_difficulty};
+
+use crate::Block;
+use crate::params::ConsensusParams;
+use crate::spec::ConsensusConfig;
+use crate::timestamp::{Clock, SystemClock, TimestampRules};
+use crate::verify::{BreakReason, verify_block_within};
+
+/// State that follows the active chain, e.g. balances or a UTXO set.
+///
+/// `disconnect_block` must exactly undo `connect_block` for the same block,
+/// so reorgs can roll state back to the fork point and forward again.
+pub trait ChainState {
+    fn connect_block(&mut self, block: &Block) -> Result<(), String>;
+    fn disconnect_block(&mut self, block: &Block) -> Result<(), String>;
+}
+
+/// A chain with no state attached.
+impl ChainState for () {
+    fn connect_block(&mut self, _block: &Block) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn disconnect_block(&mut self, _block: &Block) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Two states following the same chain, e.g. a ledger and an explorer index.
+/// If one half fails, the other is put back, so the pair never ends up with
+/// only one of them moved; a failure to put it back is reported too.
+impl<A: ChainState, B: ChainState> ChainState for (A, B) {
+    fn connect_block(&mut self, block: &Block) -> Result<(), String> {
+        self.0.connect_block(block)?;
+        if let Err(e) = self.1.connect_block(block) {
+            if let Err(undo) = self.0.disconnect_block(block) {
+                return Err(format!("{}; rolling back also failed: {}", e, undo));
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn disconnect_block(&mut self, block: &Block) -> Result<(), String> {
+        self.1.disconnect_block(block)?;
+        if let Err(e) = self.0.disconnect_block(block) {
+            if let Err(redo) = self.1.connect_block(block) {
+                return Err(format!("{}; rolling back also failed: {}", e, redo));
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Emitted whenever the active chain changes. A plain extension of the tip is a
+/// reorg with nothing disconnected.
+#[derive(Debug, Clone)]
+pub struct Reorg {
+    /// Last block shared by the old and the new chain.
+    pub fork_point: String,
+    /// Blocks removed from the active chain, tip first.
+    pub disconnected: Vec<Block>,
+    /// Blocks added to the active chain, the child of the fork point first.
+    pub connected: Vec<Block>,
+}
+
+impl Reorg {
+    pub fn is_extension(&self) -> bool {
+        self.disconnected.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeError {
+    Duplicate(String),
+    UnknownParent(String),
+    Invalid(BreakReason),
+    /// The block was stored but `ChainState` rejected it when it was connected.
+    State(String),
+    /// Rolling back a failed reorg failed too, so state no longer matches the
+    /// active chain. Nothing more is accepted until `rebuild`.
+    NeedsRebuild(String),
+}
+
+impl fmt::Display for TreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TreeError::Duplicate(hash) => write!(f, "Block already known: {}", hash),
+            TreeError::UnknownParent(hash) => write!(f, "Unknown parent block: {}", hash),
+            TreeError::Invalid(reason) => write!(f, "Invalid block: {}", reason),
+            TreeError::State(msg) => write!(f, "State transition failed: {}", msg),
+            TreeError::NeedsRebuild(msg) => {
+                write!(f, "State no longer matches the active chain: {}", msg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TreeError {}
+
+/// Why `switch_to` gave up. Unless rolling back failed as well, state is back
+/// at the old tip.
+enum SwitchError {
+    /// A block on the new branch failed to connect.
+    Connect { hash: String, msg: String },
+    /// A block on the old branch failed to disconnect.
+    Disconnect(String),
+    /// One of the above, and putting state back failed too. `bad` is the block
+    /// that failed to connect, if that is how it started.
+    Rollback { bad: Option<String>, msg: String },
+}
+
+/// Every known block, including side branches, plus the active chain that
+/// `ChainState` currently reflects.
+pub struct ChainTree<S> {
+    index: BlockTree,
+    blocks: HashMap<String, Block>,
+    invalid: HashSet<String>,
+    active: Vec<String>,
+    rule: Box<dyn ForkChoice>,
+    state: S,
+    params: ConsensusParams,
+    consensus: ConsensusConfig,
+    rules: TimestampRules,
+    clock: Box<dyn Clock>,
+    /// Set once state stops matching `active`.
+    broken: Option<String>,
+}
+
+impl<S: ChainState> ChainTree<S> {
+    /// Every block, genesis included, is held to the limits in `params` and
+    /// the difficulty schedule of `consensus`; pass the ones from the chain's
+    /// spec. Timestamps of later blocks follow
+    /// the default `TimestampRules` against the system clock until
+    /// `with_timestamp_rules` says otherwise.
+    pub fn new(
+        mut genesis: Block,
+        rule: Box<dyn ForkChoice>,
+        mut state: S,
+        params: ConsensusParams,
+        consensus: ConsensusConfig,
+    ) -> Result<Self, TreeError> {
+        genesis.hash =
+            verify_block_within(&genesis, None, &params, &consensus).map_err(TreeError::Invalid)?;
+        state.connect_block(&genesis).map_err(TreeError::State)?;
+
+        Ok(ChainTree {
+            index: BlockTree::new(&genesis.hash, block_work(&genesis)),
+            active: vec![genesis.hash.clone()],
+            blocks: HashMap::from([(genesis.hash.clone(), genesis)]),
+            invalid: HashSet::new(),
+            rule,
+            state,
+            params,
+            consensus,
+            rules: TimestampRules::default(),
+            clock: Box::new(SystemClock),
+            broken: None,
+        })
+    }
+
+    /// Checks the timestamps of inserted blocks against `rules` and `clock`.
+    pub fn with_timestamp_rules(
+        mut self,
+        rules: TimestampRules,
+        clock: impl Clock + 'static,
+    ) -> Self {
+        self.rules = rules;
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Timestamps of `hash` and up to `count - 1` of its ancestors, oldest first.
+    pub fn recent_timestamps(&self, hash: &str, count: usize) -> Vec<u64> {
+        let mut timestamps = Vec::with_capacity(count);
+        let mut cursor = self.blocks.get(hash);
+        while let Some(block) = cursor {
+            if timestamps.len() == count {
+                break;
+            }
+            timestamps.push(block.timestamp);
+            cursor = self.blocks.get(&block.prev_hash);
+        }
+        timestamps.reverse();
+        timestamps
+    }
+
+    pub fn tip(&self) -> &Block {
+        &self.blocks[self.active.last().expect("active chain has genesis")]
+    }
+
+    pub fn height(&self) -> u32 {
+        self.tip().id
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    /// Active block at `height`.
+    pub fn block_at(&self, height: u32) -> Option<&Block> {
+        self.active
+            .get(height as usize)
+            .map(|hash| &self.blocks[hash])
+    }
+
+    pub fn is_active(&self, hash: &str) -> bool {
+        self.blocks
+            .get(hash)
+            .is_some_and(|b| self.active.get(b.id as usize).map(String::as_str) == Some(hash))
+    }
+
+    /// Blocks of the active chain from genesis to tip.
+    pub fn active_chain(&self) -> impl Iterator<Item = &Block> {
+        self.active.iter().map(|hash| &self.blocks[hash])
+    }
+
+    /// Heads of every branch, active or not.
+    pub fn tips(&self) -> Vec<&str> {
+        let mut tips: Vec<&str> = self
+            .index
+            .leaves_from(self.index.genesis())
+            .into_iter()
+            .map(|node| node.hash.as_str())
+            .collect();
+        tips.sort();
+        tips
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Why state no longer matches the active chain, if a failed rollback left
+    /// it that way.
+    pub fn needs_rebuild(&self) -> Option<&str> {
+        self.broken.as_deref()
+    }
+
+    /// Replaces state with `state` after connecting the active chain to it from
+    /// genesis, e.g. a fresh one after a failed rollback.
+    pub fn rebuild(&mut self, mut state: S) -> Result<(), TreeError> {
+        for hash in &self.active {
+            state
+                .connect_block(&self.blocks[hash])
+                .map_err(TreeError::State)?;
+        }
+        self.state = state;
+        self.broken = None;
+        Ok(())
+    }
+
+    /// Stores `block` and switches the active chain if the fork-choice rule now
+    /// prefers another head. Returns the resulting reorg, if any.
+    pub fn insert(&mut self, mut block: Block) -> Result<Option<Reorg>, TreeError> {
+        if let Some(msg) = &self.broken {
+            return Err(TreeError::NeedsRebuild(msg.clone()));
+        }
+        let parent = match self.blocks.get(&block.prev_hash) {
+            Some(parent) if !self.invalid.contains(&block.prev_hash) => parent,
+            _ => return Err(TreeError::UnknownParent(block.prev_hash.clone())),
+        };
+        let hash = verify_block_within(
+            &block,
+            Some((&parent.header(), &block.prev_hash)),
+            &self.params,
+            &self.consensus,
+        )
+        .map_err(TreeError::Invalid)?;
+        let ancestors = self.recent_timestamps(&block.prev_hash, self.rules.median_window);
+        self.rules
+            .check(block.timestamp, &ancestors, self.clock.as_ref())
+            .map_err(TreeError::Invalid)?;
+        if self.blocks.contains_key(&hash) {
+            return Err(TreeError::Duplicate(hash));
+        }
+
+        block.hash = hash.clone();
+        self.index
+            .insert(&hash, &block.prev_hash, block_work(&block))
+            .map_err(|_| TreeError::Duplicate(hash.clone()))?;
+        self.blocks.insert(hash.clone(), block);
+
+        loop {
+            let head = self.rule.head(&self.index);
+            if self.active.last() == Some(&head) {
+                return Ok(None);
+            }
+            match self.switch_to(&head) {
+                Ok(reorg) => return Ok(Some(reorg)),
+                Err(SwitchError::Connect { hash: bad, msg }) => {
+                    self.mark_invalid(&bad);
+                    if self.invalid.contains(&hash) {
+                        return Err(TreeError::State(msg));
+                    }
+                }
+                // Not the new branch's fault, so it stays a candidate.
+                Err(SwitchError::Disconnect(msg)) => return Err(TreeError::State(msg)),
+                Err(SwitchError::Rollback { bad, msg }) => {
+                    if let Some(bad) = bad {
+                        self.mark_invalid(&bad);
+                    }
+                    self.broken = Some(msg.clone());
+                    return Err(TreeError::NeedsRebuild(msg));
+                }
+            }
+        }
+    }
+
+    /// Moves state and the active chain to `head`. On a state error everything is
+    /// rolled back to the previous tip and the offending block is returned, or
+    /// `SwitchError::Rollback` if that failed too.
+    fn switch_to(&mut self, head: &str) -> Result<Reorg, SwitchError> {
+        let path = self
+            .index
+            .path(self.index.genesis(), head)
+            .expect("head descends from genesis");
+        let fork = path
+            .iter()
+            .zip(&self.active)
+            .take_while(|(new, old)| new == old)
+            .count();
+
+        let disconnected: Vec<String> = self.active[fork..].iter().rev().cloned().collect();
+        let connected: Vec<String> = path[fork..].to_vec();
+
+        for (i, hash) in disconnected.iter().enumerate() {
+            if let Err(msg) = self.state.disconnect_block(&self.blocks[hash]) {
+                let msg = format!("cannot disconnect active block {}: {}", hash, msg);
+                return Err(match self.roll_back(&[], &disconnected[..i]) {
+                    Ok(()) => SwitchError::Disconnect(msg),
+                    Err(undo) => SwitchError::Rollback {
+                        bad: None,
+                        msg: format!("{}; rolling back also failed: {}", msg, undo),
+                    },
+                });
+            }
+        }
+        for (i, hash) in connected.iter().enumerate() {
+            if let Err(msg) = self.state.connect_block(&self.blocks[hash]) {
+                return Err(match self.roll_back(&connected[..i], &disconnected) {
+                    Ok(()) => SwitchError::Connect {
+                        hash: hash.clone(),
+                        msg,
+                    },
+                    Err(undo) => SwitchError::Rollback {
+                        bad: Some(hash.clone()),
+                        msg: format!("{}; rolling back also failed: {}", msg, undo),
+                    },
+                });
+            }
+        }
+
+        self.active.truncate(fork);
+        self.active.extend(connected.iter().cloned());
+
+        let clone = |hash: &String| self.blocks[hash].clone();
+        Ok(Reorg {
+            fork_point: path[fork - 1].clone(),
+            disconnected: disconnected.iter().map(clone).collect(),
+            connected: connected.iter().map(clone).collect(),
+        })
+    }
+
+    /// Puts state back at the old tip: disconnects the `connected` blocks newest
+    /// first, then reconnects the `disconnected` ones (tip first, as they came
+    /// off) oldest first.
+    fn roll_back(&mut self, connected: &[String], disconnected: &[String]) -> Result<(), String> {
+        for hash in connected.iter().rev() {
+            self.state
+                .disconnect_block(&self.blocks[hash])
+                .map_err(|e| format!("cannot disconnect block {}: {}", hash, e))?;
+        }
+        for hash in disconnected.iter().rev() {
+            self.state
+                .connect_block(&self.blocks[hash])
+                .map_err(|e| format!("cannot reconnect block {}: {}", hash, e))?;
+        }
+        Ok(())
+    }
+
+    /// Drops `hash` and all its descendants from fork choice; they stay known
+    /// so they are not downloaded again.
+    fn mark_invalid(&mut self, hash: &str) {
+        let mut stack = vec![hash.to_string()];
+        while let Some(hash) = stack.pop() {
+            stack.extend(self.index.children(&hash).iter().cloned());
+            self.invalid.insert(hash);
+        }
+
+        let genesis = self.index.genesis().to_string();
+        let mut valid: Vec<&Block> = self
+            .blocks
+            .iter()
+            .filter(|(hash, _)| **hash != genesis && !self.invalid.contains(*hash))
+            .map(|(_, block)| block)
+            .collect();
+        valid.sort_by(|a, b| a.id.cmp(&b.id).then_with(|| a.hash.cmp(&b.hash)));
+
+        let mut index = BlockTree::new(&genesis, block_work(&self.blocks[&genesis]));
+        for block in valid {
+            index
+                .insert(&block.hash, &block.prev_hash, block_work(block))
+                .expect("parents of valid blocks are valid");
+        }
+        self.index = index;
+    }
+}
+
+/// Work fork choice credits `block` with.
+fn block_work(block: &Block) -> u128 {
+    work_for_difficulty(block.difficulty as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transaction;
+    use crate::timestamp::ManualClock;
+    use crate::verify::verify_chain_report_within;
+    use blockchain_traits::{HeaviestChain, LongestChain};
+
+    /// Sums transaction amounts along the active chain. Refuses to connect
+    /// amounts of 0 and to disconnect amounts of 13.
+    #[derive(Default)]
+    struct Total(u64);
+
+    impl ChainState for Total {
+        fn connect_block(&mut self, block: &Block) -> Result<(), String> {
+            if block.transactions.iter().any(|tx| tx.amount == 0) {
+                return Err(format!("zero amount in block {}", block.id));
+            }
+            self.0 += block
+                .transactions
+                .iter()
+                .map(|tx| tx.amount as u64)
+                .sum::<u64>();
+            Ok(())
+        }
+
+        fn disconnect_block(&mut self, block: &Block) -> Result<(), String> {
+            if block.transactions.iter().any(|tx| tx.amount == 13) {
+                return Err(format!("block {} is stuck", block.id));
+            }
+            self.0 -= block
+                .transactions
+                .iter()
+                .map(|tx| tx.amount as u64)
+                .sum::<u64>();
+            Ok(())
+        }
+    }
+
+    /// Counts connected blocks, failing whichever operations are refused.
+    #[derive(Default)]
+    struct Count {
+        blocks: u32,
+        refuse_connect: bool,
+        refuse_disconnect: bool,
+    }
+
+    impl ChainState for Count {
+        fn connect_block(&mut self, _block: &Block) -> Result<(), String> {
+            if self.refuse_connect {
+                return Err("connect refused".to_string());
+            }
+            self.blocks += 1;
+            Ok(())
+        }
+
+        fn disconnect_block(&mut self, _block: &Block) -> Result<(), String> {
+            if self.refuse_disconnect {
+                return Err("disconnect refused".to_string());
+            }
+            self.blocks -= 1;
+            Ok(())
+        }
+    }
+
+    fn genesis() -> Block {
+        Block::new(0, 1000, vec![tx(1, "Genesis")], "0".to_string())
+    }
+
+    fn tx(amount: u32, sender: &str) -> Transaction {
+        Transaction {
+            id: amount,
+            amount,
+            sender: sender.to_string(),
+            receiver: String::new(),
+            fee: 0,
+            nonce: 0,
+        }
+    }
+
+    fn child(parent: &Block, amount: u32, tag: &str) -> Block {
+        Block::new(
+            parent.id.checked_add(1).expect("test chains stay short"),
+            parent.timestamp + 1,
+            vec![tx(amount, tag)],
+            parent.hash.clone(),
+        )
+    }
+
+    /// Extends `from` by `len` blocks, each carrying `amount`. Returns the blocks
+    /// and every reorg their insertion caused.
+    fn branch(
+        tree: &mut ChainTree<Total>,
+        from: &Block,
+        len: usize,
+        amount: u32,
+        tag: &str,
+    ) -> (Vec<Block>, Vec<Reorg>) {
+        let mut blocks = Vec::new();
+        let mut reorgs = Vec::new();
+        let mut parent = from.clone();
+        for _ in 0..len {
+            let block = child(&parent, amount, tag);
+            reorgs.extend(tree.insert(block.clone()).unwrap());
+            parent = block.clone();
+            blocks.push(block);
+        }
+        (blocks, reorgs)
+    }
+
+    /// The one reorg that actually disconnected blocks. Equal-height branches are
+    /// decided by hash, so the switch may happen on the tie or one block later.
+    fn only_switch(reorgs: Vec<Reorg>) -> Reorg {
+        let mut switches: Vec<Reorg> = reorgs.into_iter().filter(|r| !r.is_extension()).collect();
+        assert_eq!(switches.len(), 1);
+        switches.remove(0)
+    }
+
+    fn expected_total(tree: &ChainTree<Total>) -> u64 {
+        tree.active_chain()
+            .flat_map(|b| &b.transactions)
+            .map(|tx| tx.amount as u64)
+            .sum()
+    }
+
+    #[test]
+    fn test_extension_and_side_branch() {
+        let genesis = genesis();
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(LongestChain),
+            Total::default(),
+            ConsensusParams::default(),
+            ConsensusConfig::default(),
+        )
+        .unwrap();
+
+        let a1 = child(&genesis, 10, "a");
+        let reorg = tree.insert(a1.clone()).unwrap().unwrap();
+        assert!(reorg.is_extension());
+        assert_eq!(reorg.connected[0].hash, a1.hash);
+
+        // A competing block at the same height is kept as a side branch.
+        let a2 = child(&a1, 10, "a");
+        tree.insert(a2.clone()).unwrap();
+        let b1 = child(&genesis, 20, "b");
+        assert!(tree.insert(b1.clone()).unwrap().is_none());
+        assert_eq!(tree.tips().len(), 2);
+        assert!(tree.get(&b1.hash).is_some());
+        assert!(!tree.is_active(&b1.hash));
+        assert_eq!(tree.tip().hash, a2.hash);
+        assert_eq!(tree.state().0, 21);
+
+        assert_eq!(
+            tree.insert(a1.clone()).unwrap_err(),
+            TreeError::Duplicate(a1.hash)
+        );
+    }
+
+    #[test]
+    fn test_shallow_reorg() {
+        let genesis = genesis();
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(LongestChain),
+            Total::default(),
+            ConsensusParams::default(),
+            ConsensusConfig::default(),
+        )
+        .unwrap();
+        let (main, _) = branch(&mut tree, &genesis, 3, 10, "main");
+
+        let (side, reorgs) = branch(&mut tree, &main[1], 2, 50, "side");
+        let reorg = only_switch(reorgs);
+
+        assert_eq!(reorg.fork_point, main[1].hash);
+        assert_eq!(reorg.disconnected.len(), 1);
+        assert_eq!(reorg.disconnected[0].hash, main[2].hash);
+        assert_eq!(reorg.connected[0].hash, side[0].hash);
+        assert_eq!(tree.tip().hash, side[1].hash);
+        assert!(!tree.is_active(&main[2].hash));
+        assert_eq!(tree.state().0, expected_total(&tree));
+    }
+
+    #[test]
+    fn test_deep_reorg() {
+        let genesis = genesis();
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(HeaviestChain),
+            Total::default(),
+            ConsensusParams::default(),
+            ConsensusConfig::default(),
+        )
+        .unwrap();
+        let (main, _) = branch(&mut tree, &genesis, 10, 10, "main");
+        assert_eq!(tree.state().0, 101);
+
+        let (side, reorgs) = branch(&mut tree, &main[1], 9, 7, "side");
+        let reorg = only_switch(reorgs);
+
+        assert_eq!(reorg.fork_point, main[1].hash);
+        let disconnected: Vec<&str> = reorg.disconnected.iter().map(|b| b.hash.as_str()).collect();
+        let expected: Vec<&str> = main[2..].iter().rev().map(|b| b.hash.as_str()).collect();
+        assert_eq!(disconnected, expected);
+        assert_eq!(reorg.connected[0].hash, side[0].hash);
+        assert_eq!(tree.height(), 11);
+        assert_eq!(tree.block_at(3).unwrap().hash, side[0].hash);
+        assert_eq!(tree.state().0, 1 + 20 + 9 * 7);
+        assert_eq!(tree.state().0, expected_total(&tree));
+    }
+
+    #[test]
+    fn test_invalid_branch_rolls_back() {
+        let genesis = genesis();
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(LongestChain),
+            Total::default(),
+            ConsensusParams::default(),
+            ConsensusConfig::default(),
+        )
+        .unwrap();
+        let (main, _) = branch(&mut tree, &genesis, 3, 10, "main");
+
+        let (side, _) = branch(&mut tree, &genesis, 2, 5, "side");
+        let bad = child(&side[1], 0, "side");
+        let after = child(&bad, 5, "side");
+        let results = [tree.insert(bad.clone()), tree.insert(after)];
+
+        // Connecting `bad` fails as soon as its branch is preferred; after that it is
+        // treated as unknown.
+        let state_errors = results
+            .iter()
+            .filter(|r| matches!(r, Err(TreeError::State(_))))
+            .count();
+        assert_eq!(state_errors, 1);
+        assert_eq!(tree.tip().hash, main[2].hash);
+        assert_eq!(tree.state().0, expected_total(&tree));
+
+        let err = tree.insert(child(&bad, 1, "other")).unwrap_err();
+        assert_eq!(err, TreeError::UnknownParent(bad.hash));
+    }
+
+    #[test]
+    fn test_failed_disconnect_keeps_old_chain() {
+        let genesis = genesis();
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(LongestChain),
+            Total::default(),
+            ConsensusParams::default(),
+            ConsensusConfig::default(),
+        )
+        .unwrap();
+        let (main, _) = branch(&mut tree, &genesis, 1, 10, "main");
+        let (stuck, _) = branch(&mut tree, &main[0], 1, 13, "main");
+        let (main, _) = branch(&mut tree, &stuck[0], 1, 10, "main");
+
+        // The tie at height 3 may already be decided for the side branch.
+        let (side, _) = branch(&mut tree, &genesis, 2, 5, "side");
+        let tie = child(&side[1], 5, "side");
+        let longer = child(&tie, 5, "side");
+        let results = [tree.insert(tie), tree.insert(longer.clone())];
+        assert!(
+            results
+                .iter()
+                .all(|r| matches!(r, Ok(None) | Err(TreeError::State(_))))
+        );
+        assert!(matches!(results[1], Err(TreeError::State(_))));
+
+        // The block above the stuck one was reconnected.
+        assert_eq!(tree.tip().hash, main[0].hash);
+        assert!(tree.is_active(&stuck[0].hash));
+        assert_eq!(tree.state().0, expected_total(&tree));
+        assert!(tree.get(&longer.hash).is_some());
+    }
+
+    #[test]
+    fn test_failed_rollback_needs_rebuild() {
+        let genesis = genesis();
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(LongestChain),
+            Total::default(),
+            ConsensusParams::default(),
+            ConsensusConfig::default(),
+        )
+        .unwrap();
+        let (main, _) = branch(&mut tree, &genesis, 2, 10, "main");
+
+        // The side branch cannot connect its second block, and its first cannot
+        // be disconnected again to go back.
+        let stuck = child(&genesis, 13, "side");
+        let bad = child(&stuck, 0, "side");
+        let after = child(&bad, 5, "side");
+        assert!(tree.insert(stuck).unwrap().is_none());
+        let results = [tree.insert(bad), tree.insert(after)];
+        assert!(matches!(results[1], Err(TreeError::NeedsRebuild(_))));
+        assert!(tree.needs_rebuild().unwrap().contains("stuck"));
+        assert!(matches!(
+            tree.insert(child(&main[1], 1, "main")),
+            Err(TreeError::NeedsRebuild(_))
+        ));
+
+        tree.rebuild(Total::default()).unwrap();
+        assert_eq!(tree.needs_rebuild(), None);
+        assert_eq!(tree.tip().hash, main[1].hash);
+        assert_eq!(tree.state().0, expected_total(&tree));
+        branch(&mut tree, &main[1], 1, 10, "main");
+        assert_eq!(tree.state().0, expected_total(&tree));
+    }
+
+    #[test]
+    fn test_heaviest_chain_follows_work() {
+        let genesis = genesis();
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(HeaviestChain),
+            Total::default(),
+            ConsensusParams::default(),
+            ConsensusConfig::default(),
+        )
+        .unwrap();
+        let (main, _) = branch(&mut tree, &genesis, 3, 10, "main");
+
+        // One block at difficulty 1 outweighs three at difficulty 0.
+        let heavy = child(&genesis, 7, "heavy").mine(1).unwrap();
+        let reorg = tree.insert(heavy.clone()).unwrap().unwrap();
+        assert_eq!(reorg.disconnected.len(), 3);
+        assert_eq!(tree.tip().hash, heavy.hash);
+        assert_eq!(tree.height(), 1);
+        assert!(!tree.is_active(&main[2].hash));
+        assert_eq!(tree.state().0, expected_total(&tree));
+    }
+
+    #[test]
+    fn test_pair_stays_in_sync() {
+        let block = genesis();
+        let refuse_connect = || Count {
+            refuse_connect: true,
+            ..Count::default()
+        };
+
+        let mut pair = (Count::default(), refuse_connect());
+        assert!(pair.connect_block(&block).is_err());
+        assert_eq!(pair.0.blocks, 0);
+
+        let mut pair = (Count::default(), Count::default());
+        pair.connect_block(&block).unwrap();
+        pair.0.refuse_disconnect = true;
+        assert!(pair.disconnect_block(&block).is_err());
+        assert_eq!((pair.0.blocks, pair.1.blocks), (1, 1));
+
+        // A rollback that fails as well is not swallowed.
+        let mut pair = (
+            Count {
+                refuse_disconnect: true,
+                ..Count::default()
+            },
+            refuse_connect(),
+        );
+        let err = pair.connect_block(&block).unwrap_err();
+        assert!(err.contains("connect refused") && err.contains("disconnect refused"));
+    }
+
+    #[test]
+    fn test_rejects_unlinked_blocks() {
+        let genesis = genesis();
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(LongestChain),
+            (),
+            ConsensusParams::default(),
+            ConsensusConfig::default(),
+        )
+        .unwrap();
+
+        let orphan = Block::new(1, 1001, vec![], "missing".to_string());
+        let err = tree.insert(orphan).unwrap_err();
+        assert_eq!(err, TreeError::UnknownParent("missing".to_string()));
+
+        let wrong_height = Block::new(5, 1001, vec![], genesis.hash.clone());
+        let err = tree.insert(wrong_height).unwrap_err();
+        assert!(matches!(
+            err,
+            TreeError::Invalid(BreakReason::HeightGap { .. })
+        ));
+    }
+
+    #[test]
+    fn test_applies_chain_params() {
+        let genesis = genesis();
+        let params = ConsensusParams {
+            max_block_transactions: 1,
+            ..ConsensusParams::default()
+        };
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(LongestChain),
+            (),
+            params,
+            ConsensusConfig::default(),
+        )
+        .unwrap();
+
+        let crowded = Block::new(1, 1001, vec![tx(1, "a"), tx(2, "b")], genesis.hash.clone());
+        assert!(matches!(
+            tree.insert(crowded),
+            Err(TreeError::Invalid(BreakReason::LimitExceeded(_)))
+        ));
+        assert!(tree.insert(child(&genesis, 1, "a")).is_ok());
+
+        let jumped = Block::new(1, 1001, vec![tx(3, "c")], genesis.hash.clone())
+            .mine(2)
+            .unwrap();
+        assert!(matches!(
+            tree.insert(jumped),
+            Err(TreeError::Invalid(BreakReason::DifficultyJump {
+                parent: 0,
+                found: 2
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_applies_timestamp_rules() {
+        let genesis = genesis();
+        let rules = TimestampRules {
+            median_window: 3,
+            max_future_drift: 10,
+        };
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(LongestChain),
+            Total::default(),
+            ConsensusParams::default(),
+            ConsensusConfig::default(),
+        )
+        .unwrap()
+        .with_timestamp_rules(rules, ManualClock::new(1000));
+
+        let ahead = Block::new(1, 1011, vec![tx(1, "a")], genesis.hash.clone());
+        assert!(matches!(
+            tree.insert(ahead),
+            Err(TreeError::Invalid(BreakReason::TimestampTooFarAhead { .. }))
+        ));
+        // Same time as its parent: not before it, but not after the median.
+        let stalled = Block::new(1, 1000, vec![tx(1, "a")], genesis.hash.clone());
+        assert!(matches!(
+            tree.insert(stalled),
+            Err(TreeError::Invalid(
+                BreakReason::TimestampNotAfterMedian { .. }
+            ))
+        ));
+        assert_eq!(tree.tips(), [genesis.hash.as_str()]);
+
+        let (blocks, _) = branch(&mut tree, &genesis, 3, 1, "a");
+        assert_eq!(tree.height(), 3);
+        assert_eq!(
+            tree.recent_timestamps(&blocks[2].hash, 3),
+            [1001, 1002, 1003]
+        );
+
+        // Older than its parent is fine as long as it beats the median, 1006.
+        let mut parent = blocks[2].clone();
+        for (amount, timestamp) in [(2, 1006), (3, 1009), (4, 1007)] {
+            let block = Block::new(
+                parent.id.checked_add(1).expect("test chains stay short"),
+                timestamp,
+                vec![tx(amount, "a")],
+                parent.hash.clone(),
+            );
+            tree.insert(block.clone()).unwrap();
+            parent = block;
+        }
+        assert_eq!(tree.tip().hash, parent.hash);
+        let chain: Vec<Block> = tree.active_chain().cloned().collect();
+        let clock = ManualClock::new(1000);
+        let (params, consensus) = (ConsensusParams::default(), ConsensusConfig::default());
+        assert!(verify_chain_report_within(&chain, &params, &consensus, &rules, &clock).is_ok());
+    }
+}
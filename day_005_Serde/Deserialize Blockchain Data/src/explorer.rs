@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+
+use crate::chain::ChainState;
+use crate::{Block, Transaction};
+
+/// Most results a single page holds, whatever the caller asks for.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// Position of a transaction on the active chain. Orders by height, then by
+/// position in the block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TxLocation {
+    pub height: u32,
+    pub index: u32,
+}
+
+/// A transaction together with the block it was found in.
+#[derive(Debug, Clone)]
+pub struct TxRecord {
+    pub transaction: Transaction,
+    pub location: TxLocation,
+    pub block_hash: String,
+}
+
+/// Which side of a transfer an address must be on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Sender,
+    Receiver,
+    Either,
+}
+
+/// Asks for up to `limit` results, newest first, starting just before
+/// `before`. Pass a page's `next` back as `before` to get the following page;
+/// since cursors are positions rather than offsets, blocks connected in the
+/// meantime do not shift later pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageRequest {
+    pub limit: usize,
+    pub before: Option<TxLocation>,
+}
+
+impl PageRequest {
+    pub fn first(limit: usize) -> Self {
+        PageRequest {
+            limit,
+            before: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Cursor for the next page, or `None` if this was the last one.
+    pub next: Option<TxLocation>,
+}
+
+struct IndexedBlock {
+    hash: String,
+    transactions: Vec<Transaction>,
+}
+
+/// Secondary indexes over the active chain for explorer-style lookups.
+///
+/// Implements `ChainState`, so a `ChainTree` keeps it in step with the active
+/// chain through reorgs, alone or paired with a ledger as `(Ledger,
+/// ExplorerIndex)`. Maintains:
+///
+/// - height -> block hash, and back;
+/// - transaction id -> location (ids are not required to be unique; the
+///   most recent occurrence wins);
+/// - sender and receiver address -> locations, oldest first.
+#[derive(Default)]
+pub struct ExplorerIndex {
+    blocks: Vec<IndexedBlock>,
+    heights: HashMap<String, u32>,
+    by_id: HashMap<u32, Vec<TxLocation>>,
+    by_sender: HashMap<String, Vec<TxLocation>>,
+    by_receiver: HashMap<String, Vec<TxLocation>>,
+}
+
+/// Removes `location` from the end of the list under `key`, dropping the key
+/// once the list is empty. Disconnects run tip first, so it is always last.
+fn pop_location<K: std::hash::Hash + Eq>(
+    index: &mut HashMap<K, Vec<TxLocation>>,
+    key: &K,
+    location: TxLocation,
+) {
+    if let Some(list) = index.get_mut(key) {
+        if list.last() == Some(&location) {
+            list.pop();
+        }
+        if list.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+/// Locations of `address` in `index` that come before `cursor`, oldest first.
+fn locations_before<'a>(
+    index: &'a HashMap<String, Vec<TxLocation>>,
+    address: &str,
+    cursor: Option<TxLocation>,
+) -> &'a [TxLocation] {
+    let list = index.get(address).map_or(&[][..], Vec::as_slice);
+    match cursor {
+        Some(cursor) => &list[..list.partition_point(|l| *l < cursor)],
+        None => list,
+    }
+}
+
+/// The last `n` entries of `list`.
+fn newest(list: &[TxLocation], n: usize) -> &[TxLocation] {
+    &list[list.len().saturating_sub(n)..]
+}
+
+impl ExplorerIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Indexes a chain from genesis, e.g. one read back from a `BlockStore`.
+    pub fn from_chain<'a>(chain: impl IntoIterator<Item = &'a Block>) -> Result<Self, String> {
+        let mut index = Self::new();
+        for block in chain {
+            index.connect_block(block)?;
+        }
+        Ok(index)
+    }
+
+    /// Number of indexed blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn hash_at(&self, height: u32) -> Option<&str> {
+        self.blocks.get(height as usize).map(|b| b.hash.as_str())
+    }
+
+    pub fn height_of(&self, hash: &str) -> Option<u32> {
+        self.heights.get(hash).copied()
+    }
+
+    fn record(&self, location: TxLocation) -> TxRecord {
+        let block = &self.blocks[location.height as usize];
+        TxRecord {
+            transaction: block.transactions[location.index as usize].clone(),
+            location,
+            block_hash: block.hash.clone(),
+        }
+    }
+
+    /// The transaction with `id` and the block containing it.
+    pub fn transaction(&self, id: u32) -> Option<TxRecord> {
+        let location = *self.by_id.get(&id)?.last()?;
+        Some(self.record(location))
+    }
+
+    /// Transactions involving `address`, newest first.
+    pub fn transactions_by_address(
+        &self,
+        address: &str,
+        role: Role,
+        page: PageRequest,
+    ) -> Page<TxRecord> {
+        let limit = page.limit.clamp(1, MAX_PAGE_SIZE);
+        let sent = locations_before(&self.by_sender, address, page.before);
+        let received = locations_before(&self.by_receiver, address, page.before);
+
+        // One more than the page holds, to tell whether another page follows.
+        let mut locations: Vec<TxLocation> = match role {
+            Role::Sender => newest(sent, limit + 1).to_vec(),
+            Role::Receiver => newest(received, limit + 1).to_vec(),
+            Role::Either => {
+                let mut merged = newest(sent, limit + 1).to_vec();
+                merged.extend_from_slice(newest(received, limit + 1));
+                merged.sort_unstable();
+                // A transfer to oneself is in both lists.
+                merged.dedup();
+                newest(&merged, limit + 1).to_vec()
+            }
+        };
+        locations.reverse();
+
+        let next = if locations.len() > limit {
+            locations.truncate(limit);
+            locations.last().copied()
+        } else {
+            None
+        };
+        Page {
+            items: locations.into_iter().map(|l| self.record(l)).collect(),
+            next,
+        }
+    }
+}
+
+impl ChainState for ExplorerIndex {
+    fn connect_block(&mut self, block: &Block) -> Result<(), String> {
+        if block.id as usize != self.blocks.len() {
+            return Err(format!(
+                "explorer expected block {}, got {}",
+                self.blocks.len(),
+                block.id
+            ));
+        }
+        let hash = block.compute_hash();
+        for (index, tx) in block.transactions.iter().enumerate() {
+            let location = TxLocation {
+                height: block.id,
+                index: index as u32,
+            };
+            self.by_id.entry(tx.id).or_default().push(location);
+            self.by_sender
+                .entry(tx.sender.clone())
+                .or_default()
+                .push(location);
+            self.by_receiver
+                .entry(tx.receiver.clone())
+                .or_default()
+                .push(location);
+        }
+        self.heights.insert(hash.clone(), block.id);
+        self.blocks.push(IndexedBlock {
+            hash,
+            transactions: block.transactions.clone(),
+        });
+        Ok(())
+    }
+
+    fn disconnect_block(&mut self, block: &Block) -> Result<(), String> {
+        let hash = block.compute_hash();
+        if self.blocks.last().map(|b| &b.hash) != Some(&hash) {
+            return Err(format!("block {} is not the explorer tip", block.id));
+        }
+        for (index, tx) in block.transactions.iter().enumerate().rev() {
+            let location = TxLocation {
+                height: block.id,
+                index: index as u32,
+            };
+            pop_location(&mut self.by_id, &tx.id, location);
+            pop_location(&mut self.by_sender, &tx.sender, location);
+            pop_location(&mut self.by_receiver, &tx.receiver, location);
+        }
+        self.heights.remove(&hash);
+        self.blocks.pop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::ChainTree;
+    use crate::ledger::Ledger;
+    use crate::params::ConsensusParams;
+    use crate::spec::ConsensusConfig;
+    use blockchain_traits::fork_choice::LongestChain;
+
+    fn tx(id: u32, sender: &str, receiver: &str) -> Transaction {
+        Transaction {
+            id,
+            amount: 1,
+            sender: sender.to_string(),
+            receiver: receiver.to_string(),
+            fee: 0,
+            nonce: 0,
+        }
+    }
+
+    fn child(parent: &Block, txs: Vec<Transaction>) -> Block {
+        Block::new(
+            parent.id + 1,
+            parent.timestamp + 1,
+            txs,
+            parent.hash.clone(),
+        )
+    }
+
+    fn ids(page: &Page<TxRecord>) -> Vec<u32> {
+        page.items.iter().map(|r| r.transaction.id).collect()
+    }
+
+    #[test]
+    fn test_lookups_and_pagination() {
+        let genesis = Block::new(0, 1000, vec![tx(0, "Genesis", "alice")], "0".to_string());
+        let mut chain = vec![genesis];
+        for id in 1..=6 {
+            // alice pays bob, and in block 3 also pays herself.
+            let mut txs = vec![tx(id * 10, "alice", "bob")];
+            if id == 3 {
+                txs.push(tx(id * 10 + 1, "alice", "alice"));
+            }
+            chain.push(child(chain.last().unwrap(), txs));
+        }
+        let index = ExplorerIndex::from_chain(&chain).unwrap();
+
+        assert_eq!(index.len(), 7);
+        assert_eq!(index.hash_at(3), Some(chain[3].hash.as_str()));
+        assert_eq!(index.height_of(&chain[5].hash), Some(5));
+        assert_eq!(index.hash_at(7), None);
+
+        let found = index.transaction(31).unwrap();
+        assert_eq!(
+            found.location,
+            TxLocation {
+                height: 3,
+                index: 1
+            }
+        );
+        assert_eq!(found.block_hash, chain[3].hash);
+        assert!(index.transaction(99).is_none());
+
+        let first = index.transactions_by_address("alice", Role::Either, PageRequest::first(3));
+        assert_eq!(ids(&first), [60, 50, 40]);
+        let second = index.transactions_by_address(
+            "alice",
+            Role::Either,
+            PageRequest {
+                limit: 3,
+                before: first.next,
+            },
+        );
+        // The self-transfer shows up once.
+        assert_eq!(ids(&second), [31, 30, 20]);
+        let last = index.transactions_by_address(
+            "alice",
+            Role::Either,
+            PageRequest {
+                limit: 3,
+                before: second.next,
+            },
+        );
+        assert_eq!(ids(&last), [10, 0]);
+        assert_eq!(last.next, None);
+
+        let received =
+            index.transactions_by_address("alice", Role::Receiver, PageRequest::first(10));
+        assert_eq!(ids(&received), [31, 0]);
+        let sent = index.transactions_by_address("bob", Role::Sender, PageRequest::first(10));
+        assert!(sent.items.is_empty());
+        let capped = index.transactions_by_address("bob", Role::Receiver, PageRequest::first(0));
+        assert_eq!(ids(&capped), [60]);
+    }
+
+    #[test]
+    fn test_follows_reorgs() {
+        let genesis = Block::new(0, 1000, vec![tx(0, "Genesis", "alice")], "0".to_string());
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(LongestChain),
+            (Ledger::new(), ExplorerIndex::new()),
+            ConsensusParams::default(),
+            ConsensusConfig::default(),
+        )
+        .unwrap();
+
+        let main = child(&genesis, vec![tx(1, "alice", "bob")]);
+        tree.insert(main.clone()).unwrap();
+        assert_eq!(tree.state().1.transaction(1).unwrap().block_hash, main.hash);
+
+        // A longer branch without transaction 1 takes over.
+        let side1 = child(&genesis, vec![tx(2, "alice", "carol")]);
+        let side2 = child(&side1, vec![]);
+        tree.insert(side1.clone()).unwrap();
+        tree.insert(side2.clone()).unwrap();
+        assert_eq!(tree.tip().hash, side2.hash);
+
+        let (ledger, index) = tree.state();
+        assert_eq!(ledger.balance("carol"), 1);
+        assert!(index.transaction(1).is_none());
+        assert_eq!(index.hash_at(1), Some(side1.hash.as_str()));
+        assert_eq!(index.height_of(&main.hash), None);
+        let bob = index.transactions_by_address("bob", Role::Either, PageRequest::first(10));
+        assert!(bob.items.is_empty());
+        let alice = index.transactions_by_address("alice", Role::Either, PageRequest::first(10));
+        assert_eq!(ids(&alice), [2, 0]);
+    }
+}
@@ -0,0 +1,766 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::chain::ChainState;
+use crate::params::ConsensusParams;
+use crate::smt::{SmtProof, SparseMerkleTree};
+use crate::{Block, Transaction};
+
+/// Transactions from this sender create coins instead of moving them. Any
+/// number may appear in the genesis block; after that, only a block's first
+/// transaction, its reward, may.
+pub const MINT_SENDER: &str = "Genesis";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockChainError {
+    InvalidTransaction(String),
+    /// The sender is short by this many coins.
+    InsufficientFunds(u64),
+    /// The block commits to a different state than applying it produced.
+    StateRootMismatch {
+        expected: String,
+        computed: String,
+    },
+    /// The reward mints more than the subsidy plus the block's fees.
+    RewardTooLarge {
+        allowed: u64,
+        claimed: u64,
+    },
+    /// The transaction does not spend the sender's current nonce, e.g. it is
+    /// a replay or skips ahead.
+    BadNonce {
+        sender: String,
+        expected: u64,
+        found: u64,
+    },
+}
+
+impl fmt::Display for BlockChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockChainError::InvalidTransaction(msg) => write!(f, "Invalid Transaction: {}", msg),
+            BlockChainError::InsufficientFunds(deficit) => {
+                write!(f, "Not enough balance. Required deficit: {}", deficit)
+            }
+            BlockChainError::StateRootMismatch { expected, computed } => {
+                write!(
+                    f,
+                    "State root mismatch: block has {}, computed {}",
+                    expected, computed
+                )
+            }
+            BlockChainError::RewardTooLarge { allowed, claimed } => {
+                write!(f, "Reward of {} exceeds the allowed {}", claimed, allowed)
+            }
+            BlockChainError::BadNonce {
+                sender,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Nonce {} from {} does not match its account nonce {}",
+                found, sender, expected
+            ),
+        }
+    }
+}
+
+impl Error for BlockChainError {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Account {
+    pub balance: u64,
+    /// Number of transactions sent from this account.
+    pub nonce: u64,
+}
+
+impl Account {
+    /// Bytes stored in the state tree for this account.
+    pub fn leaf_value(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Serialization failed")
+    }
+}
+
+/// Account-based state: address -> balance and nonce.
+///
+/// Accounts are mirrored in a sparse Merkle tree keyed by address, whose root
+/// is the state root committed in blocks. Empty accounts are left out.
+///
+/// The block subsidy is configuration rather than state, so it is not
+/// serialized; a loaded ledger has the default until `with_subsidy` sets it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "LedgerData", into = "LedgerData")]
+pub struct Ledger {
+    accounts: HashMap<String, Account>,
+    tree: SparseMerkleTree,
+    subsidy: u64,
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Ledger {
+            accounts: HashMap::new(),
+            tree: SparseMerkleTree::default(),
+            subsidy: ConsensusParams::default().block_subsidy,
+        }
+    }
+}
+
+/// Serialized form of a `Ledger`; the tree is rebuilt on load.
+#[derive(Serialize, Deserialize)]
+struct LedgerData {
+    accounts: HashMap<String, Account>,
+}
+
+impl From<LedgerData> for Ledger {
+    fn from(data: LedgerData) -> Self {
+        Ledger::from_accounts(data.accounts)
+    }
+}
+
+impl From<Ledger> for LedgerData {
+    fn from(ledger: Ledger) -> Self {
+        LedgerData {
+            accounts: ledger.accounts,
+        }
+    }
+}
+
+/// Checks a proof from `Ledger::prove_account` against a state root. `None`
+/// checks that the address has no (or an empty) account.
+pub fn verify_account(
+    state_root: &str,
+    address: &str,
+    account: Option<&Account>,
+    proof: &SmtProof,
+) -> bool {
+    let value = account
+        .filter(|a| **a != Account::default())
+        .map(Account::leaf_value);
+    proof.verify(state_root, address.as_bytes(), value.as_deref())
+}
+
+/// Accounts touched by a block, applied to the ledger only once every
+/// transaction has succeeded.
+struct Changes<'a> {
+    ledger: &'a Ledger,
+    touched: HashMap<String, Account>,
+}
+
+impl Changes<'_> {
+    fn get(&self, address: &str) -> Account {
+        self.touched
+            .get(address)
+            .copied()
+            .unwrap_or_else(|| self.ledger.account(address))
+    }
+
+    fn set(&mut self, address: &str, account: Account) {
+        self.touched.insert(address.to_string(), account);
+    }
+}
+
+fn check_shape(tx: &Transaction) -> Result<(), BlockChainError> {
+    if tx.amount == 0 {
+        return Err(BlockChainError::InvalidTransaction(
+            "Amount must be positive".to_string(),
+        ));
+    }
+    if tx.receiver.is_empty() {
+        return Err(BlockChainError::InvalidTransaction(format!(
+            "transaction {} has no receiver",
+            tx.id
+        )));
+    }
+    if tx.sender == MINT_SENDER && tx.fee != 0 {
+        return Err(BlockChainError::InvalidTransaction(format!(
+            "mint transaction {} cannot pay a fee",
+            tx.id
+        )));
+    }
+    Ok(())
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds a ledger from a list of accounts, e.g. a snapshot.
+    pub fn from_accounts(accounts: impl IntoIterator<Item = (String, Account)>) -> Self {
+        let mut ledger = Ledger::new();
+        ledger.commit(accounts.into_iter().collect());
+        ledger
+    }
+
+    /// Sets the subsidy rewards are checked against, normally
+    /// `ConsensusParams::block_subsidy`.
+    pub fn with_subsidy(mut self, subsidy: u64) -> Self {
+        self.subsidy = subsidy;
+        self
+    }
+
+    pub fn subsidy(&self) -> u64 {
+        self.subsidy
+    }
+
+    /// Returns the account, or an empty one if the address has never been seen.
+    pub fn account(&self, address: &str) -> Account {
+        self.accounts.get(address).copied().unwrap_or_default()
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        self.account(address).balance
+    }
+
+    pub fn nonce(&self, address: &str) -> u64 {
+        self.account(address).nonce
+    }
+
+    /// Sum of all balances.
+    pub fn total_supply(&self) -> u64 {
+        self.accounts.values().map(|a| a.balance).sum()
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = (&str, &Account)> {
+        self.accounts.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    /// Hex-encoded root of the account tree.
+    pub fn state_root(&self) -> String {
+        self.tree.root_hex()
+    }
+
+    /// Proof of `address`'s account, or of its absence, under `state_root`.
+    pub fn prove_account(&self, address: &str) -> SmtProof {
+        self.tree.prove(address.as_bytes())
+    }
+
+    /// The state root `block` should commit to if applied on top of this
+    /// ledger.
+    pub fn state_root_after(&self, block: &Block) -> Result<String, BlockChainError> {
+        let mut next = self.clone();
+        let mut unchecked = block.clone();
+        unchecked.state_root.clear();
+        next.apply_block(&unchecked)?;
+        Ok(next.state_root())
+    }
+
+    /// State transition: applies every transaction in `block` in order.
+    ///
+    /// Debits the sender the amount plus fee (unless it is `MINT_SENDER`, see
+    /// there for where mints are allowed), credits the receiver the amount and
+    /// bumps the sender's nonce, which the transaction must carry. Fees leave circulation here; the block's
+    /// reward transaction mints them back to the miner, along with at most the
+    /// subsidy. If the block has a `state_root`, the resulting state must
+    /// match it. If anything fails, the ledger is left exactly as it was.
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), BlockChainError> {
+        let mut changes = Changes {
+            ledger: self,
+            touched: HashMap::new(),
+        };
+        let mut reward = None;
+        let mut fees = 0u64;
+
+        for (index, tx) in block.transactions.iter().enumerate() {
+            check_shape(tx)?;
+            if tx.sender == MINT_SENDER && block.id != 0 {
+                if index != 0 {
+                    return Err(BlockChainError::InvalidTransaction(format!(
+                        "mint transaction {} is not the block reward",
+                        tx.id
+                    )));
+                }
+                reward = Some(tx.amount as u64);
+            }
+            fees = fees.checked_add(tx.fee).ok_or_else(|| {
+                BlockChainError::InvalidTransaction(format!("fee overflow in {}", tx.id))
+            })?;
+            let amount = tx.amount as u64;
+
+            if tx.sender != MINT_SENDER {
+                let mut sender = changes.get(&tx.sender);
+                if tx.nonce != sender.nonce {
+                    return Err(BlockChainError::BadNonce {
+                        sender: tx.sender.clone(),
+                        expected: sender.nonce,
+                        found: tx.nonce,
+                    });
+                }
+                let cost = amount.checked_add(tx.fee).ok_or_else(|| {
+                    BlockChainError::InvalidTransaction(format!("fee overflow in {}", tx.id))
+                })?;
+                if sender.balance < cost {
+                    return Err(BlockChainError::InsufficientFunds(cost - sender.balance));
+                }
+                sender.balance -= cost;
+                sender.nonce += 1;
+                changes.set(&tx.sender, sender);
+            }
+
+            let mut receiver = changes.get(&tx.receiver);
+            receiver.balance = receiver.balance.checked_add(amount).ok_or_else(|| {
+                BlockChainError::InvalidTransaction(format!("balance overflow for {}", tx.receiver))
+            })?;
+            changes.set(&tx.receiver, receiver);
+        }
+
+        if let Some(claimed) = reward {
+            let allowed = self.subsidy.saturating_add(fees);
+            if claimed > allowed {
+                return Err(BlockChainError::RewardTooLarge { allowed, claimed });
+            }
+        }
+
+        let touched = changes.touched;
+        let previous = self.commit(touched);
+
+        let computed = self.state_root();
+        if !block.state_root.is_empty() && block.state_root != computed {
+            self.commit(previous);
+            return Err(BlockChainError::StateRootMismatch {
+                expected: block.state_root.clone(),
+                computed,
+            });
+        }
+        Ok(())
+    }
+
+    /// Undoes `apply_block` for the same block, walking its transactions
+    /// backwards. Fails without changing anything if the ledger is not in the
+    /// state `apply_block` left it in.
+    pub fn revert_block(&mut self, block: &Block) -> Result<(), BlockChainError> {
+        let mut changes = Changes {
+            ledger: self,
+            touched: HashMap::new(),
+        };
+
+        for tx in block.transactions.iter().rev() {
+            let amount = tx.amount as u64;
+
+            let mut receiver = changes.get(&tx.receiver);
+            if receiver.balance < amount {
+                return Err(BlockChainError::InsufficientFunds(
+                    amount - receiver.balance,
+                ));
+            }
+            receiver.balance -= amount;
+            changes.set(&tx.receiver, receiver);
+
+            if tx.sender != MINT_SENDER {
+                let mut sender = changes.get(&tx.sender);
+                if tx.nonce.checked_add(1) != Some(sender.nonce) {
+                    return Err(BlockChainError::InvalidTransaction(format!(
+                        "cannot revert transaction {}: {} has nonce {}",
+                        tx.id, tx.sender, sender.nonce
+                    )));
+                }
+                sender.balance = amount
+                    .checked_add(tx.fee)
+                    .and_then(|refund| sender.balance.checked_add(refund))
+                    .ok_or_else(|| {
+                        BlockChainError::InvalidTransaction(format!(
+                            "cannot revert transaction {}: balance overflow for {}",
+                            tx.id, tx.sender
+                        ))
+                    })?;
+                sender.nonce = tx.nonce;
+                changes.set(&tx.sender, sender);
+            }
+        }
+
+        let touched = changes.touched;
+        self.commit(touched);
+        Ok(())
+    }
+
+    /// Writes `touched` into the accounts and the tree in one batch, and
+    /// returns the accounts it replaced.
+    fn commit(&mut self, touched: HashMap<String, Account>) -> HashMap<String, Account> {
+        let leaves: Vec<(String, Option<Vec<u8>>)> = touched
+            .iter()
+            .map(|(address, account)| {
+                let value = (*account != Account::default()).then(|| account.leaf_value());
+                (address.clone(), value)
+            })
+            .collect();
+        self.tree.update_batch(
+            leaves
+                .iter()
+                .map(|(address, value)| (address.as_bytes(), value.as_deref())),
+        );
+
+        let mut previous = HashMap::new();
+        for (address, account) in touched {
+            let old = if account == Account::default() {
+                self.accounts.remove(&address)
+            } else {
+                self.accounts.insert(address.clone(), account)
+            };
+            previous.insert(address, old.unwrap_or_default());
+        }
+        previous
+    }
+}
+
+impl ChainState for Ledger {
+    fn connect_block(&mut self, block: &Block) -> Result<(), String> {
+        self.apply_block(block).map_err(|e| e.to_string())
+    }
+
+    fn disconnect_block(&mut self, block: &Block) -> Result<(), String> {
+        self.revert_block(block).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::ChainTree;
+    use crate::spec::ConsensusConfig;
+    use blockchain_traits::fork_choice::LongestChain;
+
+    fn tx(id: u32, sender: &str, receiver: &str, amount: u32) -> Transaction {
+        Transaction {
+            id,
+            amount,
+            sender: sender.to_string(),
+            receiver: receiver.to_string(),
+            fee: 0,
+            nonce: 0,
+        }
+    }
+
+    fn genesis() -> Block {
+        Block::new(
+            0,
+            1000,
+            vec![tx(0, MINT_SENDER, "alice", 100)],
+            "0".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_apply_block_moves_balances() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&genesis()).unwrap();
+
+        let block = Block::new(
+            1,
+            1001,
+            vec![tx(1, "alice", "bob", 30), tx(2, "bob", "carol", 10)],
+            genesis().hash,
+        );
+        ledger.apply_block(&block).unwrap();
+
+        assert_eq!(ledger.balance("alice"), 70);
+        assert_eq!(ledger.balance("bob"), 20);
+        assert_eq!(ledger.balance("carol"), 10);
+        assert_eq!(ledger.nonce("alice"), 1);
+        assert_eq!(ledger.nonce("bob"), 1);
+        assert_eq!(ledger.nonce("carol"), 0);
+        assert_eq!(ledger.total_supply(), 100);
+    }
+
+    #[test]
+    fn test_overdraft_rolls_back_whole_block() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&genesis()).unwrap();
+        let before = ledger.clone();
+
+        let block = Block::new(
+            1,
+            1001,
+            vec![
+                tx(1, "alice", "bob", 60),
+                Transaction {
+                    nonce: 1,
+                    ..tx(2, "alice", "carol", 60)
+                },
+            ],
+            genesis().hash,
+        );
+        let err = ledger.apply_block(&block).unwrap_err();
+
+        assert_eq!(err, BlockChainError::InsufficientFunds(20));
+        assert_eq!(ledger, before);
+
+        let zero = Block::new(1, 1001, vec![tx(1, "alice", "bob", 0)], genesis().hash);
+        assert!(matches!(
+            ledger.apply_block(&zero),
+            Err(BlockChainError::InvalidTransaction(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_replayed_and_skipped_nonces() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&genesis()).unwrap();
+        let pay = tx(1, "alice", "bob", 10);
+        let block = Block::new(1, 1001, vec![pay.clone()], genesis().hash);
+        ledger.apply_block(&block).unwrap();
+        let after = ledger.clone();
+
+        // The same transaction again, in the same block or a later one.
+        let again = Block::new(2, 1002, vec![pay.clone()], block.hash.clone());
+        assert_eq!(
+            ledger.apply_block(&again),
+            Err(BlockChainError::BadNonce {
+                sender: "alice".to_string(),
+                expected: 1,
+                found: 0
+            })
+        );
+        let ahead = Transaction {
+            nonce: 2,
+            ..tx(2, "alice", "bob", 10)
+        };
+        let ahead = Block::new(2, 1002, vec![ahead], block.hash.clone());
+        assert!(matches!(
+            ledger.apply_block(&ahead),
+            Err(BlockChainError::BadNonce { found: 2, .. })
+        ));
+        assert_eq!(ledger, after);
+
+        // Reverting needs the nonce the transaction spent.
+        assert!(ledger.revert_block(&ahead).is_err());
+        ledger.revert_block(&block).unwrap();
+        assert_eq!(ledger.nonce("alice"), 0);
+    }
+
+    #[test]
+    fn test_fees_are_charged_and_reverted() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&genesis()).unwrap();
+        let before = ledger.clone();
+
+        let paid = Transaction {
+            fee: 5,
+            ..tx(1, "alice", "bob", 30)
+        };
+        let reward = tx(2, MINT_SENDER, "miner", 5);
+        let block = Block::new(1, 1001, vec![reward, paid], genesis().hash);
+        ledger.apply_block(&block).unwrap();
+        assert_eq!(ledger.balance("alice"), 65);
+        assert_eq!(ledger.balance("bob"), 30);
+        assert_eq!(ledger.balance("miner"), 5);
+        assert_eq!(ledger.total_supply(), 100);
+        ledger.revert_block(&block).unwrap();
+        assert_eq!(ledger, before);
+
+        // Enough for the amount but not the fee.
+        let short = Transaction {
+            fee: 1,
+            ..tx(1, "alice", "bob", 100)
+        };
+        let block = Block::new(1, 1001, vec![short], genesis().hash);
+        assert_eq!(
+            ledger.apply_block(&block),
+            Err(BlockChainError::InsufficientFunds(1))
+        );
+        let mint = Transaction {
+            fee: 1,
+            ..tx(1, MINT_SENDER, "bob", 10)
+        };
+        let block = Block::new(1, 1001, vec![mint], genesis().hash);
+        assert!(ledger.apply_block(&block).is_err());
+    }
+
+    #[test]
+    fn test_mints_only_in_genesis_or_as_reward() {
+        let genesis = genesis();
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(LongestChain),
+            Ledger::new(),
+            ConsensusParams::default(),
+            ConsensusConfig::default(),
+        )
+        .unwrap();
+
+        // A transfer followed by a mint to the block's own producer.
+        let minting = Block::new(
+            1,
+            1001,
+            vec![
+                tx(1, "alice", "bob", 10),
+                tx(2, MINT_SENDER, "mallory", 1_000),
+            ],
+            genesis.hash.clone(),
+        );
+        assert!(tree.insert(minting).is_err());
+        // A second mint after the reward is no better.
+        let twice = Block::new(
+            1,
+            1001,
+            vec![
+                tx(1, MINT_SENDER, "mallory", 1),
+                tx(2, MINT_SENDER, "mallory", 1),
+            ],
+            genesis.hash.clone(),
+        );
+        assert!(matches!(
+            Ledger::new().apply_block(&twice),
+            Err(BlockChainError::InvalidTransaction(_))
+        ));
+        assert!(tree.insert(twice).is_err());
+        assert_eq!(tree.state().balance("mallory"), 0);
+        assert_eq!(tree.state().total_supply(), 100);
+    }
+
+    #[test]
+    fn test_reward_is_capped_by_subsidy_and_fees() {
+        let mut ledger = Ledger::new().with_subsidy(10);
+        ledger.apply_block(&genesis()).unwrap();
+        let before = ledger.clone();
+
+        let paid = Transaction {
+            fee: 5,
+            ..tx(1, "alice", "bob", 30)
+        };
+        let greedy = Block::new(
+            1,
+            1001,
+            vec![tx(2, MINT_SENDER, "miner", 16), paid.clone()],
+            genesis().hash,
+        );
+        assert_eq!(
+            ledger.apply_block(&greedy),
+            Err(BlockChainError::RewardTooLarge {
+                allowed: 15,
+                claimed: 16
+            })
+        );
+        assert_eq!(ledger, before);
+
+        let fair = Block::new(
+            1,
+            1001,
+            vec![tx(2, MINT_SENDER, "miner", 15), paid],
+            genesis().hash,
+        );
+        ledger.apply_block(&fair).unwrap();
+        assert_eq!(ledger.total_supply(), 110);
+    }
+
+    #[test]
+    fn test_revert_restores_previous_state() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&genesis()).unwrap();
+        let before = ledger.clone();
+
+        let block = Block::new(
+            1,
+            1001,
+            vec![tx(1, "alice", "bob", 40), tx(2, "bob", "alice", 15)],
+            genesis().hash,
+        );
+        ledger.apply_block(&block).unwrap();
+        ledger.revert_block(&block).unwrap();
+        assert_eq!(ledger, before);
+        assert!(ledger.revert_block(&block).is_err());
+        assert_eq!(ledger, before);
+    }
+
+    #[test]
+    fn test_state_root_proofs() {
+        let mut ledger = Ledger::new();
+        let empty_root = ledger.state_root();
+        ledger.apply_block(&genesis()).unwrap();
+        let block = Block::new(1, 1001, vec![tx(1, "alice", "bob", 30)], genesis().hash);
+        ledger.apply_block(&block).unwrap();
+        assert_ne!(ledger.state_root(), empty_root);
+
+        let root = ledger.state_root();
+        let bob = ledger.account("bob");
+        let proof = ledger.prove_account("bob");
+        assert!(verify_account(&root, "bob", Some(&bob), &proof));
+        let richer = Account { balance: 31, ..bob };
+        assert!(!verify_account(&root, "bob", Some(&richer), &proof));
+
+        let absent = ledger.prove_account("mallory");
+        assert!(verify_account(&root, "mallory", None, &absent));
+        assert!(!verify_account(&root, "bob", None, &proof));
+
+        // Reverting restores the old root, and a reloaded ledger has the same one.
+        let json = serde_json::to_string(&ledger).unwrap();
+        let reloaded: Ledger = serde_json::from_str(&json).unwrap();
+        assert_eq!(reloaded, ledger);
+        ledger.revert_block(&block).unwrap();
+        let mut only_genesis = Ledger::new();
+        only_genesis.apply_block(&genesis()).unwrap();
+        assert_eq!(ledger.state_root(), only_genesis.state_root());
+    }
+
+    #[test]
+    fn test_block_state_root_is_checked() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&genesis()).unwrap();
+        let before = ledger.clone();
+
+        let block = Block::new(1, 1001, vec![tx(1, "alice", "bob", 30)], genesis().hash);
+        let root = ledger.state_root_after(&block).unwrap();
+        let wrong = block.clone().with_state_root(before.state_root());
+        assert!(matches!(
+            ledger.apply_block(&wrong),
+            Err(BlockChainError::StateRootMismatch { .. })
+        ));
+        assert_eq!(ledger, before);
+
+        ledger
+            .apply_block(&block.with_state_root(root.clone()))
+            .unwrap();
+        assert_eq!(ledger.state_root(), root);
+    }
+
+    #[test]
+    fn test_ledger_follows_reorgs() {
+        let genesis = genesis();
+        let mut tree = ChainTree::new(
+            genesis.clone(),
+            Box::new(LongestChain),
+            Ledger::new(),
+            ConsensusParams::default(),
+            ConsensusConfig::default(),
+        )
+        .unwrap();
+
+        let a1 = Block::new(
+            1,
+            1001,
+            vec![tx(1, "alice", "bob", 50)],
+            genesis.hash.clone(),
+        );
+        tree.insert(a1).unwrap();
+        assert_eq!(tree.state().balance("bob"), 50);
+
+        let b1 = Block::new(
+            1,
+            1002,
+            vec![tx(1, "alice", "carol", 20)],
+            genesis.hash.clone(),
+        );
+        let b2 = Block::new(2, 1003, vec![tx(2, "carol", "dave", 5)], b1.hash.clone());
+        tree.insert(b1).unwrap();
+        tree.insert(b2).unwrap();
+
+        let ledger = tree.state();
+        assert_eq!(ledger.balance("bob"), 0);
+        assert_eq!(ledger.balance("alice"), 80);
+        assert_eq!(ledger.balance("carol"), 15);
+        assert_eq!(ledger.balance("dave"), 5);
+        assert_eq!(ledger.nonce("alice"), 1);
+
+        let overdraft = Block::new(
+            3,
+            1004,
+            vec![tx(3, "dave", "bob", 6)],
+            tree.tip().hash.clone(),
+        );
+        assert!(tree.insert(overdraft).is_err());
+        assert_eq!(tree.state().balance("dave"), 5);
+    }
+}
@@ -0,0 +1,294 @@
+use std::fmt;
+
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+pub mod chain;
+pub mod explorer;
+pub mod ledger;
+pub mod light;
+pub mod merkle;
+pub mod params;
+pub mod smt;
+pub mod snapshot;
+pub mod spec;
+pub mod storage;
+pub mod store;
+pub mod stream;
+pub mod template;
+pub mod timestamp;
+pub mod transfer;
+pub mod utxo;
+pub mod verify;
+pub mod versioned;
+pub mod wal;
+
+pub use chain::{ChainState, ChainTree, Reorg, TreeError};
+pub use explorer::{ExplorerIndex, Page, PageRequest, Role, TxLocation, TxRecord};
+pub use ledger::{verify_account, Account, BlockChainError, Ledger, MINT_SENDER};
+pub use light::{DifficultyBounds, HeaderError, HeaderRule, LightClient};
+pub use merkle::{MerkleProof, MerkleTree};
+pub use params::{ConsensusParams, LimitError};
+pub use smt::{SmtProof, SparseMerkleTree};
+pub use snapshot::{
+    bootstrap, load_snapshot, write_snapshot, Snapshot, SnapshotError, Snapshotter,
+};
+pub use spec::{ChainSpec, ConsensusConfig, GenesisConfig, SpecError};
+pub use storage::{FileStorage, MemoryStorage, Storage, WriteBatch};
+pub use store::BlockStore;
+pub use stream::{open_chain_file, ChainReader, StreamError, StreamErrorKind};
+pub use template::{rate_cmp, BlockTemplate, SkipReason, TemplateBuilder, TemplateError};
+pub use timestamp::{
+    median_time_past, verify_chain_timestamps, Clock, ManualClock, SystemClock, TimestampRules,
+};
+pub use transfer::{
+    export_chain, import_chain, Format, ImportSummary, Progress, TransferError, EXPORT_VERSION,
+};
+pub use utxo::{BlockUndo, OutPoint, TxIn, TxOut, UtxoError, UtxoSet, UtxoTransaction};
+pub use verify::{
+    verify_chain_report, verify_chain_report_within, BreakReason, ChainBreak, ChainReport,
+};
+pub use versioned::{Migration, VersionError, Versioned};
+pub use wal::{write_atomic, Wal};
+
+// ----------------------------
+// Data Structures
+// ----------------------------
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Transaction {
+    pub id: u32,
+    pub amount: u32,
+    pub sender: String,
+    /// Missing in chains written before the account ledger existed.
+    #[serde(default)]
+    pub receiver: String,
+    /// Paid by the sender on top of `amount` and collected by the block's
+    /// reward transaction.
+    #[serde(default)]
+    pub fee: u64,
+    /// The sender's account nonce this transaction spends: 0 for its first
+    /// transaction, 1 for the next and so on. Being part of the transaction,
+    /// it is committed in the Merkle root, so a transaction cannot be replayed
+    /// in a later block.
+    #[serde(default)]
+    pub nonce: u64,
+}
+
+/// A zero fee or nonce is left out of human-readable encodings, so
+/// transactions without them, and the Merkle roots over them, hash as they
+/// did before those fields existed. Binary encodings always carry both:
+/// bincode cannot tell a missing field from the next one.
+impl Serialize for Transaction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let compact = serializer.is_human_readable();
+        let with_fee = self.fee != 0 || !compact;
+        let with_nonce = self.nonce != 0 || !compact;
+        let len = 4 + with_fee as usize + with_nonce as usize;
+        let mut state = serializer.serialize_struct("Transaction", len)?;
+        state.serialize_field("id", &self.id)?;
+        state.serialize_field("amount", &self.amount)?;
+        state.serialize_field("sender", &self.sender)?;
+        state.serialize_field("receiver", &self.receiver)?;
+        if with_fee {
+            state.serialize_field("fee", &self.fee)?;
+        } else {
+            state.skip_field("fee")?;
+        }
+        if with_nonce {
+            state.serialize_field("nonce", &self.nonce)?;
+        } else {
+            state.skip_field("nonce")?;
+        }
+        state.end()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Block {
+    pub id: u32,
+    pub timestamp: u64,
+    pub transactions: Vec<Transaction>,
+    pub prev_hash: String,
+    /// Merkle root of `transactions`; see `merkle::merkle_root`.
+    #[serde(default)]
+    pub merkle_root: String,
+    /// Root of the account state after this block (`Ledger::state_root`).
+    /// Empty when the block was built without state; `Ledger::apply_block`
+    /// only checks it when set.
+    #[serde(default)]
+    pub state_root: String,
+    /// Number of leading zero hex digits the hash must have; 0 means no
+    /// proof of work.
+    #[serde(default)]
+    pub difficulty: u32,
+    #[serde(default)]
+    pub nonce: u64,
+    #[serde(skip)]
+    pub hash: String,
+}
+
+/// The part of a block that is hashed. Transactions are committed through
+/// `merkle_root`, so a header plus a `MerkleProof` is enough to show that a
+/// transaction is in the block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub id: u32,
+    pub timestamp: u64,
+    pub prev_hash: String,
+    pub merkle_root: String,
+    pub state_root: String,
+    pub difficulty: u32,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    /// Compute SHA-256 hash of the serialized header
+    pub fn compute_hash(&self) -> String {
+        let serialized = serde_json::to_string(self).expect("Serialization failed");
+        let mut hasher = Sha256::new();
+        hasher.update(serialized);
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+/// Hex digits in a block hash, and so the highest difficulty a block can meet.
+pub const MAX_DIFFICULTY: u32 = 64;
+
+/// Why `Block::mine` gave up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MineError {
+    /// More leading zeros than a hash has.
+    DifficultyTooHigh(u32),
+    /// Every nonce was tried and none met the difficulty.
+    NonceSpaceExhausted,
+}
+
+impl fmt::Display for MineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MineError::DifficultyTooHigh(d) => {
+                write!(f, "difficulty {} exceeds {} hex digits", d, MAX_DIFFICULTY)
+            }
+            MineError::NonceSpaceExhausted => write!(f, "no nonce meets the difficulty"),
+        }
+    }
+}
+
+impl std::error::Error for MineError {}
+
+/// True if `hash` (hex) starts with `difficulty` zero digits.
+pub fn meets_difficulty(hash: &str, difficulty: u32) -> bool {
+    hash.len() >= difficulty as usize && hash.bytes().take(difficulty as usize).all(|b| b == b'0')
+}
+
+// ----------------------------
+// Implementations
+// ----------------------------
+
+impl Block {
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            id: self.id,
+            timestamp: self.timestamp,
+            prev_hash: self.prev_hash.clone(),
+            merkle_root: self.merkle_root.clone(),
+            state_root: self.state_root.clone(),
+            difficulty: self.difficulty,
+            nonce: self.nonce,
+        }
+    }
+
+    /// Compute SHA-256 hash of the block header
+    pub fn compute_hash(&self) -> String {
+        self.header().compute_hash()
+    }
+
+    /// Commits the block to a state root and rehashes it.
+    pub fn with_state_root(mut self, state_root: String) -> Self {
+        self.state_root = state_root;
+        self.hash = self.compute_hash();
+        self
+    }
+
+    /// Sets the difficulty and searches nonces until the hash meets it.
+    pub fn mine(mut self, difficulty: u32) -> Result<Self, MineError> {
+        if difficulty > MAX_DIFFICULTY {
+            return Err(MineError::DifficultyTooHigh(difficulty));
+        }
+        self.difficulty = difficulty;
+        self.nonce = 0;
+        self.search()
+    }
+
+    /// Tries nonces from the current one up.
+    fn search(mut self) -> Result<Self, MineError> {
+        loop {
+            self.hash = self.compute_hash();
+            if meets_difficulty(&self.hash, self.difficulty) {
+                return Ok(self);
+            }
+            self.nonce = self
+                .nonce
+                .checked_add(1)
+                .ok_or(MineError::NonceSpaceExhausted)?;
+        }
+    }
+
+    /// Proof that transaction `index` is committed in `merkle_root`.
+    pub fn prove_transaction(&self, index: usize) -> Option<MerkleProof> {
+        MerkleTree::from_transactions(&self.transactions).proof(index)
+    }
+
+    /// Constructor for creating a new block with computed hash
+    pub fn new(id: u32, timestamp: u64, transactions: Vec<Transaction>, prev_hash: String) -> Self {
+        let mut block = Block {
+            id,
+            timestamp,
+            merkle_root: merkle::merkle_root(&transactions),
+            transactions,
+            prev_hash,
+            state_root: String::new(),
+            difficulty: 0,
+            nonce: 0,
+            hash: String::new(),
+        };
+        block.hash = block.compute_hash();
+        block
+    }
+}
+
+// ----------------------------
+// Helper Functions
+// ----------------------------
+
+/// Verify the integrity of a blockchain
+///
+/// Hashes are recomputed from block contents, so this also works on chains
+/// loaded with serde (where `hash` is skipped and left empty).
+pub fn verify_chain(chain: &[Block]) -> bool {
+    verify_chain_report(chain).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mine_reports_impossible_work() {
+        let block = Block::new(1, 1631234567, vec![], "0".to_string());
+        let mined = block.clone().mine(1).unwrap();
+        assert!(meets_difficulty(&mined.hash, 1));
+        assert_eq!(
+            block.clone().mine(MAX_DIFFICULTY + 1).unwrap_err(),
+            MineError::DifficultyTooHigh(65)
+        );
+
+        // The last few nonces cannot meet this difficulty in practice.
+        let mut late = block;
+        late.difficulty = 16;
+        late.nonce = u64::MAX - 3;
+        assert_eq!(late.search().unwrap_err(), MineError::NonceSpaceExhausted);
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use blockchain_traits::fork_choice::work_for_difficulty;
+
+use crate::ledger::{Account, verify_account};
+use crate::merkle::MerkleProof;
+use crate::smt::SmtProof;
+use crate::spec::ConsensusConfig;
+use crate::timestamp::{Clock, TimestampRules};
+use crate::verify::{BreakReason, check_difficulty, verify_header};
+use crate::{BlockHeader, Transaction};
+
+/// Consensus check on a header beyond linkage and proof of work, e.g. the
+/// difficulty schedule or an authority signature.
+pub trait HeaderRule {
+    fn check(&self, header: &BlockHeader, parent: &BlockHeader) -> Result<(), String>;
+}
+
+/// Difficulty must stay at or above `min_difficulty` and move by at most one
+/// step from the parent's.
+pub struct DifficultyBounds {
+    pub min_difficulty: u32,
+}
+
+impl HeaderRule for DifficultyBounds {
+    fn check(&self, header: &BlockHeader, parent: &BlockHeader) -> Result<(), String> {
+        check_difficulty(header, Some(parent), self.min_difficulty).map_err(|e| e.to_string())
+    }
+}
+
+/// The difficulty schedule of a chain spec, so a light client holds headers
+/// to the same rules as a full node.
+impl HeaderRule for ConsensusConfig {
+    fn check(&self, header: &BlockHeader, parent: &BlockHeader) -> Result<(), String> {
+        self.check_difficulty(header, Some(parent))
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    Duplicate(String),
+    UnknownParent(String),
+    Invalid(BreakReason),
+    Rule(String),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::Duplicate(hash) => write!(f, "Header already known: {}", hash),
+            HeaderError::UnknownParent(hash) => write!(f, "Unknown parent header: {}", hash),
+            HeaderError::Invalid(reason) => write!(f, "Invalid header: {}", reason),
+            HeaderError::Rule(msg) => write!(f, "Header rejected: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+#[derive(Debug, Clone)]
+pub struct HeaderEntry {
+    pub header: BlockHeader,
+    pub hash: String,
+    /// Work of this header and all its ancestors.
+    pub total_work: u128,
+}
+
+/// Follows the chain from headers alone.
+///
+/// Each header is checked for proof of work, linkage and the configured
+/// `HeaderRule`; the best chain is the one with the most cumulative work
+/// (ties keep the chain seen first). Transactions and account balances are
+/// then checked with Merkle proofs against the roots in those headers. No
+/// block bodies are kept; each header costs a few hundred bytes, so ten
+/// thousand headers fit in a few megabytes.
+pub struct LightClient<R> {
+    headers: HashMap<String, HeaderEntry>,
+    /// Hashes of the best chain, indexed by height.
+    best: Vec<String>,
+    rule: R,
+    timestamps: Option<(TimestampRules, Box<dyn Clock>)>,
+}
+
+impl<R: HeaderRule> LightClient<R> {
+    pub fn new(genesis: BlockHeader, rule: R) -> Result<Self, HeaderError> {
+        let hash = genesis.compute_hash();
+        verify_header(&genesis, &hash, None).map_err(HeaderError::Invalid)?;
+        let entry = HeaderEntry {
+            total_work: work_for_difficulty(genesis.difficulty as usize),
+            header: genesis,
+            hash: hash.clone(),
+        };
+        Ok(LightClient {
+            headers: HashMap::from([(hash.clone(), entry)]),
+            best: vec![hash],
+            rule,
+            timestamps: None,
+        })
+    }
+
+    /// Also checks each header's timestamp against the median of its
+    /// ancestors and `clock`.
+    pub fn with_timestamp_rules(
+        mut self,
+        rules: TimestampRules,
+        clock: impl Clock + 'static,
+    ) -> Self {
+        self.timestamps = Some((rules, Box::new(clock)));
+        self
+    }
+
+    /// Timestamps of `hash` and up to `count - 1` of its ancestors, oldest first.
+    fn recent_timestamps(&self, hash: &str, count: usize) -> Vec<u64> {
+        let mut timestamps = Vec::with_capacity(count);
+        let mut cursor = self.headers.get(hash);
+        while let Some(entry) = cursor {
+            if timestamps.len() == count {
+                break;
+            }
+            timestamps.push(entry.header.timestamp);
+            cursor = self.headers.get(&entry.header.prev_hash);
+        }
+        timestamps.reverse();
+        timestamps
+    }
+
+    /// Validates and stores a header. Returns `true` if it became the new tip.
+    pub fn submit_header(&mut self, header: BlockHeader) -> Result<bool, HeaderError> {
+        let hash = header.compute_hash();
+        if self.headers.contains_key(&hash) {
+            return Err(HeaderError::Duplicate(hash));
+        }
+        let parent = self
+            .headers
+            .get(&header.prev_hash)
+            .ok_or_else(|| HeaderError::UnknownParent(header.prev_hash.clone()))?;
+
+        verify_header(&header, &hash, Some((&parent.header, &parent.hash)))
+            .map_err(HeaderError::Invalid)?;
+        self.rule
+            .check(&header, &parent.header)
+            .map_err(HeaderError::Rule)?;
+        if let Some((rules, clock)) = &self.timestamps {
+            let ancestors = self.recent_timestamps(&parent.hash, rules.median_window);
+            rules
+                .check(header.timestamp, &ancestors, clock.as_ref())
+                .map_err(HeaderError::Invalid)?;
+        }
+
+        let total_work = parent
+            .total_work
+            .saturating_add(work_for_difficulty(header.difficulty as usize));
+        let better = total_work > self.tip().total_work;
+        self.headers.insert(
+            hash.clone(),
+            HeaderEntry {
+                header,
+                hash: hash.clone(),
+                total_work,
+            },
+        );
+        if better {
+            self.reorg_to(hash);
+        }
+        Ok(better)
+    }
+
+    /// Submits headers in order, stopping at the first invalid one. Returns
+    /// how many were accepted.
+    pub fn submit_headers(
+        &mut self,
+        headers: impl IntoIterator<Item = BlockHeader>,
+    ) -> Result<usize, HeaderError> {
+        let mut accepted = 0;
+        for header in headers {
+            self.submit_header(header)?;
+            accepted += 1;
+        }
+        Ok(accepted)
+    }
+
+    /// Rewrites `best` from `tip` back to where it meets the current chain.
+    fn reorg_to(&mut self, tip: String) {
+        let mut branch = Vec::new();
+        let mut cursor = tip;
+        loop {
+            let entry = &self.headers[&cursor];
+            let height = entry.header.id as usize;
+            if self.best.get(height) == Some(&cursor) {
+                break;
+            }
+            let parent = entry.header.prev_hash.clone();
+            branch.push(cursor);
+            if height == 0 {
+                break;
+            }
+            cursor = parent;
+        }
+        let lowest = branch.last().expect("new tip is not on the best chain");
+        let fork_height = self.headers[lowest].header.id as usize;
+        self.best.truncate(fork_height);
+        self.best.extend(branch.into_iter().rev());
+    }
+
+    pub fn tip(&self) -> &HeaderEntry {
+        &self.headers[self.best.last().expect("best chain has genesis")]
+    }
+
+    pub fn height(&self) -> u32 {
+        self.tip().header.id
+    }
+
+    pub fn header(&self, hash: &str) -> Option<&BlockHeader> {
+        self.headers.get(hash).map(|e| &e.header)
+    }
+
+    /// Header at `height` on the best chain.
+    pub fn header_at(&self, height: u32) -> Option<&BlockHeader> {
+        let hash = self.best.get(height as usize)?;
+        self.header(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// Confirmations of `block_hash`: 1 for the tip, `None` if it is unknown or
+    /// not on the best chain.
+    pub fn confirmations(&self, block_hash: &str) -> Option<u32> {
+        let height = self.headers.get(block_hash)?.header.id;
+        (self.best.get(height as usize)? == block_hash).then(|| self.height() - height + 1)
+    }
+
+    /// Checks that `tx` is in block `block_hash` on the best chain. Returns the
+    /// number of confirmations, or `None` if the proof does not hold.
+    pub fn verify_transaction(
+        &self,
+        tx: &Transaction,
+        proof: &MerkleProof,
+        block_hash: &str,
+    ) -> Option<u32> {
+        let confirmations = self.confirmations(block_hash)?;
+        let header = self.header(block_hash)?;
+        proof
+            .verify_transaction(tx, &header.merkle_root)
+            .then_some(confirmations)
+    }
+
+    /// Checks an account (or its absence) against the state root of block
+    /// `block_hash` on the best chain.
+    pub fn verify_account(
+        &self,
+        address: &str,
+        account: Option<&Account>,
+        proof: &SmtProof,
+        block_hash: &str,
+    ) -> bool {
+        self.confirmations(block_hash).is_some()
+            && self
+                .header(block_hash)
+                .is_some_and(|h| verify_account(&h.state_root, address, account, proof))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Block;
+    use crate::timestamp::ManualClock;
+
+    fn tx(id: u32) -> Transaction {
+        Transaction {
+            id,
+            amount: id + 1,
+            sender: "alice".to_string(),
+            receiver: "bob".to_string(),
+            fee: 0,
+            nonce: 0,
+        }
+    }
+
+    fn genesis() -> Block {
+        Block::new(0, 1000, vec![tx(0)], "0".to_string())
+            .mine(1)
+            .unwrap()
+    }
+
+    fn extend(parent: &Block, len: u32, difficulty: u32, tag: u32) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        let mut parent = parent.clone();
+        for i in 0..len {
+            let txs = vec![tx(tag * 100 + i), tx(tag * 100 + i + 50)];
+            let block = Block::new(
+                parent.id + 1,
+                parent.timestamp + 1,
+                txs,
+                parent.hash.clone(),
+            )
+            .mine(difficulty)
+            .unwrap();
+            parent = block.clone();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    fn client() -> LightClient<DifficultyBounds> {
+        LightClient::new(genesis().header(), DifficultyBounds { min_difficulty: 1 }).unwrap()
+    }
+
+    #[test]
+    fn test_follows_most_work() {
+        let genesis = genesis();
+        let mut client = client();
+        let main = extend(&genesis, 20, 1, 1);
+        client
+            .submit_headers(main.iter().map(Block::header))
+            .unwrap();
+        assert_eq!(client.height(), 20);
+
+        // A much shorter branch with more work per block takes over once its
+        // total work is higher: 16 * 2 + 256 * 2 > 16 * 21.
+        let heavy = extend(&main[0], 2, 2, 2);
+        assert!(!client.submit_header(heavy[0].header()).unwrap());
+        assert!(client.submit_header(heavy[1].header()).unwrap());
+        assert_eq!(client.tip().hash, heavy[1].hash);
+        assert_eq!(client.height(), 3);
+        assert_eq!(client.header_at(1).unwrap(), &main[0].header());
+        assert_eq!(client.header_at(2).unwrap(), &heavy[0].header());
+        assert_eq!(client.confirmations(&main[19].hash), None);
+        assert_eq!(client.confirmations(&main[0].hash), Some(3));
+    }
+
+    #[test]
+    fn test_rejects_bad_headers() {
+        let genesis = genesis();
+        let mut client = client();
+        let good = extend(&genesis, 1, 1, 1).remove(0);
+        client.submit_header(good.header()).unwrap();
+        assert_eq!(
+            client.submit_header(good.header()),
+            Err(HeaderError::Duplicate(good.hash.clone()))
+        );
+
+        let mut unmined = Block::new(2, 2000, vec![tx(9)], good.hash.clone());
+        unmined.difficulty = 4;
+        assert!(matches!(
+            client.submit_header(unmined.header()),
+            Err(HeaderError::Invalid(BreakReason::InsufficientWork {
+                difficulty: 4
+            }))
+        ));
+
+        let easy = Block::new(2, 2000, vec![tx(9)], good.hash.clone());
+        assert!(matches!(
+            client.submit_header(easy.header()),
+            Err(HeaderError::Rule(_))
+        ));
+
+        let jump = Block::new(2, 2000, vec![tx(9)], good.hash.clone())
+            .mine(3)
+            .unwrap();
+        assert!(matches!(
+            client.submit_header(jump.header()),
+            Err(HeaderError::Rule(_))
+        ));
+
+        let orphan = Block::new(5, 2000, vec![], "f".repeat(64)).mine(1).unwrap();
+        assert!(matches!(
+            client.submit_header(orphan.header()),
+            Err(HeaderError::UnknownParent(_))
+        ));
+        assert_eq!(client.len(), 2);
+    }
+
+    #[test]
+    fn test_timestamp_rules() {
+        let genesis = genesis();
+        let rules = TimestampRules {
+            median_window: 3,
+            max_future_drift: 100,
+        };
+        let mut client = client().with_timestamp_rules(rules, ManualClock::new(1000));
+        let main = extend(&genesis, 3, 1, 1);
+        client
+            .submit_headers(main.iter().map(Block::header))
+            .unwrap();
+
+        // Ancestors are 1001..=1003, so the median is 1002 and repeating the
+        // parent's time is allowed once.
+        let same = Block::new(4, 1003, vec![tx(7)], main[2].hash.clone())
+            .mine(1)
+            .unwrap();
+        assert!(client.submit_header(same.header()).unwrap());
+        // Now the last three are 1002, 1003, 1003.
+        let stale = Block::new(5, 1003, vec![tx(8)], same.hash.clone())
+            .mine(1)
+            .unwrap();
+        assert!(matches!(
+            client.submit_header(stale.header()),
+            Err(HeaderError::Invalid(BreakReason::TimestampNotAfterMedian {
+                median: 1003,
+                ..
+            }))
+        ));
+
+        let ahead = Block::new(5, 1101, vec![tx(8)], same.hash.clone())
+            .mine(1)
+            .unwrap();
+        assert!(matches!(
+            client.submit_header(ahead.header()),
+            Err(HeaderError::Invalid(
+                BreakReason::TimestampTooFarAhead { .. }
+            ))
+        ));
+        let ok = Block::new(5, 1100, vec![tx(8)], same.hash.clone())
+            .mine(1)
+            .unwrap();
+        assert!(client.submit_header(ok.header()).unwrap());
+    }
+
+    #[test]
+    fn test_verifies_transactions_against_headers() {
+        let genesis = genesis();
+        let mut client = client();
+        let main = extend(&genesis, 3, 1, 1);
+        client
+            .submit_headers(main.iter().map(Block::header))
+            .unwrap();
+
+        let block = &main[1];
+        let proof = block.prove_transaction(1).unwrap();
+        assert_eq!(
+            client.verify_transaction(&block.transactions[1], &proof, &block.hash),
+            Some(2)
+        );
+        assert_eq!(
+            client.verify_transaction(&block.transactions[0], &proof, &block.hash),
+            None
+        );
+        assert_eq!(
+            client.verify_transaction(&block.transactions[1], &proof, &main[2].hash),
+            None
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::Transaction;
+
+pub type Hash = [u8; 32];
+
+/// Root of a block with no transactions.
+pub const EMPTY_ROOT: Hash = [0u8; 32];
+
+// Leaves and inner nodes are hashed with different prefixes, so an inner node
+// can never be passed off as a leaf (or the other way round).
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+pub fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+pub fn transaction_leaf(tx: &Transaction) -> Hash {
+    leaf_hash(&serde_json::to_vec(tx).expect("Serialization failed"))
+}
+
+/// Binary Merkle tree over a list of leaves.
+///
+/// Unlike Bitcoin, a node without a sibling is carried up to the next level
+/// unchanged instead of being paired with a copy of itself. Duplicating the
+/// last node is what lets `[a, b, c]` and `[a, b, c, c]` share a root
+/// (CVE-2012-2459); here every leaf list has its own root.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    /// `levels[0]` are the leaves, the last level holds the root.
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<Hash>) -> Self {
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { levels }
+    }
+
+    pub fn from_transactions(txs: &[Transaction]) -> Self {
+        Self::new(txs.iter().map(transaction_leaf).collect())
+    }
+
+    pub fn root(&self) -> Hash {
+        self.levels
+            .last()
+            .unwrap()
+            .first()
+            .copied()
+            .unwrap_or(EMPTY_ROOT)
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels[0].is_empty()
+    }
+
+    /// Proof that leaf `index` is part of this tree.
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.len() {
+            return None;
+        }
+        let mut siblings = Vec::new();
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(hex::encode(sibling));
+            }
+            position /= 2;
+        }
+        Some(MerkleProof {
+            index: index as u32,
+            leaf_count: self.len() as u32,
+            siblings,
+        })
+    }
+}
+
+/// Hex-encoded root of `txs`, as stored in `Block::merkle_root`.
+pub fn merkle_root(txs: &[Transaction]) -> String {
+    hex::encode(MerkleTree::from_transactions(txs).root())
+}
+
+/// Sibling hashes from a leaf up to the root.
+///
+/// The leaf count fixes the shape of the tree, so the verifier knows at every
+/// level whether the node has a sibling or was carried up alone.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index: u32,
+    pub leaf_count: u32,
+    /// Hex-encoded, lowest level first.
+    pub siblings: Vec<String>,
+}
+
+impl MerkleProof {
+    /// Recomputes the root from the leaf's data and compares it to `root` (hex).
+    ///
+    /// Takes the data rather than a leaf hash so that an inner node can never
+    /// be presented as a leaf.
+    pub fn verify(&self, data: &[u8], root: &str) -> bool {
+        self.verify_leaf(leaf_hash(data), root)
+    }
+
+    fn verify_leaf(&self, leaf: Hash, root: &str) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+        let mut siblings = self.siblings.iter();
+        let mut hash = leaf;
+        let mut position = self.index;
+        let mut width = self.leaf_count;
+
+        while width > 1 {
+            if position ^ 1 < width {
+                let Some(sibling) = siblings.next().and_then(|s| decode_hash(s)) else {
+                    return false;
+                };
+                hash = if position.is_multiple_of(2) {
+                    node_hash(&hash, &sibling)
+                } else {
+                    node_hash(&sibling, &hash)
+                };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+
+        siblings.next().is_none() && hex::encode(hash) == root
+    }
+
+    pub fn verify_transaction(&self, tx: &Transaction, root: &str) -> bool {
+        self.verify_leaf(transaction_leaf(tx), root)
+    }
+}
+
+fn decode_hash(s: &str) -> Option<Hash> {
+    hex::decode(s).ok()?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: u8) -> Vec<Hash> {
+        (0..n).map(|i| leaf_hash(&[i])).collect()
+    }
+
+    fn tx(id: u32) -> Transaction {
+        Transaction {
+            id,
+            amount: id * 10,
+            sender: "alice".to_string(),
+            receiver: "bob".to_string(),
+            fee: 0,
+            nonce: 0,
+        }
+    }
+
+    #[test]
+    fn test_proofs_for_every_leaf_and_size() {
+        for n in 1..=9 {
+            let tree = MerkleTree::new(leaves(n));
+            let root = hex::encode(tree.root());
+            for i in 0..n {
+                let proof = tree.proof(i as usize).unwrap();
+                assert!(proof.verify(&[i], &root), "leaf {} of {}", i, n);
+                assert!(!proof.verify(b"other", &root));
+            }
+            assert!(tree.proof(n as usize).is_none());
+        }
+        assert_eq!(MerkleTree::new(vec![]).root(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_duplicated_last_leaf_changes_root() {
+        let three = leaves(3);
+        let mut four = three.clone();
+        four.push(three[2]);
+        assert_ne!(MerkleTree::new(three).root(), MerkleTree::new(four).root());
+
+        let mut six = leaves(6);
+        let five = six[..5].to_vec();
+        six[5] = six[4];
+        assert_ne!(MerkleTree::new(five).root(), MerkleTree::new(six).root());
+    }
+
+    #[test]
+    fn test_rejects_forged_proofs() {
+        let tree = MerkleTree::new(leaves(5));
+        let root = hex::encode(tree.root());
+        let leaf = [4u8];
+        let proof = tree.proof(4).unwrap();
+
+        // The last leaf of five has a single sibling; claiming a sixth leaf
+        // would need another one.
+        let wider = MerkleProof {
+            leaf_count: 6,
+            ..proof.clone()
+        };
+        assert!(!wider.verify(&leaf, &root));
+
+        let mut extra = proof.clone();
+        extra.siblings.push(hex::encode([7u8; 32]));
+        assert!(!extra.verify(&leaf, &root));
+
+        // The two leaves under an inner node can't be passed off as one leaf.
+        let mut inner = leaves(5)[0].to_vec();
+        inner.extend(leaves(5)[1]);
+        let short = MerkleProof {
+            index: 0,
+            leaf_count: 3,
+            siblings: tree.proof(0).unwrap().siblings[1..].to_vec(),
+        };
+        assert!(!short.verify(&inner, &root));
+    }
+
+    #[test]
+    fn test_transaction_proofs() {
+        let txs: Vec<Transaction> = (0..7).map(tx).collect();
+        let root = merkle_root(&txs);
+        let proof = MerkleTree::from_transactions(&txs).proof(3).unwrap();
+        assert!(proof.verify_transaction(&txs[3], &root));
+        assert!(!proof.verify_transaction(&txs[4], &root));
+
+        let json = serde_json::to_string(&proof).unwrap();
+        let decoded: MerkleProof = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, proof);
+        assert_eq!(merkle_root(&[]), hex::encode(EMPTY_ROOT));
+    }
+}
@@ -0,0 +1,222 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Block;
+
+/// Size limits and the block subsidy every node must agree on, plus the
+/// mempool capacity so one file configures the whole node. Sizes are measured
+/// on the JSON encoding, the same one blocks are hashed in.
+///
+/// Loaded from the `params` section of a chain spec; any field left out
+/// takes its default.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsensusParams {
+    pub max_block_bytes: usize,
+    pub max_block_transactions: usize,
+    pub max_transaction_bytes: usize,
+    /// Free-form data carried by a block or transaction.
+    pub max_payload_bytes: usize,
+    /// Not a consensus rule: how many transactions a node's mempool holds.
+    pub mempool_capacity: usize,
+    /// Coins a block's reward transaction may mint on top of the block's fees.
+    pub block_subsidy: u64,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        ConsensusParams {
+            max_block_bytes: 1_000_000,
+            max_block_transactions: 5_000,
+            max_transaction_bytes: 1_000,
+            max_payload_bytes: 1_000,
+            mempool_capacity: 10_000,
+            block_subsidy: 50,
+        }
+    }
+}
+
+/// A consensus limit that was exceeded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitError {
+    BlockTooLarge { bytes: usize, max: usize },
+    TooManyTransactions { count: usize, max: usize },
+    TransactionTooLarge { bytes: usize, max: usize },
+    PayloadTooLarge { bytes: usize, max: usize },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitError::BlockTooLarge { bytes, max } => {
+                write!(f, "block is {} bytes, limit is {}", bytes, max)
+            }
+            LimitError::TooManyTransactions { count, max } => {
+                write!(f, "block has {} transactions, limit is {}", count, max)
+            }
+            LimitError::TransactionTooLarge { bytes, max } => {
+                write!(f, "transaction is {} bytes, limit is {}", bytes, max)
+            }
+            LimitError::PayloadTooLarge { bytes, max } => {
+                write!(f, "payload is {} bytes, limit is {}", bytes, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+fn encoded_len(value: &impl Serialize) -> usize {
+    serde_json::to_vec(value)
+        .expect("Serialization failed")
+        .len()
+}
+
+impl ConsensusParams {
+    /// Rejects limits that would make every block or transaction invalid.
+    pub fn validate(&self) -> Result<(), String> {
+        let fields = [
+            ("max_block_bytes", self.max_block_bytes),
+            ("max_block_transactions", self.max_block_transactions),
+            ("max_transaction_bytes", self.max_transaction_bytes),
+            ("mempool_capacity", self.mempool_capacity),
+        ];
+        if let Some((name, _)) = fields.iter().find(|(_, value)| *value == 0) {
+            return Err(format!("{} must be positive", name));
+        }
+        if self.max_transaction_bytes > self.max_block_bytes {
+            return Err("max_transaction_bytes exceeds max_block_bytes".to_string());
+        }
+        if self.block_subsidy > u32::MAX as u64 {
+            return Err("block_subsidy exceeds the largest transaction amount".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn check_payload(&self, payload: &[u8]) -> Result<(), LimitError> {
+        if payload.len() > self.max_payload_bytes {
+            return Err(LimitError::PayloadTooLarge {
+                bytes: payload.len(),
+                max: self.max_payload_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks the encoded size of any transaction type and returns it.
+    pub fn check_transaction(&self, tx: &impl Serialize) -> Result<usize, LimitError> {
+        let bytes = encoded_len(tx);
+        if bytes > self.max_transaction_bytes {
+            return Err(LimitError::TransactionTooLarge {
+                bytes,
+                max: self.max_transaction_bytes,
+            });
+        }
+        Ok(bytes)
+    }
+
+    pub fn check_block(&self, block: &Block) -> Result<(), LimitError> {
+        let count = block.transactions.len();
+        if count > self.max_block_transactions {
+            return Err(LimitError::TooManyTransactions {
+                count,
+                max: self.max_block_transactions,
+            });
+        }
+        for tx in &block.transactions {
+            self.check_transaction(tx)?;
+        }
+        let bytes = encoded_len(block);
+        if bytes > self.max_block_bytes {
+            return Err(LimitError::BlockTooLarge {
+                bytes,
+                max: self.max_block_bytes,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transaction;
+
+    fn tx(id: u32, sender: &str) -> Transaction {
+        Transaction {
+            id,
+            amount: 1,
+            sender: sender.to_string(),
+            receiver: "bob".to_string(),
+            fee: 0,
+            nonce: 0,
+        }
+    }
+
+    #[test]
+    fn test_block_limits() {
+        let params = ConsensusParams {
+            max_block_bytes: 600,
+            max_block_transactions: 3,
+            max_transaction_bytes: 100,
+            ..ConsensusParams::default()
+        };
+        let txs: Vec<Transaction> = (0..3).map(|i| tx(i, "alice")).collect();
+        let block = Block::new(1, 1000, txs.clone(), "0".to_string());
+        assert_eq!(params.check_block(&block), Ok(()));
+
+        let mut four = txs.clone();
+        four.push(tx(3, "alice"));
+        let block = Block::new(1, 1000, four, "0".to_string());
+        assert_eq!(
+            params.check_block(&block),
+            Err(LimitError::TooManyTransactions { count: 4, max: 3 })
+        );
+
+        let big = Block::new(1, 1000, vec![tx(0, &"a".repeat(200))], "0".to_string());
+        assert!(matches!(
+            params.check_block(&big),
+            Err(LimitError::TransactionTooLarge { max: 100, .. })
+        ));
+
+        let tight = ConsensusParams {
+            max_block_bytes: 300,
+            ..params
+        };
+        let block = Block::new(1, 1000, txs, "0".to_string());
+        assert!(matches!(
+            tight.check_block(&block),
+            Err(LimitError::BlockTooLarge { max: 300, .. })
+        ));
+    }
+
+    #[test]
+    fn test_payload_and_validation() {
+        let params = ConsensusParams::default();
+        assert!(params.check_payload(&[0; 1000]).is_ok());
+        assert_eq!(
+            params.check_payload(&[0; 1001]),
+            Err(LimitError::PayloadTooLarge {
+                bytes: 1001,
+                max: 1000
+            })
+        );
+        assert!(params.validate().is_ok());
+        let zero = ConsensusParams {
+            max_block_transactions: 0,
+            ..params
+        };
+        assert!(zero.validate().is_err());
+        let rich = ConsensusParams {
+            block_subsidy: u32::MAX as u64 + 1,
+            ..params
+        };
+        assert!(rich.validate().is_err());
+
+        let partial: ConsensusParams =
+            serde_json::from_str(r#"{"max_block_transactions": 7}"#).unwrap();
+        assert_eq!(partial.max_block_transactions, 7);
+        assert_eq!(partial.max_block_bytes, params.max_block_bytes);
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::merkle::Hash;
+
+/// Depth of the tree: one level per bit of a SHA-256 key.
+pub const DEPTH: usize = 256;
+
+/// Hash of an empty subtree at any height.
+///
+/// An inner node whose children are both empty is itself empty, so the tree
+/// only stores nodes above at least one leaf and an empty tree has this root.
+pub const EMPTY: Hash = [0u8; 32];
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// Position of a key in the tree.
+pub fn key_hash(key: &[u8]) -> Hash {
+    Sha256::digest(key).into()
+}
+
+fn leaf_hash(path: &Hash, value: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(path);
+    hasher.update(value);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    if left == &EMPTY && right == &EMPTY {
+        return EMPTY;
+    }
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Bit `height` of `path`, counting from the least significant bit. It picks
+/// the left (0) or right (1) child below height `height + 1`.
+fn bit(path: &Hash, height: usize) -> bool {
+    path[31 - height / 8] >> (height % 8) & 1 == 1
+}
+
+/// `path` with its lowest `height` bits cleared: the id of the subtree at
+/// `height` that contains `path`.
+fn prefix(path: &Hash, height: usize) -> Hash {
+    let mut out = *path;
+    for byte in &mut out[32 - height / 8..] {
+        *byte = 0;
+    }
+    if height < DEPTH {
+        out[31 - height / 8] &= 0xff << (height % 8);
+    }
+    out
+}
+
+fn with_bit(path: &Hash, height: usize) -> Hash {
+    let mut out = *path;
+    out[31 - height / 8] |= 1 << (height % 8);
+    out
+}
+
+/// Sparse Merkle tree over 256-bit key hashes.
+///
+/// Every possible key has a leaf, so a key that is absent simply has an empty
+/// leaf, and proving it is absent works exactly like proving a value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SparseMerkleTree {
+    /// Non-empty nodes keyed by (height, prefix). Height 0 holds the leaves.
+    nodes: HashMap<(u16, Hash), Hash>,
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn node(&self, height: usize, prefix: &Hash) -> Hash {
+        self.nodes
+            .get(&(height as u16, *prefix))
+            .copied()
+            .unwrap_or(EMPTY)
+    }
+
+    fn set_node(&mut self, height: usize, prefix: Hash, hash: Hash) {
+        if hash == EMPTY {
+            self.nodes.remove(&(height as u16, prefix));
+        } else {
+            self.nodes.insert((height as u16, prefix), hash);
+        }
+    }
+
+    pub fn root(&self) -> Hash {
+        self.node(DEPTH, &EMPTY)
+    }
+
+    pub fn root_hex(&self) -> String {
+        hex::encode(self.root())
+    }
+
+    /// Sets (`Some`) or removes (`None`) one key.
+    pub fn update(&mut self, key: &[u8], value: Option<&[u8]>) {
+        self.update_batch([(key, value)]);
+    }
+
+    /// Applies many updates at once. Each inner node above the changed leaves
+    /// is rehashed once, however many of the updated keys sit below it.
+    pub fn update_batch<'a>(
+        &mut self,
+        updates: impl IntoIterator<Item = (&'a [u8], Option<&'a [u8]>)>,
+    ) {
+        let mut dirty = BTreeSet::new();
+        for (key, value) in updates {
+            let path = key_hash(key);
+            let leaf = value.map_or(EMPTY, |v| leaf_hash(&path, v));
+            self.set_node(0, path, leaf);
+            dirty.insert(path);
+        }
+
+        for height in 0..DEPTH {
+            let parents: BTreeSet<Hash> = dirty.iter().map(|p| prefix(p, height + 1)).collect();
+            for parent in &parents {
+                let left = self.node(height, parent);
+                let right = self.node(height, &with_bit(parent, height));
+                self.set_node(height + 1, *parent, node_hash(&left, &right));
+            }
+            dirty = parents;
+        }
+    }
+
+    /// Proof for `key`, whether or not it has a value.
+    pub fn prove(&self, key: &[u8]) -> SmtProof {
+        let path = key_hash(key);
+        let mut bitmap = [0u8; 32];
+        let mut siblings = Vec::new();
+        for height in 0..DEPTH {
+            let own = prefix(&path, height);
+            let sibling_prefix = if bit(&path, height) {
+                prefix(&own, height + 1)
+            } else {
+                with_bit(&own, height)
+            };
+            let sibling = self.node(height, &sibling_prefix);
+            if sibling != EMPTY {
+                bitmap[31 - height / 8] |= 1 << (height % 8);
+                siblings.push(hex::encode(sibling));
+            }
+        }
+        SmtProof {
+            bitmap: hex::encode(bitmap),
+            siblings,
+        }
+    }
+}
+
+/// Path from a leaf to the root. Empty siblings are left out and marked as
+/// absent in `bitmap`, so a proof in a tree of `n` keys holds about
+/// `log2(n)` hashes instead of 256.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SmtProof {
+    /// Hex-encoded 256-bit map; bit `h` is set when the sibling at height `h`
+    /// is non-empty.
+    pub bitmap: String,
+    /// Hex-encoded non-empty siblings, lowest first.
+    pub siblings: Vec<String>,
+}
+
+impl SmtProof {
+    /// Checks that `key` maps to `value` under `root` (hex). `None` checks
+    /// that the key is absent.
+    pub fn verify(&self, root: &str, key: &[u8], value: Option<&[u8]>) -> bool {
+        let Some(bitmap) = hex::decode(&self.bitmap)
+            .ok()
+            .and_then(|b| Hash::try_from(b).ok())
+        else {
+            return false;
+        };
+
+        let path = key_hash(key);
+        let mut siblings = self.siblings.iter();
+        let mut hash = value.map_or(EMPTY, |v| leaf_hash(&path, v));
+        for height in 0..DEPTH {
+            let sibling = if bit(&bitmap, height) {
+                match siblings
+                    .next()
+                    .and_then(|s| hex::decode(s).ok())
+                    .and_then(|b| Hash::try_from(b).ok())
+                {
+                    Some(sibling) => sibling,
+                    None => return false,
+                }
+            } else {
+                EMPTY
+            };
+            hash = if bit(&path, height) {
+                node_hash(&sibling, &hash)
+            } else {
+                node_hash(&hash, &sibling)
+            };
+        }
+
+        siblings.next().is_none() && hex::encode(hash) == root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(n: u32) -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        for i in 0..n {
+            tree.update(format!("key{}", i).as_bytes(), Some(&i.to_le_bytes()));
+        }
+        tree
+    }
+
+    #[test]
+    fn test_root_depends_on_contents_not_order() {
+        assert_eq!(SparseMerkleTree::new().root(), EMPTY);
+
+        let a = filled(20);
+        let mut b = SparseMerkleTree::new();
+        let keys: Vec<String> = (0..20).rev().map(|i| format!("key{}", i)).collect();
+        let values: Vec<[u8; 4]> = (0..20u32).rev().map(|i| i.to_le_bytes()).collect();
+        b.update_batch(
+            keys.iter()
+                .zip(&values)
+                .map(|(k, v)| (k.as_bytes(), Some(&v[..]))),
+        );
+        assert_eq!(a, b);
+
+        let mut c = a.clone();
+        c.update(b"key3", Some(b"changed"));
+        assert_ne!(a.root(), c.root());
+        c.update(b"key3", Some(&3u32.to_le_bytes()));
+        assert_eq!(a.root(), c.root());
+
+        c.update(b"extra", Some(b"x"));
+        c.update(b"extra", None);
+        assert_eq!(a, c);
+    }
+
+    #[test]
+    fn test_inclusion_and_non_inclusion_proofs() {
+        let tree = filled(50);
+        let root = tree.root_hex();
+
+        let proof = tree.prove(b"key7");
+        assert!(proof.verify(&root, b"key7", Some(&7u32.to_le_bytes())));
+        assert!(!proof.verify(&root, b"key7", Some(&8u32.to_le_bytes())));
+        assert!(!proof.verify(&root, b"key7", None));
+        assert!(proof.siblings.len() < 20);
+
+        let absent = tree.prove(b"missing");
+        assert!(absent.verify(&root, b"missing", None));
+        assert!(!absent.verify(&root, b"missing", Some(b"x")));
+
+        let json = serde_json::to_string(&absent).unwrap();
+        let decoded: SmtProof = serde_json::from_str(&json).unwrap();
+        assert!(decoded.verify(&root, b"missing", None));
+    }
+
+    #[test]
+    fn test_tampered_proofs_fail() {
+        let tree = filled(10);
+        let root = tree.root_hex();
+        let mut proof = tree.prove(b"key1");
+
+        let mut extra = proof.clone();
+        extra.siblings.push(hex::encode([9u8; 32]));
+        assert!(!extra.verify(&root, b"key1", Some(&1u32.to_le_bytes())));
+
+        proof.siblings[0] = hex::encode([9u8; 32]);
+        assert!(!proof.verify(&root, b"key1", Some(&1u32.to_le_bytes())));
+
+        // A proof for one key does not prove another.
+        let proof = tree.prove(b"key1");
+        assert!(!proof.verify(&root, b"key2", Some(&1u32.to_le_bytes())));
+    }
+}
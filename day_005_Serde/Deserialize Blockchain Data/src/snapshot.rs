@@ -0,0 +1,614 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::ledger::{Account, BlockChainError, Ledger};
+use crate::params::ConsensusParams;
+use crate::spec::ConsensusConfig;
+use crate::verify::{BreakReason, verify_block_within};
+use crate::versioned::{self, Migration, Versioned};
+use crate::wal::sync_dir;
+use crate::{Block, BlockHeader};
+
+const MANIFEST: &str = "manifest.json";
+const PREFIX: &str = "snapshot-";
+
+/// Describes a snapshot directory. Written last, so a directory with a
+/// manifest is complete.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotManifest {
+    pub height: u32,
+    pub block_hash: String,
+    /// State root of the ledger after `height`, checked again on load.
+    pub state_root: String,
+    pub accounts: u64,
+    pub chunks: Vec<ChunkInfo>,
+}
+
+impl Versioned for SnapshotManifest {
+    const KIND: &'static str = "snapshot-manifest";
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkInfo {
+    pub file: String,
+    /// Hex SHA-256 of the chunk file.
+    pub sha256: String,
+    pub accounts: u64,
+}
+
+/// The accounts in one chunk file, sorted by address.
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+struct SnapshotChunk(Vec<(String, Account)>);
+
+impl Versioned for SnapshotChunk {
+    const KIND: &'static str = "snapshot-chunk";
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+/// A loaded and verified snapshot.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub manifest: SnapshotManifest,
+    pub ledger: Ledger,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Decode(String),
+    ChunkHash {
+        file: String,
+    },
+    StateRootMismatch {
+        expected: String,
+        computed: String,
+    },
+    /// The header the snapshot is anchored to does not match it.
+    Anchor(String),
+    /// A block after the snapshot failed verification.
+    Block {
+        height: u32,
+        reason: BreakReason,
+    },
+    /// A block after the snapshot could not be applied to the state.
+    Ledger {
+        height: u32,
+        error: BlockChainError,
+    },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "I/O error: {}", e),
+            SnapshotError::Decode(msg) => write!(f, "cannot decode snapshot: {}", msg),
+            SnapshotError::ChunkHash { file } => write!(f, "chunk {} is corrupt", file),
+            SnapshotError::StateRootMismatch { expected, computed } => write!(
+                f,
+                "snapshot state root is {} but its accounts give {}",
+                expected, computed
+            ),
+            SnapshotError::Anchor(msg) => write!(f, "snapshot does not match the chain: {}", msg),
+            SnapshotError::Block { height, reason } => write!(f, "block {}: {}", height, reason),
+            SnapshotError::Ledger { height, error } => write!(f, "block {}: {}", height, error),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<versioned::VersionError> for SnapshotError {
+    fn from(e: versioned::VersionError) -> Self {
+        SnapshotError::Decode(e.to_string())
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Writes the state after `block` to `dir/snapshot-<height>/`.
+///
+/// Accounts are sorted by address and split into chunks of `chunk_size`
+/// entries, so the same state always produces the same files. The manifest
+/// and chunks are versioned documents (see `Versioned`). Everything is
+/// written to a temporary directory first and renamed into place, so a crash
+/// never leaves a half-written snapshot behind. A snapshot already at that
+/// height is renamed aside rather than deleted before the swap, so a crash
+/// in between still leaves it readable.
+pub fn write_snapshot(
+    dir: impl AsRef<Path>,
+    block: &Block,
+    ledger: &Ledger,
+    chunk_size: usize,
+) -> Result<PathBuf, SnapshotError> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let target = plain_path(dir, block.id);
+    let aside = aside_path(dir, block.id);
+    let tmp = dir.join(format!("{}{:010}.tmp", PREFIX, block.id));
+    if tmp.exists() {
+        fs::remove_dir_all(&tmp)?;
+    }
+    fs::create_dir(&tmp)?;
+
+    let mut accounts: Vec<(String, Account)> = ledger
+        .accounts()
+        .map(|(address, account)| (address.to_string(), *account))
+        .collect();
+    accounts.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut chunks = Vec::new();
+    for (i, chunk) in accounts.chunks(chunk_size.max(1)).enumerate() {
+        let file = format!("chunk-{:04}.json", i);
+        let data = versioned::encode(&SnapshotChunk(chunk.to_vec()))?;
+        write_synced(&tmp.join(&file), &data)?;
+        chunks.push(ChunkInfo {
+            file,
+            sha256: sha256_hex(&data),
+            accounts: chunk.len() as u64,
+        });
+    }
+
+    let manifest = SnapshotManifest {
+        height: block.id,
+        block_hash: block.compute_hash(),
+        state_root: ledger.state_root(),
+        accounts: accounts.len() as u64,
+        chunks,
+    };
+    let data = versioned::encode_pretty(&manifest)?;
+    write_synced(&tmp.join(MANIFEST), data.as_bytes())?;
+    sync_dir(&tmp)?;
+
+    if target.exists() {
+        if aside.exists() {
+            fs::remove_dir_all(&aside)?;
+        }
+        fs::rename(&target, &aside)?;
+    }
+    fs::rename(&tmp, &target)?;
+    sync_dir(dir)?;
+    if aside.exists() {
+        fs::remove_dir_all(&aside)?;
+    }
+    Ok(target)
+}
+
+fn plain_path(dir: &Path, height: u32) -> PathBuf {
+    dir.join(format!("{}{:010}", PREFIX, height))
+}
+
+fn aside_path(dir: &Path, height: u32) -> PathBuf {
+    dir.join(format!("{}{:010}.old", PREFIX, height))
+}
+
+/// Where the snapshot of `height` lives: its own directory, unless a crash
+/// in `write_snapshot` left only the copy that was set aside.
+fn snapshot_path(dir: &Path, height: u32) -> PathBuf {
+    let path = plain_path(dir, height);
+    let aside = aside_path(dir, height);
+    if !path.join(MANIFEST).exists() && aside.join(MANIFEST).exists() {
+        aside
+    } else {
+        path
+    }
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Reads a snapshot directory, checking every chunk against the manifest and
+/// the rebuilt state against its state root.
+pub fn load_snapshot(path: impl AsRef<Path>) -> Result<Snapshot, SnapshotError> {
+    let path = path.as_ref();
+    let manifest: SnapshotManifest = versioned::decode(&fs::read(path.join(MANIFEST))?)?;
+
+    let mut accounts = Vec::with_capacity(manifest.accounts as usize);
+    for chunk in &manifest.chunks {
+        let data = fs::read(path.join(&chunk.file))?;
+        if sha256_hex(&data) != chunk.sha256 {
+            return Err(SnapshotError::ChunkHash {
+                file: chunk.file.clone(),
+            });
+        }
+        let SnapshotChunk(entries) = versioned::decode(&data)?;
+        accounts.extend(entries);
+    }
+
+    let ledger = Ledger::from_accounts(accounts);
+    let computed = ledger.state_root();
+    if computed != manifest.state_root {
+        return Err(SnapshotError::StateRootMismatch {
+            expected: manifest.state_root,
+            computed,
+        });
+    }
+    Ok(Snapshot { manifest, ledger })
+}
+
+/// Rebuilds the state from a snapshot plus the blocks that follow it, instead
+/// of replaying the chain from genesis.
+///
+/// `anchor` is the header at the snapshot's height, taken from a trusted
+/// header chain (a `LightClient` or a pruned `BlockStore`). It must commit to
+/// a state root, since that is all that vouches for the accounts. Each block
+/// is verified against its parent header under the chain's `params` and
+/// `consensus` difficulty schedule and applied to the ledger. Returns the
+/// ledger and the header of the last block applied.
+pub fn bootstrap(
+    snapshot: Snapshot,
+    anchor: &BlockHeader,
+    blocks: impl IntoIterator<Item = Block>,
+    params: &ConsensusParams,
+    consensus: &ConsensusConfig,
+) -> Result<(Ledger, BlockHeader), SnapshotError> {
+    let manifest = &snapshot.manifest;
+    let anchor_hash = anchor.compute_hash();
+    if anchor.id != manifest.height || anchor_hash != manifest.block_hash {
+        return Err(SnapshotError::Anchor(format!(
+            "snapshot is of block {} ({}), anchor is block {} ({})",
+            manifest.height, manifest.block_hash, anchor.id, anchor_hash
+        )));
+    }
+    if anchor.state_root.is_empty() {
+        return Err(SnapshotError::Anchor(format!(
+            "anchor block {} commits to no state root",
+            anchor.id
+        )));
+    }
+    if anchor.state_root != manifest.state_root {
+        return Err(SnapshotError::StateRootMismatch {
+            expected: anchor.state_root.clone(),
+            computed: manifest.state_root.clone(),
+        });
+    }
+
+    let mut ledger = snapshot.ledger.with_subsidy(params.block_subsidy);
+    let mut parent = anchor.clone();
+    let mut parent_hash = anchor_hash;
+    for block in blocks {
+        let height = block.id;
+        parent_hash = verify_block_within(&block, Some((&parent, &parent_hash)), params, consensus)
+            .map_err(|reason| SnapshotError::Block { height, reason })?;
+        ledger
+            .apply_block(&block)
+            .map_err(|error| SnapshotError::Ledger { height, error })?;
+        parent = block.header();
+    }
+    Ok((ledger, parent))
+}
+
+/// Takes a snapshot every `interval` blocks and keeps the newest `keep`.
+#[derive(Debug, Clone)]
+pub struct Snapshotter {
+    dir: PathBuf,
+    interval: u32,
+    chunk_size: usize,
+    keep: usize,
+}
+
+impl Snapshotter {
+    pub fn new(dir: impl Into<PathBuf>, interval: u32) -> Self {
+        Snapshotter {
+            dir: dir.into(),
+            interval: interval.max(1),
+            chunk_size: 1000,
+            keep: 2,
+        }
+    }
+
+    /// Accounts per chunk file.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Number of snapshots to keep; older ones are deleted.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep.max(1);
+        self
+    }
+
+    /// Call after applying `block`. Writes a snapshot if the height is a
+    /// multiple of the interval, then drops snapshots beyond `keep`.
+    pub fn maybe_snapshot(
+        &self,
+        block: &Block,
+        ledger: &Ledger,
+    ) -> Result<Option<PathBuf>, SnapshotError> {
+        if block.id == 0 || !block.id.is_multiple_of(self.interval) {
+            return Ok(None);
+        }
+        let path = write_snapshot(&self.dir, block, ledger, self.chunk_size)?;
+        let heights = self.heights()?;
+        for &height in &heights[..heights.len().saturating_sub(self.keep)] {
+            for path in [plain_path(&self.dir, height), aside_path(&self.dir, height)] {
+                if path.exists() {
+                    fs::remove_dir_all(path)?;
+                }
+            }
+        }
+        Ok(Some(path))
+    }
+
+    fn path(&self, height: u32) -> PathBuf {
+        snapshot_path(&self.dir, height)
+    }
+
+    /// Heights of the complete snapshots on disk, oldest first.
+    pub fn heights(&self) -> io::Result<Vec<u32>> {
+        let mut heights = Vec::new();
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(heights),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(height) = name
+                .to_str()
+                .and_then(|n| n.strip_prefix(PREFIX))
+                .and_then(|n| n.strip_suffix(".old").unwrap_or(n).parse().ok())
+            else {
+                continue;
+            };
+            if entry.path().join(MANIFEST).exists() {
+                heights.push(height);
+            }
+        }
+        heights.sort_unstable();
+        heights.dedup();
+        Ok(heights)
+    }
+
+    /// Newest snapshot that loads and verifies, skipping damaged ones.
+    pub fn latest(&self) -> Result<Option<Snapshot>, SnapshotError> {
+        for height in self.heights()?.into_iter().rev() {
+            if let Ok(snapshot) = load_snapshot(self.path(height)) {
+                return Ok(Some(snapshot));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MINT_SENDER, Transaction};
+
+    fn tx(id: u32, sender: &str, receiver: &str, amount: u32) -> Transaction {
+        Transaction {
+            id,
+            amount,
+            sender: sender.to_string(),
+            receiver: receiver.to_string(),
+            fee: 0,
+            nonce: 0,
+        }
+    }
+
+    /// Blocks with state roots, plus the ledger after each one.
+    fn chain(len: u32) -> (Vec<Block>, Vec<Ledger>) {
+        let mut ledger = Ledger::new();
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut states = Vec::new();
+        for id in 0..len {
+            let txs = vec![
+                tx(id * 2, MINT_SENDER, &format!("user{}", id), 50),
+                tx(id * 2 + 1, &format!("user{}", id), "alice", 10),
+            ];
+            let prev = blocks.last().map_or("0".to_string(), |b| b.hash.clone());
+            let block = Block::new(id, 1000 + id as u64, txs, prev);
+            let root = ledger.state_root_after(&block).unwrap();
+            let block = block.with_state_root(root);
+            ledger.apply_block(&block).unwrap();
+            blocks.push(block);
+            states.push(ledger.clone());
+        }
+        (blocks, states)
+    }
+
+    #[test]
+    fn test_write_and_load_chunked_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let (blocks, states) = chain(10);
+        let path = write_snapshot(dir.path(), &blocks[9], &states[9], 3).unwrap();
+
+        let snapshot = load_snapshot(&path).unwrap();
+        assert_eq!(snapshot.manifest.height, 9);
+        assert_eq!(snapshot.manifest.accounts, 11);
+        assert_eq!(snapshot.manifest.chunks.len(), 4);
+        assert_eq!(snapshot.ledger, states[9]);
+
+        // Same state, same bytes.
+        let again = write_snapshot(dir.path().join("other"), &blocks[9], &states[9], 3).unwrap();
+        assert_eq!(
+            fs::read(path.join("chunk-0001.json")).unwrap(),
+            fs::read(again.join("chunk-0001.json")).unwrap()
+        );
+
+        let chunk = path.join("chunk-0002.json");
+        let mut data = fs::read(&chunk).unwrap();
+        data[5] ^= 1;
+        fs::write(&chunk, data).unwrap();
+        assert!(matches!(
+            load_snapshot(&path),
+            Err(SnapshotError::ChunkHash { file }) if file == "chunk-0002.json"
+        ));
+    }
+
+    #[test]
+    fn test_bootstrap_matches_full_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let (blocks, states) = chain(12);
+        let path = write_snapshot(dir.path(), &blocks[5], &states[5], 4).unwrap();
+        let params = ConsensusParams::default();
+        let consensus = ConsensusConfig::default();
+
+        let snapshot = load_snapshot(&path).unwrap();
+        let (ledger, tip) = bootstrap(
+            snapshot,
+            &blocks[5].header(),
+            blocks[6..].iter().cloned(),
+            &params,
+            &consensus,
+        )
+        .unwrap();
+        assert_eq!(ledger, states[11]);
+        assert_eq!(tip, blocks[11].header());
+
+        let snapshot = load_snapshot(&path).unwrap();
+        assert!(matches!(
+            bootstrap(
+                snapshot,
+                &blocks[4].header(),
+                blocks[5..].iter().cloned(),
+                &params,
+                &consensus,
+            ),
+            Err(SnapshotError::Anchor(_))
+        ));
+
+        // Without a state root in the anchor, nothing backs the accounts.
+        let bare = Block::new(5, 1005, vec![], blocks[4].hash.clone());
+        let bare_path = write_snapshot(dir.path().join("bare"), &bare, &states[4], 4).unwrap();
+        assert!(matches!(
+            bootstrap(
+                load_snapshot(&bare_path).unwrap(),
+                &bare.header(),
+                vec![],
+                &params,
+                &consensus,
+            ),
+            Err(SnapshotError::Anchor(_))
+        ));
+
+        // A gap after the snapshot is caught by the linkage check.
+        let snapshot = load_snapshot(&path).unwrap();
+        assert!(matches!(
+            bootstrap(
+                snapshot,
+                &blocks[5].header(),
+                blocks[7..].iter().cloned(),
+                &params,
+                &consensus,
+            ),
+            Err(SnapshotError::Block { height: 7, .. })
+        ));
+
+        // The chain's own limits apply, not the defaults.
+        let tight = ConsensusParams {
+            max_block_transactions: 0,
+            ..params
+        };
+        let snapshot = load_snapshot(&path).unwrap();
+        assert!(matches!(
+            bootstrap(
+                snapshot,
+                &blocks[5].header(),
+                blocks[6..].iter().cloned(),
+                &tight,
+                &consensus,
+            ),
+            Err(SnapshotError::Block {
+                height: 6,
+                reason: BreakReason::LimitExceeded(_)
+            })
+        ));
+    }
+
+    #[test]
+    fn test_snapshotter_interval_and_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let (blocks, states) = chain(12);
+        let snapshotter = Snapshotter::new(dir.path(), 4).chunk_size(2).keep(2);
+        let mut taken = Vec::new();
+        for (block, ledger) in blocks.iter().zip(&states) {
+            if snapshotter.maybe_snapshot(block, ledger).unwrap().is_some() {
+                taken.push(block.id);
+            }
+        }
+        assert_eq!(taken, vec![4, 8]);
+
+        let (blocks, states) = chain(13);
+        snapshotter
+            .maybe_snapshot(&blocks[12], &states[12])
+            .unwrap();
+        assert_eq!(snapshotter.heights().unwrap(), vec![8, 12]);
+
+        // Leftovers of an interrupted write are ignored.
+        fs::create_dir(dir.path().join("snapshot-0000000016.tmp")).unwrap();
+        let latest = snapshotter.latest().unwrap().unwrap();
+        assert_eq!(latest.manifest.height, 12);
+
+        fs::remove_file(snapshotter.path(12).join("chunk-0000.json")).unwrap();
+        let latest = snapshotter.latest().unwrap().unwrap();
+        assert_eq!(latest.manifest.height, 8);
+    }
+
+    #[test]
+    fn test_rewrite_keeps_old_snapshot_until_swapped() {
+        let dir = tempfile::tempdir().unwrap();
+        let (blocks, states) = chain(5);
+        let snapshotter = Snapshotter::new(dir.path(), 4);
+        snapshotter.maybe_snapshot(&blocks[4], &states[4]).unwrap();
+
+        // A crash while rewriting height 4: the old copy was set aside and
+        // the new one is still in its temporary directory.
+        fs::rename(plain_path(dir.path(), 4), aside_path(dir.path(), 4)).unwrap();
+        fs::create_dir(dir.path().join("snapshot-0000000004.tmp")).unwrap();
+        assert_eq!(snapshotter.heights().unwrap(), vec![4]);
+        let latest = snapshotter.latest().unwrap().unwrap();
+        assert_eq!(latest.manifest.height, 4);
+
+        let path = write_snapshot(dir.path(), &blocks[4], &states[4], 1000).unwrap();
+        assert_eq!(path, plain_path(dir.path(), 4));
+        assert!(!aside_path(dir.path(), 4).exists());
+        assert_eq!(snapshotter.heights().unwrap(), vec![4]);
+        assert_eq!(load_snapshot(path).unwrap().ledger, states[4]);
+    }
+
+    /// The snapshot of block 2 of `chain(3)` as first written with versioned
+    /// documents. It must keep loading, and a snapshot written today must
+    /// match it file for file until the format version is bumped.
+    #[test]
+    fn test_golden_snapshot() {
+        let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("golden/snapshot_v1");
+        let (blocks, states) = chain(3);
+        let snapshot = load_snapshot(&golden).unwrap();
+        assert_eq!(snapshot.ledger, states[2]);
+        assert_eq!(snapshot.manifest.block_hash, blocks[2].hash);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_snapshot(dir.path(), &blocks[2], &states[2], 2).unwrap();
+        for file in ["manifest.json", "chunk-0000.json", "chunk-0001.json"] {
+            assert_eq!(
+                fs::read(path.join(file)).unwrap(),
+                fs::read(golden.join(file)).unwrap(),
+                "{}",
+                file
+            );
+        }
+        assert_eq!(SnapshotManifest::current_version(), 1);
+        assert_eq!(SnapshotChunk::current_version(), 1);
+    }
+}
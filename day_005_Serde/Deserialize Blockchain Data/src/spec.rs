@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::ledger::{Ledger, MINT_SENDER};
+use crate::params::ConsensusParams;
+use crate::verify::{BreakReason, GENESIS_PREV_HASH, check_difficulty};
+use crate::{Block, BlockHeader, Transaction};
+
+/// Everything a node needs to agree on before the first block: which chain
+/// this is, who starts with what, and how blocks are produced.
+///
+/// ```json
+/// {
+///   "chain_id": "devnet",
+///   "genesis": { "timestamp": 1631234566, "allocations": { "Alice": 50 } },
+///   "consensus": { "engine": "pow", "difficulty": 2, "min_difficulty": 1 },
+///   "params": { "max_block_transactions": 500 }
+/// }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ChainSpec {
+    pub chain_id: String,
+    pub genesis: GenesisConfig,
+    pub consensus: ConsensusConfig,
+    /// Size limits; defaults apply to anything left out.
+    #[serde(default)]
+    pub params: ConsensusParams,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisConfig {
+    pub timestamp: u64,
+    /// Initial balances. A `BTreeMap` so the genesis transactions come out in
+    /// the same order on every node.
+    #[serde(default)]
+    pub allocations: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "engine", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ConsensusConfig {
+    /// Proof of work: leading hex zeros required of each block hash.
+    Pow {
+        difficulty: u32,
+        #[serde(default)]
+        min_difficulty: u32,
+        /// Seconds between blocks the difficulty is tuned towards.
+        #[serde(default = "default_block_time")]
+        target_block_time: u64,
+    },
+    /// Proof of stake over a fixed initial validator set.
+    Pos {
+        validators: BTreeMap<String, u64>,
+        #[serde(default)]
+        min_stake: u64,
+        #[serde(default = "default_block_time")]
+        slot_duration: u64,
+    },
+}
+
+fn default_block_time() -> u64 {
+    10
+}
+
+/// Proof of work with no minimum difficulty, which is what blocks are held to
+/// when they are checked without a spec.
+impl Default for ConsensusConfig {
+    fn default() -> Self {
+        ConsensusConfig::Pow {
+            difficulty: 0,
+            min_difficulty: 0,
+            target_block_time: default_block_time(),
+        }
+    }
+}
+
+impl ConsensusConfig {
+    /// Difficulty the genesis block is mined at; 0 for proof of stake.
+    pub fn genesis_difficulty(&self) -> u32 {
+        match self {
+            ConsensusConfig::Pow { difficulty, .. } => *difficulty,
+            ConsensusConfig::Pos { .. } => 0,
+        }
+    }
+
+    /// Holds a block's difficulty to the engine's schedule: under proof of
+    /// work, `check_difficulty` with the spec's `min_difficulty`. Proof of
+    /// stake blocks carry no work to schedule.
+    pub fn check_difficulty(
+        &self,
+        header: &BlockHeader,
+        parent: Option<&BlockHeader>,
+    ) -> Result<(), BreakReason> {
+        match self {
+            ConsensusConfig::Pow { min_difficulty, .. } => {
+                check_difficulty(header, parent, *min_difficulty)
+            }
+            ConsensusConfig::Pos { .. } => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SpecError {
+    Io(io::Error),
+    Parse(String),
+    Invalid(String),
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpecError::Io(e) => write!(f, "I/O error: {}", e),
+            SpecError::Parse(msg) => write!(f, "cannot parse chain spec: {}", msg),
+            SpecError::Invalid(msg) => write!(f, "invalid chain spec: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SpecError {}
+
+impl From<io::Error> for SpecError {
+    fn from(e: io::Error) -> Self {
+        SpecError::Io(e)
+    }
+}
+
+impl ChainSpec {
+    pub fn from_json(json: &str) -> Result<Self, SpecError> {
+        let spec: ChainSpec =
+            serde_json::from_str(json).map_err(|e| SpecError::Parse(e.to_string()))?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, SpecError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn validate(&self) -> Result<(), SpecError> {
+        let invalid = |msg: String| Err(SpecError::Invalid(msg));
+        if self.chain_id.trim().is_empty() {
+            return invalid("chain_id is empty".to_string());
+        }
+        self.params.validate().map_err(SpecError::Invalid)?;
+        for (address, amount) in &self.genesis.allocations {
+            if address.is_empty() || address == MINT_SENDER {
+                return invalid(format!("cannot allocate to {:?}", address));
+            }
+            if *amount == 0 {
+                return invalid(format!("allocation to {} is zero", address));
+            }
+        }
+        let total: Option<u64> = self
+            .genesis
+            .allocations
+            .values()
+            .try_fold(0u64, |sum, a| sum.checked_add(*a));
+        if total.is_none() {
+            return invalid("allocations overflow the total supply".to_string());
+        }
+
+        match &self.consensus {
+            ConsensusConfig::Pow {
+                difficulty,
+                min_difficulty,
+                ..
+            } => {
+                if difficulty < min_difficulty {
+                    return invalid(format!(
+                        "difficulty {} is below min_difficulty {}",
+                        difficulty, min_difficulty
+                    ));
+                }
+                if *difficulty > 64 {
+                    return invalid(format!("difficulty {} exceeds 64 hex digits", difficulty));
+                }
+            }
+            ConsensusConfig::Pos {
+                validators,
+                min_stake,
+                ..
+            } => {
+                if validators.is_empty() {
+                    return invalid("proof of stake needs at least one validator".to_string());
+                }
+                if let Some((validator, stake)) = validators.iter().find(|(_, s)| **s < *min_stake)
+                {
+                    return invalid(format!(
+                        "validator {} stakes {}, below min_stake {}",
+                        validator, stake, min_stake
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// SHA-256 of the spec's canonical JSON. Covers fields the genesis block
+    /// does not, such as the chain id and consensus parameters, so peers can
+    /// compare it on handshake.
+    pub fn spec_hash(&self) -> String {
+        let json = serde_json::to_vec(self).expect("Serialization failed");
+        hex::encode(Sha256::digest(json))
+    }
+
+    /// One mint transaction per allocation, in address order. Amounts above
+    /// `u32::MAX` are split over several transactions.
+    fn genesis_transactions(&self) -> Vec<Transaction> {
+        let mut txs = Vec::new();
+        for (address, amount) in &self.genesis.allocations {
+            let mut left = *amount;
+            while left > 0 {
+                let part = left.min(u32::MAX as u64);
+                txs.push(Transaction {
+                    id: txs.len() as u32,
+                    amount: part as u32,
+                    sender: MINT_SENDER.to_string(),
+                    receiver: address.clone(),
+                    fee: 0,
+                    nonce: 0,
+                });
+                left -= part;
+            }
+        }
+        txs
+    }
+
+    /// Account state right after genesis.
+    pub fn genesis_ledger(&self) -> Result<Ledger, SpecError> {
+        let mut ledger = Ledger::new().with_subsidy(self.params.block_subsidy);
+        let block = Block::new(0, 0, self.genesis_transactions(), String::new());
+        ledger
+            .apply_block(&block)
+            .map_err(|e| SpecError::Invalid(e.to_string()))?;
+        Ok(ledger)
+    }
+
+    /// Builds the genesis block. Only the spec goes in, so every node that
+    /// loads the same spec gets the same block and hash: allocations become
+    /// mint transactions, the state root commits the resulting balances, and
+    /// under proof of work the nonce search always starts from zero.
+    pub fn genesis_block(&self) -> Result<Block, SpecError> {
+        self.validate()?;
+        let ledger = self.genesis_ledger()?;
+        let block = Block::new(
+            0,
+            self.genesis.timestamp,
+            self.genesis_transactions(),
+            GENESIS_PREV_HASH.to_string(),
+        )
+        .with_state_root(ledger.state_root());
+        self.params
+            .check_block(&block)
+            .map_err(|e| SpecError::Invalid(format!("genesis block: {}", e)))?;
+        block
+            .mine(self.consensus.genesis_difficulty())
+            .map_err(|e| SpecError::Invalid(format!("genesis block: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::verify_chain_report;
+
+    const SPEC: &str = r#"{
+        "chain_id": "testnet",
+        "genesis": {
+            "timestamp": 1631234566,
+            "allocations": { "Bob": 5000000000, "Alice": 50 }
+        },
+        "consensus": { "engine": "pow", "difficulty": 1, "min_difficulty": 1 }
+    }"#;
+
+    #[test]
+    fn test_genesis_is_deterministic() {
+        let spec = ChainSpec::from_json(SPEC).unwrap();
+        let genesis = spec.genesis_block().unwrap();
+        assert_eq!(
+            genesis.hash,
+            ChainSpec::from_json(SPEC)
+                .unwrap()
+                .genesis_block()
+                .unwrap()
+                .hash
+        );
+        assert!(verify_chain_report(std::slice::from_ref(&genesis)).is_ok());
+
+        // Alice first, then Bob's allocation split into two mints.
+        let receivers: Vec<&str> = genesis
+            .transactions
+            .iter()
+            .map(|t| t.receiver.as_str())
+            .collect();
+        assert_eq!(receivers, ["Alice", "Bob", "Bob"]);
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&genesis).unwrap();
+        assert_eq!(ledger.balance("Bob"), 5_000_000_000);
+        assert_eq!(ledger, spec.genesis_ledger().unwrap());
+
+        let mut other = spec.clone();
+        other.genesis.allocations.insert("Carol".to_string(), 1);
+        assert_ne!(other.genesis_block().unwrap().hash, genesis.hash);
+        let mut renamed = spec.clone();
+        renamed.chain_id = "mainnet".to_string();
+        assert_ne!(renamed.spec_hash(), spec.spec_hash());
+    }
+
+    #[test]
+    fn test_proof_of_stake_spec() {
+        let spec = ChainSpec::from_json(
+            r#"{
+                "chain_id": "stake",
+                "genesis": { "timestamp": 10 },
+                "consensus": {
+                    "engine": "pos",
+                    "validators": { "v1": 1000, "v2": 500 },
+                    "min_stake": 100
+                }
+            }"#,
+        )
+        .unwrap();
+        let genesis = spec.genesis_block().unwrap();
+        assert_eq!(genesis.difficulty, 0);
+        assert!(genesis.transactions.is_empty());
+        assert!(matches!(
+            spec.consensus,
+            ConsensusConfig::Pos {
+                slot_duration: 10,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_rejects_bad_specs() {
+        let invalid = |json: &str| matches!(ChainSpec::from_json(json), Err(SpecError::Invalid(_)));
+        assert!(invalid(&SPEC.replace("testnet", " ")));
+        assert!(invalid(&SPEC.replace("\"Alice\": 50", "\"Alice\": 0")));
+        assert!(invalid(
+            &SPEC.replace("\"difficulty\": 1", "\"difficulty\": 0")
+        ));
+        assert!(invalid(
+            r#"{"chain_id": "x", "genesis": {"timestamp": 0},
+                "consensus": {"engine": "pos", "validators": {"v": 5}, "min_stake": 10}}"#
+        ));
+
+        // Bob's allocation takes two mint transactions, so genesis needs three.
+        let limited = SPEC.replace(
+            r#""min_difficulty": 1 }"#,
+            r#""min_difficulty": 1 }, "params": { "max_block_transactions": 2 }"#,
+        );
+        let spec = ChainSpec::from_json(&limited).unwrap();
+        assert_eq!(spec.params.max_block_transactions, 2);
+        assert!(matches!(spec.genesis_block(), Err(SpecError::Invalid(_))));
+
+        assert!(matches!(
+            ChainSpec::from_json(&SPEC.replace("pow", "pbft")),
+            Err(SpecError::Parse(_))
+        ));
+        assert!(matches!(
+            ChainSpec::from_json(&SPEC.replace("chain_id", "chainid")),
+            Err(SpecError::Parse(_))
+        ));
+    }
+}
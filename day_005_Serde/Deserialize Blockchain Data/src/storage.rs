@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::wal::Wal;
+
+/// Column family names shared by everything that persists through a `Storage`.
+pub mod cf {
+    pub const BLOCKS: &str = "blocks";
+    pub const HEIGHTS: &str = "heights";
+    pub const STATE: &str = "state";
+    pub const MEMPOOL: &str = "mempool";
+    pub const KEYS: &str = "keys";
+    pub const META: &str = "meta";
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    Put {
+        cf: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        cf: String,
+        key: Vec<u8>,
+    },
+}
+
+/// A group of writes that a backend applies all together or not at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<WriteOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, cf: &str, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> &mut Self {
+        self.ops.push(WriteOp::Put {
+            cf: cf.to_string(),
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+        });
+        self
+    }
+
+    /// Serializes `value` as JSON and queues it like `put`.
+    pub fn put_json<T: Serialize>(
+        &mut self,
+        cf: &str,
+        key: impl AsRef<[u8]>,
+        value: &T,
+    ) -> io::Result<&mut Self> {
+        let value = serde_json::to_vec(value).map_err(invalid)?;
+        Ok(self.put(cf, key, value))
+    }
+
+    pub fn delete(&mut self, cf: &str, key: impl AsRef<[u8]>) -> &mut Self {
+        self.ops.push(WriteOp::Delete {
+            cf: cf.to_string(),
+            key: key.as_ref().to_vec(),
+        });
+        self
+    }
+
+    pub fn ops(&self) -> &[WriteOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// Key-value persistence split into column families, with atomic batches.
+pub trait Storage {
+    fn get(&self, cf: &str, key: &[u8]) -> io::Result<Option<Vec<u8>>>;
+
+    /// All entries of `cf` whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, cf: &str, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Applies every operation in `batch`, or none of them if it fails.
+    fn write(&mut self, batch: WriteBatch) -> io::Result<()>;
+
+    fn put(&mut self, cf: &str, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(cf, key, value);
+        self.write(batch)
+    }
+
+    fn delete(&mut self, cf: &str, key: &[u8]) -> io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(cf, key);
+        self.write(batch)
+    }
+
+    fn contains(&self, cf: &str, key: &[u8]) -> io::Result<bool> {
+        Ok(self.get(cf, key)?.is_some())
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, cf: &str, key: &[u8]) -> io::Result<Option<T>>
+    where
+        Self: Sized,
+    {
+        match self.get(cf, key)? {
+            Some(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(invalid),
+            None => Ok(None),
+        }
+    }
+}
+
+fn invalid(err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+type Columns = BTreeMap<String, BTreeMap<Vec<u8>, Vec<u8>>>;
+
+fn apply(columns: &mut Columns, batch: WriteBatch) {
+    for op in batch.ops {
+        match op {
+            WriteOp::Put { cf, key, value } => {
+                columns.entry(cf).or_default().insert(key, value);
+            }
+            WriteOp::Delete { cf, key } => {
+                if let Some(column) = columns.get_mut(&cf) {
+                    column.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+fn scan(columns: &Columns, cf: &str, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let Some(column) = columns.get(cf) else {
+        return Vec::new();
+    };
+    column
+        .range(prefix.to_vec()..)
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+/// Volatile backend for tests and throwaway nodes.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    columns: Columns,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, cf: &str, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.columns.get(cf).and_then(|c| c.get(key)).cloned())
+    }
+
+    fn scan_prefix(&self, cf: &str, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(scan(&self.columns, cf, prefix))
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> io::Result<()> {
+        apply(&mut self.columns, batch);
+        Ok(())
+    }
+}
+
+const LOG_FILE: &str = "storage.log";
+
+/// Embedded on-disk backend: a write-ahead log of batches in a directory,
+/// replayed into memory on open. Needs nothing but the filesystem.
+///
+/// Each batch is one checksummed `Wal` record, fsynced before `write`
+/// returns, so a batch cut short by a crash is dropped as a whole on the next
+/// open.
+pub struct FileStorage {
+    log: Wal,
+    columns: Columns,
+}
+
+impl FileStorage {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let (log, recovered) = Wal::open(dir.join(LOG_FILE))?;
+        let mut columns = Columns::new();
+        for body in recovered.records {
+            let batch = decode_body(&body).ok_or_else(|| invalid("corrupt storage record"))?;
+            apply(&mut columns, batch);
+        }
+        Ok(FileStorage { log, columns })
+    }
+
+    /// Rewrites the log so it holds only the live entries.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let body = encode_body(&snapshot(&self.columns));
+        self.log.rewrite([body.as_slice()])
+    }
+}
+
+impl Storage for FileStorage {
+    fn get(&self, cf: &str, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.columns.get(cf).and_then(|c| c.get(key)).cloned())
+    }
+
+    fn scan_prefix(&self, cf: &str, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(scan(&self.columns, cf, prefix))
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> io::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.log.append(&encode_body(&batch))?;
+        apply(&mut self.columns, batch);
+        Ok(())
+    }
+}
+
+/// One batch that recreates every live entry.
+fn snapshot(columns: &Columns) -> WriteBatch {
+    let mut batch = WriteBatch::new();
+    for (cf, column) in columns {
+        for (key, value) in column {
+            batch.put(cf, key, value);
+        }
+    }
+    batch
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// Batch layout: a sequence of `[u8 op][cf][key]([value])`, each field a
+/// `u32`-length-prefixed byte string.
+fn encode_body(batch: &WriteBatch) -> Vec<u8> {
+    let mut body = Vec::new();
+    for op in &batch.ops {
+        match op {
+            WriteOp::Put { cf, key, value } => {
+                body.push(0);
+                put_bytes(&mut body, cf.as_bytes());
+                put_bytes(&mut body, key);
+                put_bytes(&mut body, value);
+            }
+            WriteOp::Delete { cf, key } => {
+                body.push(1);
+                put_bytes(&mut body, cf.as_bytes());
+                put_bytes(&mut body, key);
+            }
+        }
+    }
+    body
+}
+
+fn decode_body(mut body: &[u8]) -> Option<WriteBatch> {
+    fn take<'a>(body: &mut &'a [u8]) -> Option<&'a [u8]> {
+        let len = u32::from_le_bytes(body.get(..4)?.try_into().ok()?) as usize;
+        let bytes = body.get(4..4 + len)?;
+        *body = &body[4 + len..];
+        Some(bytes)
+    }
+
+    let mut batch = WriteBatch::new();
+    while let Some((&op, rest)) = body.split_first() {
+        body = rest;
+        let cf = String::from_utf8(take(&mut body)?.to_vec()).ok()?;
+        let key = take(&mut body)?;
+        match op {
+            0 => batch.put(&cf, key, take(&mut body)?),
+            1 => batch.delete(&cf, key),
+            _ => return None,
+        };
+    }
+    Some(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(storage: &mut impl Storage) {
+        let mut batch = WriteBatch::new();
+        batch
+            .put(cf::BLOCKS, b"h1", b"block one")
+            .put(cf::BLOCKS, b"h2", b"block two")
+            .put(cf::HEIGHTS, 1u32.to_be_bytes(), b"h1")
+            .put_json(cf::STATE, b"acct:alice", &100u64)
+            .unwrap();
+        storage.write(batch).unwrap();
+
+        assert_eq!(
+            storage.get(cf::BLOCKS, b"h1").unwrap(),
+            Some(b"block one".to_vec())
+        );
+        assert_eq!(storage.get(cf::HEIGHTS, b"h1").unwrap(), None);
+        assert_eq!(
+            storage.get_json::<u64>(cf::STATE, b"acct:alice").unwrap(),
+            Some(100)
+        );
+
+        storage.put(cf::STATE, b"acct:bob", b"7").unwrap();
+        storage.put(cf::STATE, b"other", b"x").unwrap();
+        let accounts = storage.scan_prefix(cf::STATE, b"acct:").unwrap();
+        let keys: Vec<&[u8]> = accounts.iter().map(|(k, _)| k.as_slice()).collect();
+        assert_eq!(keys, vec![b"acct:alice".as_slice(), b"acct:bob".as_slice()]);
+
+        storage.delete(cf::BLOCKS, b"h2").unwrap();
+        assert!(!storage.contains(cf::BLOCKS, b"h2").unwrap());
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        exercise(&mut MemoryStorage::new());
+    }
+
+    #[test]
+    fn test_file_storage_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut storage = FileStorage::open(dir.path()).unwrap();
+            exercise(&mut storage);
+        }
+
+        let mut storage = FileStorage::open(dir.path()).unwrap();
+        assert_eq!(
+            storage.get(cf::BLOCKS, b"h1").unwrap(),
+            Some(b"block one".to_vec())
+        );
+        assert!(!storage.contains(cf::BLOCKS, b"h2").unwrap());
+
+        storage.compact().unwrap();
+        storage.put(cf::KEYS, b"wallet", b"secret").unwrap();
+        let storage = FileStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.scan_prefix(cf::STATE, b"").unwrap().len(), 3);
+        assert_eq!(
+            storage.get(cf::KEYS, b"wallet").unwrap(),
+            Some(b"secret".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_torn_batch_is_dropped_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut storage = FileStorage::open(dir.path()).unwrap();
+            storage.put(cf::META, b"tip", b"h1").unwrap();
+        }
+        let path = dir.path().join(LOG_FILE);
+        let committed = fs::read(&path).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(cf::META, b"tip", b"h2")
+            .put(cf::BLOCKS, b"h2", b"block two");
+        let record = crate::wal::encode_record(&encode_body(&batch));
+
+        // Crash after every byte of the second batch.
+        for cut in 0..record.len() {
+            let mut torn = committed.clone();
+            torn.extend_from_slice(&record[..cut]);
+            fs::write(&path, &torn).unwrap();
+
+            let mut storage = FileStorage::open(dir.path()).unwrap();
+            assert_eq!(storage.get(cf::META, b"tip").unwrap(), Some(b"h1".to_vec()));
+            assert!(!storage.contains(cf::BLOCKS, b"h2").unwrap());
+
+            storage.put(cf::META, b"tip", b"h3").unwrap();
+            let storage = FileStorage::open(dir.path()).unwrap();
+            assert_eq!(storage.get(cf::META, b"tip").unwrap(), Some(b"h3".to_vec()));
+        }
+    }
+}
@@ -0,0 +1,563 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::versioned;
+use crate::wal::{self, HEADER_LEN, MAGIC, Record, Wal, sync_dir};
+use crate::{Block, BlockHeader};
+
+/// Default size at which the store starts a new segment file.
+pub const DEFAULT_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+const INDEX_FILE: &str = "index.log";
+
+/// Where a block lives on disk. One JSON record per entry in `index.log`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub height: u32,
+    pub hash: String,
+    pub segment: u32,
+    /// Start of the block's `Wal` record in its segment.
+    pub offset: u64,
+    pub len: u32,
+    /// Kept in the index so headers can still be served once the body has
+    /// been pruned.
+    pub header: BlockHeader,
+}
+
+/// Append-only block store.
+///
+/// Blocks are written as versioned JSON records (see `versioned`) to
+/// `seg-NNNNNN.dat` files that rotate once they reach the configured size.
+/// Every append also writes an entry to `index.log`, so reopening only
+/// replays the index instead of reading every block; only records written
+/// after the last index entry are rescanned.
+///
+/// Segments and the index are both `Wal`s. Each append fsyncs the segment
+/// record before the index entry, so a crash can only lose the index entry
+/// (rebuilt on open from the segment) or leave a torn record at the end of
+/// either log (cut off on open).
+///
+/// Old bodies can be dropped with `prune`, a whole segment at a time. The
+/// index, and with it every header, is kept.
+pub struct BlockStore {
+    dir: PathBuf,
+    segment_bytes: u64,
+    entries: Vec<IndexEntry>,
+    /// Heights below this have had their bodies pruned.
+    pruned_below: u32,
+    by_hash: HashMap<String, u32>,
+    index: Wal,
+    segment: u32,
+    /// The segment being appended to.
+    log: Wal,
+}
+
+fn invalid(err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn segment_path(dir: &Path, segment: u32) -> PathBuf {
+    dir.join(format!("seg-{:06}.dat", segment))
+}
+
+impl BlockStore {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with_segment_size(dir, DEFAULT_SEGMENT_BYTES)
+    }
+
+    pub fn open_with_segment_size(dir: impl AsRef<Path>, segment_bytes: u64) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let (index, recovered) = Wal::open(dir.join(INDEX_FILE))?;
+        let entries = read_index(recovered.records)?;
+        let segment = entries.last().map_or(0, |e| e.segment);
+        let (log, recovered) = Wal::open(segment_path(&dir, segment))?;
+
+        let mut store = BlockStore {
+            by_hash: HashMap::new(),
+            dir,
+            segment_bytes,
+            entries: Vec::new(),
+            pruned_below: 0,
+            index,
+            segment,
+            log,
+        };
+        for entry in entries {
+            store.by_hash.insert(entry.hash.clone(), entry.height);
+            store.entries.push(entry);
+        }
+        store.pruned_below = store
+            .entries
+            .iter()
+            .position(|e| store.segment_path(e.segment).exists())
+            .unwrap_or(store.entries.len()) as u32;
+        store.recover_tail(recovered.records)?;
+        Ok(store)
+    }
+
+    /// Indexes records that reached a segment but not `index.log`, starting
+    /// with `records`, those of the current segment.
+    fn recover_tail(&mut self, mut records: Vec<Vec<u8>>) -> io::Result<()> {
+        let mut indexed = self
+            .entries
+            .iter()
+            .rev()
+            .take_while(|e| e.segment == self.segment)
+            .count();
+        if indexed > records.len() {
+            return Err(invalid(format!(
+                "index points past the end of segment {}",
+                self.segment
+            )));
+        }
+
+        loop {
+            let mut offset = MAGIC.len() as u64;
+            for body in records {
+                if indexed > 0 {
+                    indexed -= 1;
+                } else {
+                    let block = decode_block(&body)?;
+                    self.push_entry(&block, self.segment, offset, body.len() as u32)?;
+                }
+                offset += HEADER_LEN + body.len() as u64;
+            }
+
+            let next = self.segment_path(self.segment + 1);
+            if !next.exists() {
+                return Ok(());
+            }
+            let (log, recovered) = Wal::open(next)?;
+            self.segment += 1;
+            self.log = log;
+            records = recovered.records;
+        }
+    }
+
+    fn segment_path(&self, segment: u32) -> PathBuf {
+        segment_path(&self.dir, segment)
+    }
+
+    fn push_entry(&mut self, block: &Block, segment: u32, offset: u64, len: u32) -> io::Result<()> {
+        let entry = IndexEntry {
+            height: block.id,
+            hash: block.compute_hash(),
+            segment,
+            offset,
+            len,
+            header: block.header(),
+        };
+        self.index
+            .append(&serde_json::to_vec(&entry).map_err(invalid)?)?;
+
+        self.by_hash.insert(entry.hash.clone(), entry.height);
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Appends the next block. Its id must be the current tip height + 1
+    /// (or 0 for an empty store).
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        let expected = self.entries.len() as u32;
+        if block.id != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("expected block {}, got {}", expected, block.id),
+            ));
+        }
+
+        let payload = versioned::encode(block).map_err(invalid)?;
+        let record_len = HEADER_LEN + payload.len() as u64;
+        if !self.log.is_empty() && self.log.len() + record_len > self.segment_bytes {
+            let (log, _) = Wal::open(self.segment_path(self.segment + 1))?;
+            self.segment += 1;
+            self.log = log;
+        }
+
+        let offset = self.log.len();
+        self.log.append(&payload)?;
+        self.push_entry(block, self.segment, offset, payload.len() as u32)
+    }
+
+    fn read_entry(&self, entry: &IndexEntry) -> io::Result<Block> {
+        let mut file = File::open(self.segment_path(entry.segment))?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let Record::Intact(body) = wal::read_record(&mut file)? else {
+            return Err(invalid(format!(
+                "block {} is corrupt on disk",
+                entry.height
+            )));
+        };
+        let block = decode_block(&body)?;
+        if block.hash != entry.hash {
+            return Err(invalid(format!(
+                "block {} does not match its index entry",
+                entry.height
+            )));
+        }
+        Ok(block)
+    }
+
+    /// Fails with `io::ErrorKind::NotFound` if the body has been pruned.
+    pub fn get_by_height(&self, height: u32) -> io::Result<Option<Block>> {
+        if height < self.pruned_below {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("block {} has been pruned", height),
+            ));
+        }
+        match self.entries.get(height as usize) {
+            Some(entry) => self.read_entry(entry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_by_hash(&self, hash: &str) -> io::Result<Option<Block>> {
+        match self.by_hash.get(hash) {
+            Some(&height) => self.get_by_height(height),
+            None => Ok(None),
+        }
+    }
+
+    /// Header at `height`, available even after the body is pruned.
+    pub fn header_at(&self, height: u32) -> Option<BlockHeader> {
+        self.entries.get(height as usize).map(|e| e.header.clone())
+    }
+
+    /// Lowest height whose body is still stored.
+    pub fn pruned_below(&self) -> u32 {
+        self.pruned_below
+    }
+
+    /// Deletes the bodies of blocks below `keep_from`. Only whole segments are
+    /// removed and the segment being written to is always kept, so some older
+    /// bodies may survive. Returns the new `pruned_below`.
+    pub fn prune(&mut self, keep_from: u32) -> io::Result<u32> {
+        let keep_from = keep_from.min(self.entries.len() as u32) as usize;
+        // First segment that holds a block we must keep.
+        let keep_segment = self
+            .entries
+            .get(keep_from)
+            .map_or(self.segment, |e| e.segment)
+            .min(self.segment);
+
+        let mut height = self.pruned_below as usize;
+        while height < self.entries.len() && self.entries[height].segment < keep_segment {
+            let segment = self.entries[height].segment;
+            match fs::remove_file(self.segment_path(segment)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            while height < self.entries.len() && self.entries[height].segment == segment {
+                height += 1;
+            }
+        }
+        sync_dir(&self.dir)?;
+        self.pruned_below = height as u32;
+        Ok(self.pruned_below)
+    }
+
+    /// Keeps the bodies of the last `window` blocks, pruning older ones.
+    pub fn prune_to_window(&mut self, window: u32) -> io::Result<u32> {
+        let keep_from = (self.entries.len() as u32).saturating_sub(window);
+        self.prune(keep_from)
+    }
+
+    /// Height and hash of the last stored block.
+    pub fn tip(&self) -> Option<(u32, &str)> {
+        self.entries.last().map(|e| (e.height, e.hash.as_str()))
+    }
+
+    /// Hash of the block at `height`, from the index alone.
+    pub fn hash_at(&self, height: u32) -> Option<&str> {
+        self.entries.get(height as usize).map(|e| e.hash.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterates blocks with heights in `range`, in order. A pruned or
+    /// missing body yields a `NotFound` error in its place.
+    pub fn range(&self, range: Range<u32>) -> impl Iterator<Item = io::Result<Block>> + '_ {
+        let end = range.end.min(self.entries.len() as u32);
+        (range.start..end).map(move |height| {
+            self.get_by_height(height)?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("block {} is missing", height),
+                )
+            })
+        })
+    }
+}
+
+/// Decodes a segment record and fills in the block's hash.
+fn decode_block(body: &[u8]) -> io::Result<Block> {
+    let mut block: Block = versioned::decode_enveloped(body).map_err(invalid)?;
+    block.hash = block.compute_hash();
+    Ok(block)
+}
+
+/// Decodes the index records, which run from height 0 up.
+fn read_index(records: Vec<Vec<u8>>) -> io::Result<Vec<IndexEntry>> {
+    let mut entries = Vec::with_capacity(records.len());
+    for record in records {
+        let entry: IndexEntry = serde_json::from_slice(&record).map_err(invalid)?;
+        if entry.height as usize != entries.len() {
+            return Err(invalid(format!(
+                "index out of order at height {}",
+                entry.height
+            )));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transaction;
+
+    fn chain(len: u32) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for id in 0..len {
+            let prev_hash = blocks.last().map_or("0".to_string(), |b| b.hash.clone());
+            let txs = vec![Transaction {
+                id,
+                amount: id * 10,
+                sender: format!("user{}", id),
+                receiver: format!("user{}", id + 1),
+                fee: 0,
+                nonce: 0,
+            }];
+            blocks.push(Block::new(id, 1631234566 + id as u64, txs, prev_hash));
+        }
+        blocks
+    }
+
+    fn store_segments(dir: &Path) -> u32 {
+        (0..).take_while(|&n| segment_path(dir, n).exists()).count() as u32
+    }
+
+    #[test]
+    fn test_append_and_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        let blocks = chain(5);
+        for block in &blocks {
+            store.append(block).unwrap();
+        }
+
+        assert_eq!(store.tip(), Some((4, blocks[4].hash.as_str())));
+        assert_eq!(
+            store.get_by_height(2).unwrap().unwrap().hash,
+            blocks[2].hash
+        );
+        assert_eq!(store.get_by_hash(&blocks[3].hash).unwrap().unwrap().id, 3);
+        assert!(store.get_by_height(5).unwrap().is_none());
+        assert!(store.get_by_hash("missing").unwrap().is_none());
+
+        let ids: Vec<u32> = store.range(1..4).map(|b| b.unwrap().id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        let err = store.append(&blocks[1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_segments_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = BlockStore::open_with_segment_size(dir.path(), 300).unwrap();
+        for block in &chain(10) {
+            store.append(block).unwrap();
+        }
+        assert!(dir.path().join("seg-000003.dat").exists());
+        assert_eq!(store.range(0..100).count(), 10);
+    }
+
+    #[test]
+    fn test_reopen_uses_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = chain(6);
+        {
+            let mut store = BlockStore::open_with_segment_size(dir.path(), 300).unwrap();
+            for block in &blocks[..4] {
+                store.append(block).unwrap();
+            }
+        }
+
+        let mut store = BlockStore::open_with_segment_size(dir.path(), 300).unwrap();
+        assert_eq!(store.tip(), Some((3, blocks[3].hash.as_str())));
+        for block in &blocks[4..] {
+            store.append(block).unwrap();
+        }
+        assert_eq!(store.get_by_hash(&blocks[5].hash).unwrap().unwrap().id, 5);
+    }
+
+    #[test]
+    fn test_recovers_unindexed_and_torn_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = chain(4);
+        let last_entry = {
+            let mut store = BlockStore::open(dir.path()).unwrap();
+            for block in &blocks {
+                store.append(block).unwrap();
+            }
+            wal::encode_record(&serde_json::to_vec(&store.entries[3]).unwrap())
+        };
+
+        // Lose most of the last index entry and leave half a record in the
+        // segment.
+        let index_path = dir.path().join(INDEX_FILE);
+        let index = fs::read(&index_path).unwrap();
+        fs::write(&index_path, &index[..index.len() - last_entry.len() + 5]).unwrap();
+        let seg_path = dir.path().join("seg-000000.dat");
+        let mut seg = fs::read(&seg_path).unwrap();
+        seg.extend_from_slice(&wal::encode_record(b"{\"kind\":\"block\"}")[..12]);
+        fs::write(&seg_path, &seg).unwrap();
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.tip(), Some((3, blocks[3].hash.as_str())));
+        let next = Block::new(4, 1631234570, vec![], blocks[3].hash.clone());
+        store.append(&next).unwrap();
+        assert_eq!(store.get_by_height(4).unwrap().unwrap().hash, next.hash);
+    }
+
+    #[test]
+    fn test_rejects_corrupt_block_record() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut store = BlockStore::open_with_segment_size(dir.path(), 300).unwrap();
+            for block in &chain(6) {
+                store.append(block).unwrap();
+            }
+        }
+
+        // A flipped byte in the middle of a segment is not a torn append.
+        let flip = |name: &str| {
+            let path = dir.path().join(name);
+            let mut seg = fs::read(&path).unwrap();
+            seg[MAGIC.len() + HEADER_LEN as usize + 3] ^= 0x20;
+            fs::write(&path, &seg).unwrap();
+        };
+        flip("seg-000000.dat");
+        let store = BlockStore::open_with_segment_size(dir.path(), 300).unwrap();
+        let err = store.get_by_height(0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(store.get_by_height(5).unwrap().unwrap().id, 5);
+        drop(store);
+
+        // The segment being appended to is checked on open.
+        let last = format!("seg-{:06}.dat", store_segments(dir.path()) - 1);
+        flip(&last);
+        let err = BlockStore::open_with_segment_size(dir.path(), 300)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_crash_at_every_write_point() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = chain(4);
+        {
+            let mut store = BlockStore::open(dir.path()).unwrap();
+            for block in &blocks[..3] {
+                store.append(block).unwrap();
+            }
+        }
+        let seg_path = dir.path().join("seg-000000.dat");
+        let index_path = dir.path().join(INDEX_FILE);
+        let (seg_before, index_before) =
+            (fs::read(&seg_path).unwrap(), fs::read(&index_path).unwrap());
+        {
+            let mut store = BlockStore::open(dir.path()).unwrap();
+            store.append(&blocks[3]).unwrap();
+        }
+        let (seg_after, index_after) =
+            (fs::read(&seg_path).unwrap(), fs::read(&index_path).unwrap());
+
+        // The segment record is written first, then the index line. Stop at
+        // every byte of either.
+        let states = (seg_before.len()..=seg_after.len())
+            .map(|cut| (cut, index_before.len()))
+            .chain((index_before.len()..=index_after.len()).map(|cut| (seg_after.len(), cut)));
+        for (seg_cut, index_cut) in states {
+            fs::write(&seg_path, &seg_after[..seg_cut]).unwrap();
+            fs::write(&index_path, &index_after[..index_cut]).unwrap();
+
+            let mut store = BlockStore::open(dir.path()).unwrap();
+            let expected = if seg_cut == seg_after.len() { 4 } else { 3 };
+            assert_eq!(
+                store.len(),
+                expected,
+                "segment {} index {}",
+                seg_cut,
+                index_cut
+            );
+            for block in &blocks[..expected] {
+                assert_eq!(
+                    store.get_by_height(block.id).unwrap().unwrap().hash,
+                    block.hash
+                );
+            }
+
+            if expected == 3 {
+                store.append(&blocks[3]).unwrap();
+            }
+            drop(store);
+            let store = BlockStore::open(dir.path()).unwrap();
+            assert_eq!(store.tip(), Some((3, blocks[3].hash.as_str())));
+        }
+    }
+
+    #[test]
+    fn test_prune_keeps_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = chain(12);
+        let mut store = BlockStore::open_with_segment_size(dir.path(), 300).unwrap();
+        for block in &blocks {
+            store.append(block).unwrap();
+        }
+
+        let kept = store.prune_to_window(4).unwrap();
+        assert!(kept > 0 && kept <= 8);
+        assert!(!dir.path().join("seg-000000.dat").exists());
+        let err = store.get_by_height(0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(store.header_at(0).unwrap(), blocks[0].header());
+        let bodies: Vec<io::Result<Block>> = store.range(kept - 1..kept + 1).collect();
+        assert_eq!(
+            bodies[0].as_ref().unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(bodies[1].as_ref().unwrap().id, kept);
+        for block in &blocks[8..] {
+            assert_eq!(
+                store.get_by_height(block.id).unwrap().unwrap().hash,
+                block.hash
+            );
+        }
+
+        drop(store);
+        let mut store = BlockStore::open_with_segment_size(dir.path(), 300).unwrap();
+        assert_eq!(store.pruned_below(), kept);
+        assert_eq!(store.header_at(1).unwrap(), blocks[1].header());
+        let next = Block::new(12, 1631234600, vec![], blocks[11].hash.clone());
+        store.append(&next).unwrap();
+        assert_eq!(store.prune(100).unwrap(), store.len() as u32 - 1);
+    }
+}
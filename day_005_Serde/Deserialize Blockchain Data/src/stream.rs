@@ -0,0 +1,465 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::Block;
+use crate::params::ConsensusParams;
+use crate::spec::ConsensusConfig;
+use crate::timestamp::{Clock, SystemClock, TimestampRules};
+use crate::transfer::{Counting, Format, read_block, read_header};
+use crate::verify::{BreakReason, ChainReport, verify_block_within};
+
+/// What went wrong while streaming a chain.
+#[derive(Debug)]
+pub enum StreamErrorKind {
+    Io(io::Error),
+    /// The bytes are not a well-formed block (or JSON array of blocks).
+    Decode(String),
+    /// The block decoded but does not link to its parent.
+    Break(BreakReason),
+}
+
+/// A streaming failure with its position in the input.
+#[derive(Debug)]
+pub struct StreamError {
+    /// Position of the block in the stream, starting at 0.
+    pub index: u64,
+    /// Byte offset into the input. For verification failures this is where the
+    /// block starts; otherwise it is where reading stopped.
+    pub offset: u64,
+    pub kind: StreamErrorKind,
+}
+
+impl fmt::Display for StreamErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamErrorKind::Io(e) => write!(f, "I/O error: {}", e),
+            StreamErrorKind::Decode(msg) => write!(f, "cannot decode: {}", msg),
+            StreamErrorKind::Break(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "block {} at byte {}: {}",
+            self.index, self.offset, self.kind
+        )
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<io::Error> for StreamErrorKind {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+                StreamErrorKind::Decode(e.to_string())
+            }
+            _ => StreamErrorKind::Io(e),
+        }
+    }
+}
+
+enum Layout {
+    /// A single JSON array, as written by `serde_json::to_string(&chain)`.
+    JsonArray(ArrayState),
+    /// One of the block-by-block export formats, with the export version
+    /// once its header has been read.
+    Records {
+        format: Format,
+        version: Option<u32>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ArrayState {
+    Start,
+    First,
+    Rest,
+}
+
+/// Reads a chain one block at a time, verifying each block against the one
+/// before it as it goes.
+///
+/// Only the previous block and the timestamps the median rule needs are kept,
+/// so memory use does not grow with the length of the chain. Yields `Err` at
+/// most once, after which it is exhausted.
+pub struct ChainReader<R> {
+    input: Counting<BufReader<R>>,
+    layout: Layout,
+    index: u64,
+    /// Offset of the block currently being read.
+    block_start: u64,
+    parent: Option<Block>,
+    params: ConsensusParams,
+    consensus: ConsensusConfig,
+    rules: TimestampRules,
+    clock: Box<dyn Clock>,
+    /// The last `rules.median_window` timestamps, oldest first.
+    timestamps: VecDeque<u64>,
+    done: bool,
+}
+
+impl<R: Read> ChainReader<R> {
+    /// Reads blocks written by `export_chain` in `format`.
+    pub fn new(reader: R, format: Format) -> Self {
+        let version = None;
+        Self::with_layout(reader, Layout::Records { format, version })
+    }
+
+    /// Reads a JSON array of blocks without loading the whole array.
+    pub fn json_array(reader: R) -> Self {
+        Self::with_layout(reader, Layout::JsonArray(ArrayState::Start))
+    }
+
+    fn with_layout(reader: R, layout: Layout) -> Self {
+        ChainReader {
+            input: Counting {
+                inner: BufReader::new(reader),
+                count: 0,
+            },
+            layout,
+            index: 0,
+            block_start: 0,
+            parent: None,
+            params: ConsensusParams::default(),
+            consensus: ConsensusConfig::default(),
+            rules: TimestampRules::default(),
+            clock: Box::new(SystemClock),
+            timestamps: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Checks blocks against `params` instead of the default limits.
+    pub fn with_params(mut self, params: ConsensusParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Checks difficulty against the schedule of `consensus` instead of
+    /// proof of work with no minimum.
+    pub fn with_consensus(mut self, consensus: ConsensusConfig) -> Self {
+        self.consensus = consensus;
+        self
+    }
+
+    /// Checks timestamps against `rules` and `clock` instead of the default
+    /// rules and the system clock.
+    pub fn with_timestamp_rules(
+        mut self,
+        rules: TimestampRules,
+        clock: impl Clock + 'static,
+    ) -> Self {
+        self.rules = rules;
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Bytes consumed so far.
+    pub fn offset(&self) -> u64 {
+        self.input.count
+    }
+
+    /// Number of blocks yielded so far.
+    pub fn blocks_read(&self) -> u64 {
+        self.index
+    }
+
+    /// The last block yielded.
+    pub fn tip(&self) -> Option<&Block> {
+        self.parent.as_ref()
+    }
+
+    /// Drains the reader and summarises the chain, stopping at the first error.
+    pub fn verify(mut self) -> Result<ChainReport, StreamError> {
+        for block in &mut self {
+            block?;
+        }
+        Ok(ChainReport {
+            blocks_verified: self.index as usize,
+            tip_hash: self.parent.map(|b| b.hash),
+        })
+    }
+
+    fn read_next(&mut self) -> Result<Option<Block>, StreamErrorKind> {
+        self.block_start = self.input.count;
+        let state = match self.layout {
+            Layout::Records { format, version } => {
+                let version = match version {
+                    Some(version) => version,
+                    None => {
+                        let version = read_header(&mut self.input, format)?;
+                        self.layout = Layout::Records {
+                            format,
+                            version: Some(version),
+                        };
+                        self.block_start = self.input.count;
+                        version
+                    }
+                };
+                return Ok(read_block(&mut self.input, format, version)?);
+            }
+            Layout::JsonArray(state) => state,
+        };
+
+        let next = self.skip_whitespace()?;
+        match (state, next) {
+            (ArrayState::Start, Some(b'[')) => {
+                self.input.consume(1);
+                self.layout = Layout::JsonArray(ArrayState::First);
+                return self.read_next();
+            }
+            (ArrayState::Start, _) => return Err(decode("expected `[` at start of chain")),
+            (_, Some(b']')) => {
+                self.input.consume(1);
+                if self.skip_whitespace()?.is_some() {
+                    return Err(decode("trailing data after chain"));
+                }
+                return Ok(None);
+            }
+            (ArrayState::Rest, Some(b',')) => self.input.consume(1),
+            (ArrayState::Rest, Some(_)) => return Err(decode("expected `,` or `]`")),
+            (_, None) => return Err(decode("unexpected end of input")),
+            (ArrayState::First, Some(_)) => {}
+        }
+        self.skip_whitespace()?;
+        self.block_start = self.input.count;
+
+        let mut de = serde_json::Deserializer::from_reader(&mut self.input);
+        let mut block = Block::deserialize(&mut de).map_err(|e| {
+            if e.is_io() {
+                StreamErrorKind::Io(e.into())
+            } else {
+                decode(e)
+            }
+        })?;
+        block.hash = block.compute_hash();
+        self.layout = Layout::JsonArray(ArrayState::Rest);
+        Ok(Some(block))
+    }
+
+    /// Consumes whitespace and peeks at the next byte.
+    fn skip_whitespace(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            let buf = self.input.fill_buf()?;
+            let Some(&byte) = buf.first() else {
+                return Ok(None);
+            };
+            if !byte.is_ascii_whitespace() {
+                return Ok(Some(byte));
+            }
+            self.input.consume(1);
+        }
+    }
+
+    fn fail(&mut self, offset: u64, kind: StreamErrorKind) -> Option<Result<Block, StreamError>> {
+        self.done = true;
+        Some(Err(StreamError {
+            index: self.index,
+            offset,
+            kind,
+        }))
+    }
+}
+
+fn decode(msg: impl fmt::Display) -> StreamErrorKind {
+    StreamErrorKind::Decode(msg.to_string())
+}
+
+impl<R: Read> Iterator for ChainReader<R> {
+    type Item = Result<Block, StreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let block = match self.read_next() {
+            Ok(Some(block)) => block,
+            Ok(None) => {
+                self.done = true;
+                return None;
+            }
+            Err(kind) => return self.fail(self.input.count, kind),
+        };
+
+        let parent = self.parent.as_ref().map(|p| (p.header(), p.hash.as_str()));
+        let link = parent.as_ref().map(|(h, hash)| (h, *hash));
+        let checked =
+            verify_block_within(&block, link, &self.params, &self.consensus).and_then(|_| {
+                let recent = self.timestamps.make_contiguous();
+                self.rules
+                    .check(block.timestamp, recent, self.clock.as_ref())
+            });
+        if let Err(reason) = checked {
+            return self.fail(self.block_start, StreamErrorKind::Break(reason));
+        }
+
+        self.timestamps.push_back(block.timestamp);
+        if self.timestamps.len() > self.rules.median_window {
+            self.timestamps.pop_front();
+        }
+
+        self.index += 1;
+        self.parent = Some(block.clone());
+        Some(Ok(block))
+    }
+}
+
+/// Opens a chain file for streaming. `.json` files are read as a JSON array;
+/// other extensions are matched with `Format::from_path`.
+pub fn open_chain_file(path: impl AsRef<Path>) -> io::Result<ChainReader<File>> {
+    let path = path.as_ref();
+    let is_array = path.extension().is_some_and(|ext| ext == "json");
+    let format = Format::from_path(path);
+    let file = File::open(path)?;
+    match (is_array, format) {
+        (true, _) => Ok(ChainReader::json_array(file)),
+        (false, Some(format)) => Ok(ChainReader::new(file, format)),
+        (false, None) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown chain file type: {}", path.display()),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transaction;
+    use crate::timestamp::ManualClock;
+    use crate::transfer::export_chain;
+
+    fn chain(len: u32) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for id in 0..len {
+            let prev_hash = blocks.last().map_or("0".to_string(), |b| b.hash.clone());
+            let txs = vec![Transaction {
+                id,
+                amount: id * 10,
+                sender: format!("user{}", id),
+                receiver: format!("user{}", id + 1),
+                fee: 0,
+                nonce: 0,
+            }];
+            blocks.push(Block::new(id, 1631234566 + id as u64, txs, prev_hash));
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_streams_json_array() {
+        let blocks = chain(50);
+        let json = serde_json::to_string_pretty(&blocks).unwrap();
+
+        let mut reader = ChainReader::json_array(json.as_bytes());
+        let first = reader.next().unwrap().unwrap();
+        assert_eq!(first.hash, blocks[0].hash);
+        assert_eq!(reader.blocks_read(), 1);
+        assert!(reader.offset() < json.len() as u64);
+
+        let report = ChainReader::json_array(json.as_bytes()).verify().unwrap();
+        assert_eq!(report.blocks_verified, 50);
+        assert_eq!(report.tip_hash, Some(blocks[49].hash.clone()));
+
+        let empty = ChainReader::json_array(" [ ] \n".as_bytes())
+            .verify()
+            .unwrap();
+        assert_eq!(empty.blocks_verified, 0);
+    }
+
+    #[test]
+    fn test_streams_export_formats() {
+        let blocks = chain(10);
+        for format in [Format::JsonLines, Format::Cbor, Format::Binary] {
+            let mut bytes = Vec::new();
+            export_chain(blocks.iter().cloned().map(Ok), format, &mut bytes, |_| {}).unwrap();
+            let read: Vec<Block> = ChainReader::new(&bytes[..], format)
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(read.len(), 10);
+            assert_eq!(read[9].hash, blocks[9].hash);
+        }
+    }
+
+    #[test]
+    fn test_break_reports_block_and_offset() {
+        let mut blocks = chain(4);
+        blocks[2].transactions[0].amount = 7;
+        let json = serde_json::to_string(&blocks).unwrap();
+        let third = json.find(r#"{"id":2"#).unwrap() as u64;
+
+        let mut reader = ChainReader::json_array(json.as_bytes());
+        let results: Vec<_> = reader.by_ref().collect();
+        assert_eq!(results.len(), 3);
+        let err = results[2].as_ref().unwrap_err();
+        assert_eq!(err.index, 2);
+        assert_eq!(err.offset, third);
+        assert!(matches!(
+            err.kind,
+            StreamErrorKind::Break(BreakReason::MerkleRootMismatch { .. })
+        ));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_decode_error_position() {
+        let blocks = chain(3);
+        let json = serde_json::to_string(&blocks).unwrap();
+        let cut = json.find(r#"{"id":2"#).unwrap() + 10;
+        let broken = format!("{}@@@", &json[..cut]);
+
+        let err = ChainReader::json_array(broken.as_bytes())
+            .verify()
+            .unwrap_err();
+        assert_eq!(err.index, 2);
+        assert!(matches!(err.kind, StreamErrorKind::Decode(_)));
+        assert!(err.offset > cut as u64 - 10 && err.offset <= broken.len() as u64);
+        assert!(err.to_string().starts_with("block 2 at byte "));
+
+        let err = ChainReader::json_array(r#"{"id":0}"#.as_bytes())
+            .verify()
+            .unwrap_err();
+        assert_eq!((err.index, err.offset), (0, 0));
+    }
+
+    #[test]
+    fn test_applies_timestamp_rules() {
+        // `chain` starts at 1631234566, one block per second.
+        let json = serde_json::to_string(&chain(5)).unwrap();
+        let rules = TimestampRules {
+            median_window: 3,
+            max_future_drift: 0,
+        };
+        let err = ChainReader::json_array(json.as_bytes())
+            .with_timestamp_rules(rules, ManualClock::new(1631234568))
+            .verify()
+            .unwrap_err();
+        assert_eq!(err.index, 3);
+        assert!(matches!(
+            err.kind,
+            StreamErrorKind::Break(BreakReason::TimestampTooFarAhead { .. })
+        ));
+
+        // A block that does not move past its parent's time.
+        let genesis = Block::new(0, 100, vec![], "0".to_string());
+        let stalled = Block::new(1, 100, vec![], genesis.hash.clone());
+        let json = serde_json::to_string(&[genesis, stalled]).unwrap();
+        let err = ChainReader::json_array(json.as_bytes())
+            .verify()
+            .unwrap_err();
+        assert_eq!(err.index, 1);
+        assert!(matches!(
+            err.kind,
+            StreamErrorKind::Break(BreakReason::TimestampNotAfterMedian { .. })
+        ));
+    }
+}
@@ -0,0 +1,675 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::fmt;
+
+use crate::ledger::{BlockChainError, Ledger, MINT_SENDER};
+use crate::params::{ConsensusParams, LimitError};
+use crate::timestamp::{Clock, TimestampRules, median_time_past};
+use crate::{Block, BlockHeader, Transaction};
+
+/// Why a candidate was left out of the template for good. Candidates that
+/// are fine but did not fit are not reported; they can go in a later block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Already used on chain.
+    StaleNonce {
+        expected: u64,
+        found: u64,
+    },
+    /// An earlier nonce from the same sender is missing.
+    NonceGap {
+        expected: u64,
+        found: u64,
+    },
+    /// Another transaction with the same sender and nonce pays a better fee
+    /// rate.
+    Replaced,
+    /// The sender cannot cover the amount plus fee; short by this much.
+    InsufficientFunds(u64),
+    /// An earlier transaction from the same sender was skipped.
+    Blocked,
+    Limit(LimitError),
+    Invalid(String),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SkipReason::StaleNonce { expected, found } => {
+                write!(f, "nonce {} already used, next is {}", found, expected)
+            }
+            SkipReason::NonceGap { expected, found } => {
+                write!(f, "nonce {} is ahead of the next one, {}", found, expected)
+            }
+            SkipReason::Replaced => write!(f, "replaced by a higher fee rate"),
+            SkipReason::InsufficientFunds(deficit) => write!(f, "short by {}", deficit),
+            SkipReason::Blocked => write!(f, "an earlier nonce was skipped"),
+            SkipReason::Limit(e) => write!(f, "{}", e),
+            SkipReason::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// Why no template could be built at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    /// Even a block holding just the reward breaks the limits.
+    Limit(LimitError),
+    /// The ledger rejected the assembled block, e.g. because its subsidy is
+    /// not the one in `params` or the reward overflows the miner's balance.
+    State(BlockChainError),
+    /// The parent is already at the highest id a block can have.
+    HeightOverflow(u32),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::Limit(e) => write!(f, "{}", e),
+            TemplateError::State(e) => write!(f, "{}", e),
+            TemplateError::HeightOverflow(parent) => {
+                write!(f, "parent block id {} has no successor", parent)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// A block ready to be mined or proposed: reward transaction first, state
+/// root committed, difficulty and nonce still unset.
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    pub block: Block,
+    /// Fees collected by the reward transaction.
+    pub fees: u64,
+    /// Transaction id and reason for every candidate dropped as invalid or
+    /// conflicting.
+    pub skipped: Vec<(u32, SkipReason)>,
+}
+
+/// Assembles block templates from pending transactions.
+///
+/// Transactions are picked greedily by fee rate (fee per encoded byte)
+/// within the consensus size limits. A sender's transactions only go in
+/// nonce order starting at its ledger nonce, so a sender's later
+/// transactions wait behind its earlier ones whatever their fee. The order
+/// comes from `Transaction::nonce`, which `Ledger::apply_block` enforces, so
+/// a block that ignored it would not be valid.
+pub struct TemplateBuilder {
+    miner: String,
+    params: ConsensusParams,
+    rules: TimestampRules,
+}
+
+/// Next transaction of one sender, ordered by fee rate.
+struct Ready {
+    fee: u64,
+    bytes: usize,
+    sender: String,
+}
+
+/// Compares the fee per byte of `fee` over `bytes` with `other_fee` over
+/// `other_bytes`, without rounding.
+pub fn rate_cmp(fee: u64, bytes: usize, other_fee: u64, other_bytes: usize) -> Ordering {
+    (fee as u128 * other_bytes as u128).cmp(&(other_fee as u128 * bytes as u128))
+}
+
+impl PartialEq for Ready {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ready {}
+
+impl PartialOrd for Ready {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ready {
+    /// Best fee rate first; ties go to the smaller sender so templates do not
+    /// depend on input order.
+    fn cmp(&self, other: &Self) -> Ordering {
+        rate_cmp(self.fee, self.bytes, other.fee, other.bytes)
+            .then_with(|| other.sender.cmp(&self.sender))
+    }
+}
+
+struct Pending {
+    tx: Transaction,
+    bytes: usize,
+}
+
+fn ready(queue: &VecDeque<Pending>) -> Option<Ready> {
+    queue.front().map(|p| Ready {
+        fee: p.tx.fee,
+        bytes: p.bytes,
+        sender: p.tx.sender.clone(),
+    })
+}
+
+fn check_candidate(tx: &Transaction) -> Result<(), SkipReason> {
+    if tx.sender == MINT_SENDER {
+        return Err(SkipReason::Invalid("only the reward may mint".to_string()));
+    }
+    if tx.amount == 0 || tx.receiver.is_empty() {
+        return Err(SkipReason::Invalid(
+            "needs an amount and a receiver".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+impl TemplateBuilder {
+    pub fn new(miner: impl Into<String>, params: ConsensusParams) -> Self {
+        TemplateBuilder {
+            miner: miner.into(),
+            params,
+            rules: TimestampRules::default(),
+        }
+    }
+
+    /// Picks timestamps that pass `rules` rather than the default ones.
+    pub fn with_timestamp_rules(mut self, rules: TimestampRules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Sorts candidates into per-sender queues in nonce order, dropping the
+    /// ones no block on top of `ledger` could take.
+    fn queues(
+        &self,
+        ledger: &Ledger,
+        candidates: impl IntoIterator<Item = Transaction>,
+        skipped: &mut Vec<(u32, SkipReason)>,
+    ) -> HashMap<String, VecDeque<Pending>> {
+        let mut by_sender: HashMap<String, Vec<Pending>> = HashMap::new();
+        for transaction in candidates {
+            let checked = check_candidate(&transaction).and_then(|()| {
+                self.params
+                    .check_transaction(&transaction)
+                    .map_err(SkipReason::Limit)
+            });
+            match checked {
+                Ok(bytes) => by_sender
+                    .entry(transaction.sender.clone())
+                    .or_default()
+                    .push(Pending {
+                        tx: transaction,
+                        bytes,
+                    }),
+                Err(reason) => skipped.push((transaction.id, reason)),
+            }
+        }
+
+        let mut queues = HashMap::new();
+        for (sender, mut pending) in by_sender {
+            // Best fee rate first among equal nonces, so dedup keeps it.
+            pending.sort_by(|a, b| {
+                a.tx.nonce
+                    .cmp(&b.tx.nonce)
+                    .then_with(|| rate_cmp(b.tx.fee, b.bytes, a.tx.fee, a.bytes))
+            });
+            let mut queue = VecDeque::new();
+            let mut expected = ledger.nonce(&sender);
+            let mut last: Option<u64> = None;
+            for p in pending {
+                let reason = if last == Some(p.tx.nonce) {
+                    Some(SkipReason::Replaced)
+                } else if p.tx.nonce < ledger.nonce(&sender) {
+                    Some(SkipReason::StaleNonce {
+                        expected: ledger.nonce(&sender),
+                        found: p.tx.nonce,
+                    })
+                } else if p.tx.nonce != expected {
+                    Some(SkipReason::NonceGap {
+                        expected,
+                        found: p.tx.nonce,
+                    })
+                } else {
+                    None
+                };
+                last = Some(p.tx.nonce);
+                match reason {
+                    Some(reason) => skipped.push((p.tx.id, reason)),
+                    None => {
+                        expected += 1;
+                        queue.push_back(p);
+                    }
+                }
+            }
+            if !queue.is_empty() {
+                queues.insert(sender, queue);
+            }
+        }
+        queues
+    }
+
+    /// Builds a block on top of `parent`, whose state is `ledger`, from
+    /// `candidates`. `recent` holds the timestamps of the last blocks up to
+    /// and including `parent`, oldest first (see `ChainTree::recent_timestamps`).
+    /// The timestamp is the clock's, raised if the clock is behind to one
+    /// second past the median of `recent` and to no earlier than the parent.
+    ///
+    /// Candidates the ledger would reject are skipped; the build as a whole
+    /// fails only if the block left over is still invalid.
+    pub fn build(
+        &self,
+        parent: &BlockHeader,
+        recent: &[u64],
+        ledger: &Ledger,
+        candidates: impl IntoIterator<Item = Transaction>,
+        clock: &dyn Clock,
+    ) -> Result<BlockTemplate, TemplateError> {
+        let height = parent
+            .id
+            .checked_add(1)
+            .ok_or(TemplateError::HeightOverflow(parent.id))?;
+        let window = &recent[recent.len().saturating_sub(self.rules.median_window)..];
+        let median = median_time_past(window).unwrap_or(parent.timestamp);
+        let timestamp = clock
+            .now()
+            .max(median.saturating_add(1))
+            .max(parent.timestamp);
+        let mut skipped = Vec::new();
+        let mut queues = self.queues(ledger, candidates, &mut skipped);
+
+        // Size of the block with just the reward, with every field that is
+        // filled in later (state root, proof of work) at its widest.
+        let reward = |amount: u32| Transaction {
+            id: height,
+            amount,
+            sender: MINT_SENDER.to_string(),
+            receiver: self.miner.clone(),
+            fee: 0,
+            nonce: 0,
+        };
+        let mut widest = Block::new(height, timestamp, vec![reward(u32::MAX)], String::new());
+        widest.prev_hash = parent.compute_hash();
+        widest.state_root = "0".repeat(64);
+        widest.difficulty = u32::MAX;
+        widest.nonce = u64::MAX;
+        let mut bytes = serde_json::to_vec(&widest)
+            .expect("Serialization failed")
+            .len();
+
+        let mut heap: BinaryHeap<Ready> = queues.values().filter_map(ready).collect();
+        let mut spendable: HashMap<String, u64> = HashMap::new();
+        let mut txs = Vec::new();
+        let mut fees = 0u64;
+
+        while let Some(Ready { sender, .. }) = heap.pop() {
+            if txs.len() + 1 >= self.params.max_block_transactions {
+                break;
+            }
+            let queue = queues.get_mut(&sender).expect("queued sender");
+            let next = queue.front().expect("ready sender has a transaction");
+
+            // Each transaction after the reward also adds a comma.
+            let fits = bytes + next.bytes < self.params.max_block_bytes;
+            let reward_fits = fees
+                .checked_add(next.tx.fee)
+                .and_then(|f| f.checked_add(self.params.block_subsidy))
+                .is_some_and(|total| total <= u32::MAX as u64);
+            if !fits || !reward_fits {
+                // Later nonces cannot jump the queue, so the sender is done.
+                continue;
+            }
+
+            let amount = next.tx.amount as u64;
+            let balance = *spendable
+                .entry(sender.clone())
+                .or_insert_with(|| ledger.balance(&sender));
+            // The same checks `apply_block` makes, in the same order.
+            let reason = match amount.checked_add(next.tx.fee) {
+                None => Some(SkipReason::Invalid("fee overflow".to_string())),
+                Some(cost) if balance < cost => Some(SkipReason::InsufficientFunds(cost - balance)),
+                Some(cost) => {
+                    let receiver = if next.tx.receiver == sender {
+                        balance - cost
+                    } else {
+                        spendable
+                            .get(&next.tx.receiver)
+                            .copied()
+                            .unwrap_or_else(|| ledger.balance(&next.tx.receiver))
+                    };
+                    match receiver.checked_add(amount) {
+                        None => Some(SkipReason::Invalid("receiver balance overflow".to_string())),
+                        Some(credited) => {
+                            spendable.insert(sender.clone(), balance - cost);
+                            spendable.insert(next.tx.receiver.clone(), credited);
+                            None
+                        }
+                    }
+                }
+            };
+            if let Some(reason) = reason {
+                skipped.push((next.tx.id, reason));
+                for blocked in queue.drain(1..) {
+                    skipped.push((blocked.tx.id, SkipReason::Blocked));
+                }
+                continue;
+            }
+
+            let p = queue.pop_front().expect("checked above");
+            bytes += p.bytes + 1;
+            fees += p.tx.fee;
+            txs.push(p.tx);
+            heap.extend(ready(queue));
+        }
+
+        // Validated params keep the subsidy within a transaction amount.
+        let total =
+            u32::try_from(self.params.block_subsidy.saturating_add(fees)).unwrap_or(u32::MAX);
+        let mut transactions = Vec::with_capacity(txs.len() + 1);
+        if total > 0 {
+            transactions.push(reward(total));
+        }
+        transactions.extend(txs);
+        let block = Block::new(height, timestamp, transactions, parent.compute_hash());
+        let state_root = ledger
+            .state_root_after(&block)
+            .map_err(TemplateError::State)?;
+        let block = block.with_state_root(state_root);
+        self.params
+            .check_block(&block)
+            .map_err(TemplateError::Limit)?;
+        Ok(BlockTemplate {
+            block,
+            fees,
+            skipped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Account;
+    use crate::timestamp::ManualClock;
+
+    fn ledger() -> Ledger {
+        Ledger::from_accounts([
+            (
+                "alice".to_string(),
+                Account {
+                    balance: 100,
+                    nonce: 0,
+                },
+            ),
+            (
+                "bob".to_string(),
+                Account {
+                    balance: 100,
+                    nonce: 2,
+                },
+            ),
+        ])
+    }
+
+    fn parent() -> BlockHeader {
+        Block::new(0, 1000, vec![], "0".to_string()).header()
+    }
+
+    fn candidate(id: u32, sender: &str, amount: u32, fee: u64, nonce: u64) -> Transaction {
+        Transaction {
+            id,
+            amount,
+            sender: sender.to_string(),
+            receiver: "carol".to_string(),
+            fee,
+            nonce,
+        }
+    }
+
+    fn ids(template: &BlockTemplate) -> Vec<u32> {
+        template.block.transactions[1..]
+            .iter()
+            .map(|tx| tx.id)
+            .collect()
+    }
+
+    #[test]
+    fn test_orders_by_fee_rate_within_nonce_order() {
+        let params = ConsensusParams::default();
+        let builder = TemplateBuilder::new("miner", params);
+        let candidates = vec![
+            candidate(1, "alice", 10, 1, 0),
+            // alice's second transaction pays the most but must wait.
+            candidate(2, "alice", 10, 9, 1),
+            candidate(3, "bob", 10, 5, 2),
+            candidate(4, "bob", 10, 2, 3),
+        ];
+        let template = builder
+            .build(
+                &parent(),
+                &[1000],
+                &ledger(),
+                candidates,
+                &ManualClock::new(2000),
+            )
+            .unwrap();
+
+        assert_eq!(ids(&template), [3, 4, 1, 2]);
+        assert_eq!(template.fees, 17);
+        assert!(template.skipped.is_empty());
+        let block = &template.block;
+        assert_eq!((block.id, block.timestamp), (1, 2000));
+        assert_eq!(block.prev_hash, parent().compute_hash());
+        assert_eq!(
+            block.transactions[0].amount as u64,
+            params.block_subsidy + 17
+        );
+
+        // The template is a valid block on top of the ledger.
+        let mut next = ledger();
+        next.apply_block(block).unwrap();
+        assert_eq!(next.balance("miner"), 67);
+        assert_eq!(next.nonce("alice"), 2);
+        assert_eq!(next.total_supply(), 200 + params.block_subsidy);
+    }
+
+    #[test]
+    fn test_skips_invalid_and_conflicting() {
+        let params = ConsensusParams {
+            block_subsidy: 0,
+            ..ConsensusParams::default()
+        };
+        let builder = TemplateBuilder::new("miner", params);
+        let candidates = vec![
+            candidate(1, "alice", 10, 1, 0),
+            // Same nonce, better fee: replaces transaction 1.
+            candidate(2, "alice", 10, 3, 0),
+            candidate(3, "alice", 95, 1, 1),
+            candidate(4, "alice", 1, 1, 2),
+            candidate(5, "bob", 10, 1, 1),
+            candidate(6, "bob", 10, 1, 3),
+            candidate(7, MINT_SENDER, 10, 0, 0),
+            candidate(8, "dave", 0, 1, 0),
+        ];
+        let template = builder
+            .build(
+                &parent(),
+                &[1000],
+                &ledger(),
+                candidates,
+                &ManualClock::new(0),
+            )
+            .unwrap();
+
+        assert_eq!(ids(&template), [2]);
+        assert_eq!(template.block.timestamp, 1001);
+        let mut skipped = template.skipped.clone();
+        skipped.sort_by_key(|(id, _)| *id);
+        let reasons: Vec<(u32, SkipReason)> = vec![
+            (1, SkipReason::Replaced),
+            (3, SkipReason::InsufficientFunds(9)),
+            (4, SkipReason::Blocked),
+            (
+                5,
+                SkipReason::StaleNonce {
+                    expected: 2,
+                    found: 1,
+                },
+            ),
+            (
+                6,
+                SkipReason::NonceGap {
+                    expected: 2,
+                    found: 3,
+                },
+            ),
+        ];
+        assert_eq!(skipped[..5], reasons[..]);
+        assert!(matches!(skipped[5], (7, SkipReason::Invalid(_))));
+        assert!(matches!(skipped[6], (8, SkipReason::Invalid(_))));
+    }
+
+    #[test]
+    fn test_ledger_failures_do_not_panic() {
+        let mut accounts: Vec<(String, Account)> = ledger()
+            .accounts()
+            .map(|(a, acc)| (a.to_string(), *acc))
+            .collect();
+        accounts.push((
+            "carol".to_string(),
+            Account {
+                balance: u64::MAX - 5,
+                nonce: 0,
+            },
+        ));
+        let full = Ledger::from_accounts(accounts);
+        let clock = ManualClock::new(2000);
+
+        // Paying carol would overflow her balance, so alice's chain stops.
+        let template = TemplateBuilder::new("miner", ConsensusParams::default())
+            .build(
+                &parent(),
+                &[1000],
+                &full,
+                vec![
+                    candidate(1, "alice", 10, 1, 0),
+                    candidate(2, "alice", 1, 1, 1),
+                ],
+                &clock,
+            )
+            .unwrap();
+        assert!(ids(&template).is_empty());
+        assert!(matches!(template.skipped[0], (1, SkipReason::Invalid(_))));
+        assert_eq!(template.skipped[1], (2, SkipReason::Blocked));
+
+        // A ledger with a smaller subsidy than the params rejects the reward.
+        let stingy = ledger().with_subsidy(10);
+        assert!(matches!(
+            TemplateBuilder::new("miner", ConsensusParams::default()).build(
+                &parent(),
+                &[1000],
+                &stingy,
+                vec![],
+                &clock
+            ),
+            Err(TemplateError::State(BlockChainError::RewardTooLarge { .. }))
+        ));
+
+        // Nothing can follow the highest block id.
+        let mut last = parent();
+        last.id = u32::MAX;
+        assert!(matches!(
+            TemplateBuilder::new("miner", ConsensusParams::default()).build(
+                &last,
+                &[1000],
+                &ledger(),
+                vec![],
+                &clock
+            ),
+            Err(TemplateError::HeightOverflow(u32::MAX))
+        ));
+    }
+
+    #[test]
+    fn test_respects_size_limits() {
+        let txs: Vec<Transaction> = (0..10)
+            .map(|i| candidate(i, &format!("user{}", i), 1, i as u64, 0))
+            .collect();
+        let funded = Ledger::from_accounts((0..10).map(|i| {
+            (
+                format!("user{}", i),
+                Account {
+                    balance: 100,
+                    nonce: 0,
+                },
+            )
+        }));
+        let clock = ManualClock::new(2000);
+
+        let params = ConsensusParams {
+            max_block_transactions: 4,
+            ..ConsensusParams::default()
+        };
+        let template = TemplateBuilder::new("miner", params)
+            .build(&parent(), &[1000], &funded, txs.clone(), &clock)
+            .unwrap();
+        // The reward takes one slot; the best three fee payers take the rest.
+        assert_eq!(ids(&template), [9, 8, 7]);
+        assert!(template.skipped.is_empty());
+
+        let one_tx = TemplateBuilder::new("miner", ConsensusParams::default())
+            .build(&parent(), &[1000], &funded, txs[..1].to_vec(), &clock)
+            .unwrap();
+        let bytes = serde_json::to_vec(&one_tx.block).unwrap().len();
+        let params = ConsensusParams {
+            // Room for the widest one-transaction block, not for two.
+            max_block_bytes: bytes + 60,
+            max_transaction_bytes: 100,
+            ..ConsensusParams::default()
+        };
+        let template = TemplateBuilder::new("miner", params)
+            .build(&parent(), &[1000], &funded, txs.clone(), &clock)
+            .unwrap();
+        assert_eq!(ids(&template), [9]);
+        let mined = template.block.mine(1).unwrap();
+        assert!(params.check_block(&mined).is_ok());
+
+        let tiny = ConsensusParams {
+            max_block_bytes: 100,
+            max_transaction_bytes: 100,
+            ..ConsensusParams::default()
+        };
+        assert!(
+            TemplateBuilder::new("miner", tiny)
+                .build(&parent(), &[1000], &funded, txs, &clock)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_timestamp_beats_median_time_past() {
+        let timestamp = |builder: &TemplateBuilder, recent: &[u64], now: u64| {
+            builder
+                .build(&parent(), recent, &ledger(), vec![], &ManualClock::new(now))
+                .unwrap()
+                .block
+                .timestamp
+        };
+        let builder = TemplateBuilder::new("miner", ConsensusParams::default());
+        // The parent is at 1000 but the median of its window is 950.
+        let recent = [900, 950, 1000];
+        assert_eq!(timestamp(&builder, &recent, 0), 1000);
+        assert!(
+            TimestampRules::default()
+                .check(1000, &recent, &ManualClock::new(0))
+                .is_ok()
+        );
+        assert_eq!(timestamp(&builder, &recent, 2000), 2000);
+        assert_eq!(timestamp(&builder, &[1000, 1000, 1000], 0), 1001);
+
+        let narrow = builder.with_timestamp_rules(TimestampRules {
+            median_window: 1,
+            ..TimestampRules::default()
+        });
+        assert_eq!(timestamp(&narrow, &recent, 0), 1001);
+    }
+}
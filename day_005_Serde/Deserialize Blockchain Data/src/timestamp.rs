@@ -0,0 +1,204 @@
+use std::cell::Cell;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Block;
+use crate::verify::{BreakReason, ChainBreak};
+
+/// Source of the local time, in Unix seconds. Injected wherever the future
+/// drift rule is checked so tests can pin the time.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: Cell<u64>,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        ManualClock {
+            now: Cell::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, secs: u64) {
+        self.now.set(self.now.get() + secs);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+/// Median of `timestamps`, or `None` if there are none. For an even count the
+/// lower of the two middle values is used.
+pub fn median_time_past(timestamps: &[u64]) -> Option<u64> {
+    if timestamps.is_empty() {
+        return None;
+    }
+    let mut sorted = timestamps.to_vec();
+    sorted.sort_unstable();
+    Some(sorted[(sorted.len() - 1) / 2])
+}
+
+/// Bitcoin-style timestamp rules.
+///
+/// A block must be later than the median of its last `median_window`
+/// ancestors, which a single miner with a skewed clock cannot drag around,
+/// and at most `max_future_drift` seconds ahead of the local clock. A block
+/// rejected for drift is not invalid for good; it can be retried later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampRules {
+    pub median_window: usize,
+    pub max_future_drift: u64,
+}
+
+impl Default for TimestampRules {
+    fn default() -> Self {
+        TimestampRules {
+            median_window: 11,
+            max_future_drift: 2 * 60 * 60,
+        }
+    }
+}
+
+impl TimestampRules {
+    /// Checks `timestamp` against the timestamps of its ancestors (oldest
+    /// first; only the last `median_window` are used) and the clock.
+    pub fn check(
+        &self,
+        timestamp: u64,
+        ancestors: &[u64],
+        clock: &dyn Clock,
+    ) -> Result<(), BreakReason> {
+        let recent = &ancestors[ancestors.len().saturating_sub(self.median_window)..];
+        if let Some(median) = median_time_past(recent)
+            && timestamp <= median
+        {
+            return Err(BreakReason::TimestampNotAfterMedian {
+                median,
+                found: timestamp,
+            });
+        }
+        let latest = clock.now().saturating_add(self.max_future_drift);
+        if timestamp > latest {
+            return Err(BreakReason::TimestampTooFarAhead {
+                latest,
+                found: timestamp,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Applies `rules` to every block of a chain that is otherwise assumed valid;
+/// `verify_chain_report_within` makes the same checks alongside the rest.
+/// Genesis is only held to the drift limit.
+pub fn verify_chain_timestamps(
+    chain: &[Block],
+    rules: &TimestampRules,
+    clock: &dyn Clock,
+) -> Result<(), ChainBreak> {
+    let mut timestamps = Vec::with_capacity(chain.len());
+    for (index, block) in chain.iter().enumerate() {
+        rules
+            .check(block.timestamp, &timestamps, clock)
+            .map_err(|reason| ChainBreak {
+                index,
+                block_id: block.id,
+                reason,
+            })?;
+        timestamps.push(block.timestamp);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(timestamps: &[u64]) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for (id, timestamp) in timestamps.iter().enumerate() {
+            let prev = blocks.last().map_or("0".to_string(), |b| b.hash.clone());
+            blocks.push(Block::new(id as u32, *timestamp, vec![], prev));
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        assert_eq!(median_time_past(&[]), None);
+        assert_eq!(median_time_past(&[5]), Some(5));
+        assert_eq!(median_time_past(&[9, 1, 5]), Some(5));
+        assert_eq!(median_time_past(&[4, 1, 3, 2]), Some(2));
+    }
+
+    #[test]
+    fn test_block_must_follow_median() {
+        let clock = ManualClock::new(1000);
+        let rules = TimestampRules {
+            median_window: 3,
+            max_future_drift: 60,
+        };
+        // A block may be older than its parent as long as it beats the median.
+        assert!(rules.check(115, &[100, 110, 120], &clock).is_ok());
+        assert_eq!(
+            rules.check(110, &[100, 110, 120], &clock),
+            Err(BreakReason::TimestampNotAfterMedian {
+                median: 110,
+                found: 110
+            })
+        );
+        // Only the last `median_window` ancestors count.
+        assert!(rules.check(105, &[500, 600, 100, 101, 102], &clock).is_ok());
+
+        let blocks = chain(&[100, 200, 150, 300, 160]);
+        let err = verify_chain_timestamps(&blocks, &rules, &clock).unwrap_err();
+        assert_eq!(err.index, 4);
+        assert!(verify_chain_timestamps(&blocks[..4], &rules, &clock).is_ok());
+    }
+
+    #[test]
+    fn test_future_drift_uses_injected_clock() {
+        let clock = ManualClock::new(1000);
+        let rules = TimestampRules {
+            median_window: 11,
+            max_future_drift: 60,
+        };
+        assert!(rules.check(1060, &[900], &clock).is_ok());
+        assert_eq!(
+            rules.check(1061, &[900], &clock),
+            Err(BreakReason::TimestampTooFarAhead {
+                latest: 1060,
+                found: 1061
+            })
+        );
+        clock.advance(1);
+        assert!(rules.check(1061, &[900], &clock).is_ok());
+
+        let blocks = chain(&[2000]);
+        clock.set(0);
+        assert!(verify_chain_timestamps(&blocks, &rules, &clock).is_err());
+        assert!(verify_chain_timestamps(&blocks, &rules, &SystemClock).is_ok());
+    }
+}
@@ -0,0 +1,617 @@
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::store::BlockStore;
+use crate::stream::{ChainReader, StreamError};
+use crate::{Block, Transaction};
+
+/// Layout version of exported chains, recorded in the header that starts
+/// every export. Changing how blocks are encoded in any format means bumping
+/// it and adding golden exports for the new version.
+///
+/// Version 2 added `Transaction::nonce`.
+pub const EXPORT_VERSION: u32 = 2;
+
+const EXPORT_KIND: &str = "chain";
+
+/// First record of an export, encoded like the blocks that follow it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct ExportHeader {
+    kind: String,
+    version: u32,
+}
+
+/// A block as version 1 binary exports hold it, before transactions had a
+/// nonce. The other formats name their fields, so a missing nonce simply
+/// defaults there; bincode needs the old layout spelled out.
+#[derive(Deserialize)]
+struct BlockV1 {
+    id: u32,
+    timestamp: u64,
+    transactions: Vec<TransactionV1>,
+    prev_hash: String,
+    merkle_root: String,
+    state_root: String,
+    difficulty: u32,
+    nonce: u64,
+}
+
+#[derive(Deserialize)]
+struct TransactionV1 {
+    id: u32,
+    amount: u32,
+    sender: String,
+    receiver: String,
+    fee: u64,
+}
+
+impl From<BlockV1> for Block {
+    fn from(block: BlockV1) -> Self {
+        let transactions = block
+            .transactions
+            .into_iter()
+            .map(|tx| Transaction {
+                id: tx.id,
+                amount: tx.amount,
+                sender: tx.sender,
+                receiver: tx.receiver,
+                fee: tx.fee,
+                nonce: 0,
+            })
+            .collect();
+        Block {
+            id: block.id,
+            timestamp: block.timestamp,
+            transactions,
+            prev_hash: block.prev_hash,
+            merkle_root: block.merkle_root,
+            state_root: block.state_root,
+            difficulty: block.difficulty,
+            nonce: block.nonce,
+            hash: String::new(),
+        }
+    }
+}
+
+/// On-disk layout of an exported chain. Every format is a header record
+/// followed by a plain sequence of blocks, so files can be written and read
+/// one block at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object per line.
+    JsonLines,
+    /// A CBOR sequence (RFC 8742): CBOR items back to back.
+    Cbor,
+    /// `u32` little-endian length followed by a bincode payload.
+    Binary,
+}
+
+impl Format {
+    /// Picks a format from a file extension: `.jsonl`, `.cbor` or `.bin`.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Format> {
+        path.as_ref().extension()?.to_str()?.parse().ok()
+    }
+
+    /// The file extension `from_path` maps to this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::JsonLines => "jsonl",
+            Format::Cbor => "cbor",
+            Format::Binary => "bin",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" | "json-lines" => Ok(Format::JsonLines),
+            "cbor" => Ok(Format::Cbor),
+            "bin" | "binary" => Ok(Format::Binary),
+            other => Err(format!("unknown chain format {:?}", other)),
+        }
+    }
+}
+
+/// Running totals handed to the progress callback after every block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+/// Result of an import.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Blocks the store already had, checked and skipped.
+    pub skipped: u64,
+    /// Blocks verified and appended by this import.
+    pub imported: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    Io(io::Error),
+    Encode(String),
+    /// A block could not be decoded or failed verification against its parent.
+    Read(StreamError),
+    /// The stream disagrees with a block the store already holds, so it is
+    /// not a continuation of the stored chain.
+    Diverged {
+        height: u32,
+    },
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransferError::Io(e) => write!(f, "I/O error: {}", e),
+            TransferError::Encode(msg) => write!(f, "cannot encode block: {}", msg),
+            TransferError::Read(e) => write!(f, "{}", e),
+            TransferError::Diverged { height } => {
+                write!(f, "block {} differs from the one already stored", height)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> Self {
+        TransferError::Io(e)
+    }
+}
+
+/// Counts the bytes passing through a reader or writer.
+pub(crate) struct Counting<T> {
+    pub(crate) inner: T,
+    pub(crate) count: u64,
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Counting<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.count += amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes the header every export starts with.
+pub fn write_header(writer: &mut impl Write, format: Format) -> Result<(), TransferError> {
+    let header = ExportHeader {
+        kind: EXPORT_KIND.to_string(),
+        version: EXPORT_VERSION,
+    };
+    write_record(writer, format, &header)
+}
+
+/// Writes one block in `format`.
+pub fn write_block(
+    writer: &mut impl Write,
+    format: Format,
+    block: &Block,
+) -> Result<(), TransferError> {
+    write_record(writer, format, block)
+}
+
+fn write_record(
+    writer: &mut impl Write,
+    format: Format,
+    record: &impl Serialize,
+) -> Result<(), TransferError> {
+    match format {
+        Format::JsonLines => {
+            let mut line =
+                serde_json::to_vec(record).map_err(|e| TransferError::Encode(e.to_string()))?;
+            line.push(b'\n');
+            writer.write_all(&line)?;
+        }
+        Format::Cbor => ciborium::ser::into_writer(record, &mut *writer).map_err(|e| match e {
+            ciborium::ser::Error::Io(e) => TransferError::Io(e),
+            other => TransferError::Encode(other.to_string()),
+        })?,
+        Format::Binary => {
+            let payload =
+                bincode::serialize(record).map_err(|e| TransferError::Encode(e.to_string()))?;
+            writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+            writer.write_all(&payload)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads and checks the header of an export, rejecting input that is not an
+/// export or was written in a layout this version does not know. Returns the
+/// export's version, which `read_block` needs.
+pub fn read_header(reader: &mut impl BufRead, format: Format) -> io::Result<u32> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let header: ExportHeader = read_record(reader, format)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "missing export header"))?;
+    if header.kind != EXPORT_KIND {
+        return Err(invalid(format!(
+            "expected a {} export, found a {}",
+            EXPORT_KIND, header.kind
+        )));
+    }
+    if !(1..=EXPORT_VERSION).contains(&header.version) {
+        return Err(invalid(format!(
+            "export version {} is not supported (current is {})",
+            header.version, EXPORT_VERSION
+        )));
+    }
+    Ok(header.version)
+}
+
+/// Reads the next block of an export at `version`, or `None` at a clean end
+/// of input. The returned block has its `hash` filled in.
+pub fn read_block(
+    reader: &mut impl BufRead,
+    format: Format,
+    version: u32,
+) -> io::Result<Option<Block>> {
+    let block: Option<Block> = if format == Format::Binary && version == 1 {
+        read_record::<BlockV1>(reader, format)?.map(Block::from)
+    } else {
+        read_record(reader, format)?
+    };
+    Ok(block.map(|mut block| {
+        block.hash = block.compute_hash();
+        block
+    }))
+}
+
+fn read_record<T: DeserializeOwned>(
+    reader: &mut impl BufRead,
+    format: Format,
+) -> io::Result<Option<T>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let record = match format {
+        Format::JsonLines => {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            serde_json::from_str(line.trim_end()).map_err(|e| invalid(e.to_string()))?
+        }
+        Format::Cbor => ciborium::de::from_reader(&mut *reader).map_err(|e| match e {
+            ciborium::de::Error::Io(e) => e,
+            other => invalid(other.to_string()),
+        })?,
+        Format::Binary => {
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut len)?;
+            let len = u32::from_le_bytes(len) as u64;
+            let mut payload = Vec::new();
+            reader.take(len).read_to_end(&mut payload)?;
+            if payload.len() as u64 != len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated block record",
+                ));
+            }
+            bincode::deserialize(&payload).map_err(|e| invalid(e.to_string()))?
+        }
+    };
+    Ok(Some(record))
+}
+
+/// Streams `blocks` to `writer` in `format`, calling `progress` after each one.
+///
+/// Takes `io::Result` items so a `BlockStore::range` can be exported directly
+/// without loading the chain into memory.
+pub fn export_chain<I, W>(
+    blocks: I,
+    format: Format,
+    writer: W,
+    mut progress: impl FnMut(Progress),
+) -> Result<Progress, TransferError>
+where
+    I: IntoIterator<Item = io::Result<Block>>,
+    W: Write,
+{
+    let mut out = Counting {
+        inner: writer,
+        count: 0,
+    };
+    write_header(&mut out, format)?;
+    let mut totals = Progress {
+        blocks: 0,
+        bytes: out.count,
+    };
+    for block in blocks {
+        write_block(&mut out, format, &block?)?;
+        totals = Progress {
+            blocks: totals.blocks + 1,
+            bytes: out.count,
+        };
+        progress(totals);
+    }
+    out.flush()?;
+    Ok(totals)
+}
+
+/// Imports the chain `blocks` reads into `store`, appending each block only
+/// after the reader has verified it against its parent. Configure the reader
+/// with the chain's params, consensus engine and timestamp rules (see
+/// `ChainReader::with_params` and friends); what it accepts is what is
+/// imported.
+///
+/// Importing into a store that already holds a prefix of the chain resumes:
+/// the stored blocks are decoded and compared by hash instead of re-appended.
+/// Since every append is durable, an interrupted import can simply be rerun.
+pub fn import_chain(
+    mut blocks: ChainReader<impl Read>,
+    store: &mut BlockStore,
+    mut progress: impl FnMut(Progress),
+) -> Result<ImportSummary, TransferError> {
+    let mut summary = ImportSummary::default();
+    let stored = store.len() as u64;
+
+    while let Some(block) = blocks.next() {
+        let block = block.map_err(TransferError::Read)?;
+        let index = summary.skipped + summary.imported;
+        if index < stored {
+            if store.hash_at(index as u32) != Some(block.hash.as_str()) {
+                return Err(TransferError::Diverged {
+                    height: index as u32,
+                });
+            }
+            summary.skipped += 1;
+        } else {
+            store.append(&block)?;
+            summary.imported += 1;
+        }
+
+        summary.bytes = blocks.offset();
+        progress(Progress {
+            blocks: summary.skipped + summary.imported,
+            bytes: summary.bytes,
+        });
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transaction;
+    use crate::stream::StreamErrorKind;
+    use crate::verify::BreakReason;
+
+    fn chain(len: u32) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for id in 0..len {
+            let prev_hash = blocks.last().map_or("0".to_string(), |b| b.hash.clone());
+            let txs = vec![Transaction {
+                id,
+                amount: id * 10,
+                sender: format!("user{}", id),
+                receiver: format!("user{}", id + 1),
+                // Zero fees are left out of JSON but not of bincode.
+                fee: id as u64 % 2,
+                nonce: 0,
+            }];
+            blocks.push(Block::new(id, 1631234566 + id as u64, txs, prev_hash));
+        }
+        blocks
+    }
+
+    fn export(blocks: &[Block], format: Format) -> Vec<u8> {
+        let mut out = Vec::new();
+        export_chain(blocks.iter().cloned().map(Ok), format, &mut out, |_| {}).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_roundtrip_every_format() {
+        let blocks = chain(20);
+        for format in [Format::JsonLines, Format::Cbor, Format::Binary] {
+            let bytes = export(&blocks, format);
+            let dir = tempfile::tempdir().unwrap();
+            let mut store = BlockStore::open(dir.path()).unwrap();
+
+            let mut seen = Vec::new();
+            let summary = import_chain(ChainReader::new(&bytes[..], format), &mut store, |p| {
+                seen.push(p)
+            })
+            .unwrap();
+
+            assert_eq!(summary.imported, 20);
+            assert_eq!(summary.bytes, bytes.len() as u64);
+            assert_eq!(seen.len(), 20);
+            assert_eq!(seen.last().unwrap().bytes, bytes.len() as u64);
+            assert_eq!(store.tip(), Some((19, blocks[19].hash.as_str())));
+        }
+
+        assert!(export(&blocks, Format::Binary).len() < export(&blocks, Format::JsonLines).len());
+    }
+
+    #[test]
+    fn test_resume_partial_import() {
+        let blocks = chain(10);
+        let bytes = export(&blocks, Format::Cbor);
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut store = BlockStore::open(dir.path()).unwrap();
+            let partial = export(&blocks[..4], Format::Cbor);
+            import_chain(
+                ChainReader::new(&partial[..], Format::Cbor),
+                &mut store,
+                |_| {},
+            )
+            .unwrap();
+        }
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        let summary = import_chain(
+            ChainReader::new(&bytes[..], Format::Cbor),
+            &mut store,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!((summary.skipped, summary.imported), (4, 6));
+        assert_eq!(store.len(), 10);
+
+        let mut forked = blocks[..2].to_vec();
+        forked.push(Block::new(2, 1631234599, vec![], blocks[1].hash.clone()));
+        let err = import_chain(
+            ChainReader::new(&export(&forked, Format::Binary)[..], Format::Binary),
+            &mut store,
+            |_| {},
+        )
+        .unwrap_err();
+        assert!(matches!(err, TransferError::Diverged { height: 2 }));
+    }
+
+    #[test]
+    fn test_rejects_tampered_and_truncated_input() {
+        let mut blocks = chain(5);
+        blocks[3].transactions[0].amount = 1_000_000;
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = BlockStore::open(dir.path()).unwrap();
+
+        let bytes = export(&blocks, Format::JsonLines);
+        let err = import_chain(
+            ChainReader::new(&bytes[..], Format::JsonLines),
+            &mut store,
+            |_| {},
+        )
+        .unwrap_err();
+        match err {
+            TransferError::Read(StreamError {
+                index: 3,
+                kind: StreamErrorKind::Break(BreakReason::MerkleRootMismatch { .. }),
+                ..
+            }) => {}
+            other => panic!("unexpected error: {}", other),
+        }
+        assert_eq!(store.len(), 3);
+
+        let bytes = export(&chain(3), Format::Binary);
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        let err = import_chain(
+            ChainReader::new(&bytes[..bytes.len() - 3], Format::Binary),
+            &mut store,
+            |_| {},
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            TransferError::Read(StreamError {
+                index: 2,
+                kind: StreamErrorKind::Decode(_),
+                ..
+            })
+        ));
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(Format::from_path("chain.jsonl"), Some(Format::JsonLines));
+        assert_eq!(Format::from_path("/tmp/chain.cbor"), Some(Format::Cbor));
+        assert_eq!(Format::from_path("chain.bin"), Some(Format::Binary));
+        assert_eq!(Format::from_path("chain.json"), None);
+    }
+
+    fn golden(name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("golden")
+            .join(name);
+        std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+    }
+
+    /// Every export version stays importable, and today's exports match the
+    /// newest golden files byte for byte, so changing an encoding without
+    /// bumping `EXPORT_VERSION` fails here.
+    #[test]
+    fn test_golden_exports() {
+        let blocks = chain(3);
+        let hashes: Vec<&str> = blocks.iter().map(|b| b.hash.as_str()).collect();
+        for format in [Format::JsonLines, Format::Cbor, Format::Binary] {
+            for version in 1..=EXPORT_VERSION {
+                let bytes = golden(&format!("chain_v{}.{}", version, format.extension()));
+                let read: Vec<Block> = ChainReader::new(&bytes[..], format)
+                    .collect::<Result<_, _>>()
+                    .unwrap();
+                let read: Vec<&str> = read.iter().map(|b| b.hash.as_str()).collect();
+                assert_eq!(read, hashes, "{:?} version {}", format, version);
+            }
+            let latest = golden(&format!("chain_v{}.{}", EXPORT_VERSION, format.extension()));
+            assert_eq!(export(&blocks, format), latest, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_rejects_unknown_export_versions() {
+        let blocks = chain(2);
+        for format in [Format::JsonLines, Format::Cbor, Format::Binary] {
+            let mut bytes = Vec::new();
+            let header = ExportHeader {
+                kind: EXPORT_KIND.to_string(),
+                version: EXPORT_VERSION + 1,
+            };
+            write_record(&mut bytes, format, &header).unwrap();
+            write_block(&mut bytes, format, &blocks[0]).unwrap();
+            let err = ChainReader::new(&bytes[..], format).verify().unwrap_err();
+            assert!(
+                matches!(err.kind, StreamErrorKind::Decode(ref msg) if msg.contains("not supported")),
+                "{:?}: {}",
+                format,
+                err
+            );
+
+            // Bare blocks with no header at all.
+            let mut bytes = Vec::new();
+            write_block(&mut bytes, format, &blocks[0]).unwrap();
+            let err = ChainReader::new(&bytes[..], format).verify().unwrap_err();
+            assert!(
+                matches!(err.kind, StreamErrorKind::Decode(_)),
+                "{:?}",
+                format
+            );
+
+            let err = ChainReader::new(&[][..], format).verify().unwrap_err();
+            assert!(
+                matches!(err.kind, StreamErrorKind::Decode(_)),
+                "{:?}",
+                format
+            );
+        }
+    }
+}
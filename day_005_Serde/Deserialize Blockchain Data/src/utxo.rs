@@ -0,0 +1,495 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Reference to one output of an earlier transaction.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: String,
+    pub index: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxOut {
+    pub value: u64,
+    /// Hex-encoded ed25519 public key allowed to spend this output.
+    pub owner: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxIn {
+    pub prev: OutPoint,
+    /// Hex-encoded signature over `UtxoTransaction::sighash` by the owner of
+    /// `prev`. Empty until signed.
+    pub signature: String,
+}
+
+/// A coin transfer: spends earlier outputs and creates new ones. A transaction
+/// with no inputs is a coinbase and may only appear first in a block.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct UtxoTransaction {
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+}
+
+/// Hex-encoded public key, as used in `TxOut::owner`.
+pub fn address(key: &VerifyingKey) -> String {
+    hex::encode(key.to_bytes())
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+impl UtxoTransaction {
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Hash of the whole transaction, signatures included.
+    pub fn txid(&self) -> String {
+        sha256_hex(&serde_json::to_vec(self).expect("Serialization failed"))
+    }
+
+    /// What every input signs: the transaction with all signatures blanked.
+    pub fn sighash(&self) -> String {
+        let mut unsigned = self.clone();
+        for input in &mut unsigned.inputs {
+            input.signature.clear();
+        }
+        sha256_hex(&serde_json::to_vec(&unsigned).expect("Serialization failed"))
+    }
+
+    /// Signs input `index` with `key`.
+    pub fn sign_input(&mut self, index: usize, key: &SigningKey) {
+        let signature = key.sign(self.sighash().as_bytes());
+        self.inputs[index].signature = hex::encode(signature.to_bytes());
+    }
+
+    pub fn output_value(&self) -> Option<u64> {
+        self.outputs
+            .iter()
+            .try_fold(0u64, |sum, out| sum.checked_add(out.value))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UtxoError {
+    /// The input refers to an output that never existed or is already spent.
+    MissingInput(OutPoint),
+    BadSignature {
+        txid: String,
+        input: usize,
+    },
+    /// Inputs are worth less than the outputs they fund.
+    InsufficientInputs {
+        txid: String,
+        inputs: u64,
+        outputs: u64,
+    },
+    /// The coinbase pays out more than subsidy plus fees.
+    CoinbaseTooLarge {
+        allowed: u64,
+        claimed: u64,
+    },
+    MisplacedCoinbase(String),
+    /// An identical transaction already created this output.
+    DuplicateOutput(OutPoint),
+    Overflow(String),
+}
+
+impl fmt::Display for UtxoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UtxoError::MissingInput(op) => {
+                write!(
+                    f,
+                    "input {}:{} is missing or already spent",
+                    op.txid, op.index
+                )
+            }
+            UtxoError::BadSignature { txid, input } => {
+                write!(
+                    f,
+                    "input {} of transaction {} has a bad signature",
+                    input, txid
+                )
+            }
+            UtxoError::InsufficientInputs {
+                txid,
+                inputs,
+                outputs,
+            } => write!(
+                f,
+                "transaction {} spends {} but creates {}",
+                txid, inputs, outputs
+            ),
+            UtxoError::CoinbaseTooLarge { allowed, claimed } => {
+                write!(
+                    f,
+                    "coinbase claims {}, at most {} allowed",
+                    claimed, allowed
+                )
+            }
+            UtxoError::MisplacedCoinbase(txid) => {
+                write!(f, "coinbase {} is not the first transaction", txid)
+            }
+            UtxoError::DuplicateOutput(op) => {
+                write!(f, "output {}:{} already exists", op.txid, op.index)
+            }
+            UtxoError::Overflow(txid) => write!(f, "value overflow in transaction {}", txid),
+        }
+    }
+}
+
+impl Error for UtxoError {}
+
+/// What a block changed in the UTXO set, enough to take it back out.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockUndo {
+    /// Outputs the block spent, in spending order.
+    pub spent: Vec<(OutPoint, TxOut)>,
+    /// Outputs the block created.
+    pub created: Vec<OutPoint>,
+}
+
+/// The set of unspent transaction outputs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct UtxoSet {
+    coins: HashMap<OutPoint, TxOut>,
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&TxOut> {
+        self.coins.get(outpoint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.coins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.coins.is_empty()
+    }
+
+    /// Sum of unspent outputs owned by `owner`.
+    pub fn balance(&self, owner: &str) -> u64 {
+        self.coins
+            .values()
+            .filter(|out| out.owner == owner)
+            .map(|out| out.value)
+            .sum()
+    }
+
+    /// Unspent outputs owned by `owner`, in a stable order.
+    pub fn unspent_for(&self, owner: &str) -> Vec<(OutPoint, TxOut)> {
+        let mut coins: Vec<_> = self
+            .coins
+            .iter()
+            .filter(|(_, out)| out.owner == owner)
+            .map(|(op, out)| (op.clone(), out.clone()))
+            .collect();
+        coins.sort_by(|a, b| a.0.cmp(&b.0));
+        coins
+    }
+
+    /// Applies a block's transactions and returns the undo data for it.
+    ///
+    /// Every input must be unspent and signed by the owner of the output it
+    /// spends, and each transaction's inputs must cover its outputs; the
+    /// difference is the fee. An optional leading coinbase may claim up to
+    /// `subsidy` plus the block's fees. Outputs created earlier in the block
+    /// can be spent later in it. On error nothing is changed.
+    pub fn apply_block(
+        &mut self,
+        txs: &[UtxoTransaction],
+        subsidy: u64,
+    ) -> Result<BlockUndo, UtxoError> {
+        let mut undo = BlockUndo::default();
+        match self.apply_into(txs, subsidy, &mut undo) {
+            Ok(()) => Ok(undo),
+            Err(e) => {
+                self.revert_block(&undo);
+                Err(e)
+            }
+        }
+    }
+
+    fn apply_into(
+        &mut self,
+        txs: &[UtxoTransaction],
+        subsidy: u64,
+        undo: &mut BlockUndo,
+    ) -> Result<(), UtxoError> {
+        let mut fees = 0u64;
+        for (position, tx) in txs.iter().enumerate() {
+            let txid = tx.txid();
+            if tx.is_coinbase() && position != 0 {
+                return Err(UtxoError::MisplacedCoinbase(txid));
+            }
+            let outputs = tx
+                .output_value()
+                .ok_or_else(|| UtxoError::Overflow(txid.clone()))?;
+
+            if !tx.is_coinbase() {
+                let inputs = self.spend_inputs(tx, &txid, undo)?;
+                if inputs < outputs {
+                    return Err(UtxoError::InsufficientInputs {
+                        txid,
+                        inputs,
+                        outputs,
+                    });
+                }
+                fees = fees
+                    .checked_add(inputs - outputs)
+                    .ok_or_else(|| UtxoError::Overflow(txid.clone()))?;
+            }
+
+            for (index, out) in tx.outputs.iter().enumerate() {
+                let outpoint = OutPoint {
+                    txid: txid.clone(),
+                    index: index as u32,
+                };
+                if self.coins.contains_key(&outpoint) {
+                    return Err(UtxoError::DuplicateOutput(outpoint));
+                }
+                self.coins.insert(outpoint.clone(), out.clone());
+                undo.created.push(outpoint);
+            }
+        }
+
+        if let Some(coinbase) = txs.first().filter(|tx| tx.is_coinbase()) {
+            let allowed = subsidy.saturating_add(fees);
+            let claimed = coinbase.output_value().unwrap_or(u64::MAX);
+            if claimed > allowed {
+                return Err(UtxoError::CoinbaseTooLarge { allowed, claimed });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks and removes every input of `tx`, returning their total value.
+    fn spend_inputs(
+        &mut self,
+        tx: &UtxoTransaction,
+        txid: &str,
+        undo: &mut BlockUndo,
+    ) -> Result<u64, UtxoError> {
+        let sighash = tx.sighash();
+        let mut total = 0u64;
+        for (index, input) in tx.inputs.iter().enumerate() {
+            let coin = self
+                .coins
+                .get(&input.prev)
+                .ok_or_else(|| UtxoError::MissingInput(input.prev.clone()))?;
+            if !signature_valid(&coin.owner, &input.signature, &sighash) {
+                return Err(UtxoError::BadSignature {
+                    txid: txid.to_string(),
+                    input: index,
+                });
+            }
+            total = total
+                .checked_add(coin.value)
+                .ok_or_else(|| UtxoError::Overflow(txid.to_string()))?;
+
+            let coin = self.coins.remove(&input.prev).expect("checked above");
+            undo.spent.push((input.prev.clone(), coin));
+        }
+        Ok(total)
+    }
+
+    /// Takes a block back out using the undo data `apply_block` returned.
+    pub fn revert_block(&mut self, undo: &BlockUndo) {
+        // Restore first so outputs created and spent within the block end up
+        // removed again below.
+        for (outpoint, coin) in undo.spent.iter().rev() {
+            self.coins.insert(outpoint.clone(), coin.clone());
+        }
+        for outpoint in &undo.created {
+            self.coins.remove(outpoint);
+        }
+    }
+}
+
+fn signature_valid(owner: &str, signature: &str, sighash: &str) -> bool {
+    let Ok(key) = hex::decode(owner) else {
+        return false;
+    };
+    let Ok(sig) = hex::decode(signature) else {
+        return false;
+    };
+    let (Ok(key), Ok(sig)) = (<[u8; 32]>::try_from(key), <[u8; 64]>::try_from(sig)) else {
+        return false;
+    };
+    let Ok(key) = VerifyingKey::from_bytes(&key) else {
+        return false;
+    };
+    key.verify(sighash.as_bytes(), &Signature::from_bytes(&sig))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn coinbase(to: &SigningKey, value: u64, tag: u64) -> UtxoTransaction {
+        UtxoTransaction {
+            inputs: vec![],
+            // A zero-value output keeps coinbases paying the same key distinct.
+            outputs: vec![
+                TxOut {
+                    value,
+                    owner: address(&to.verifying_key()),
+                },
+                TxOut {
+                    value: 0,
+                    owner: tag.to_string(),
+                },
+            ],
+        }
+    }
+
+    fn spend(from: &SigningKey, coins: &[OutPoint], pay: &[(&SigningKey, u64)]) -> UtxoTransaction {
+        let mut tx = UtxoTransaction {
+            inputs: coins
+                .iter()
+                .map(|op| TxIn {
+                    prev: op.clone(),
+                    signature: String::new(),
+                })
+                .collect(),
+            outputs: pay
+                .iter()
+                .map(|(to, value)| TxOut {
+                    value: *value,
+                    owner: address(&to.verifying_key()),
+                })
+                .collect(),
+        };
+        for i in 0..coins.len() {
+            tx.sign_input(i, from);
+        }
+        tx
+    }
+
+    fn out(tx: &UtxoTransaction, index: u32) -> OutPoint {
+        OutPoint {
+            txid: tx.txid(),
+            index,
+        }
+    }
+
+    #[test]
+    fn test_spend_with_fee_and_change() {
+        let (alice, bob, miner) = (key(1), key(2), key(3));
+        let mut set = UtxoSet::new();
+        let cb = coinbase(&alice, 50, 0);
+        set.apply_block(std::slice::from_ref(&cb), 50).unwrap();
+
+        let pay = spend(&alice, &[out(&cb, 0)], &[(&bob, 30), (&alice, 15)]);
+        let reward = coinbase(&miner, 55, 1);
+        set.apply_block(&[reward, pay.clone()], 50).unwrap();
+
+        assert_eq!(set.balance(&address(&alice.verifying_key())), 15);
+        assert_eq!(set.balance(&address(&bob.verifying_key())), 30);
+        assert_eq!(set.balance(&address(&miner.verifying_key())), 55);
+        assert!(set.get(&out(&cb, 0)).is_none());
+
+        // Chained spend inside one block.
+        let next = spend(&bob, &[out(&pay, 0)], &[(&alice, 30)]);
+        set.apply_block(&[next], 0).unwrap();
+        assert_eq!(set.balance(&address(&alice.verifying_key())), 45);
+    }
+
+    #[test]
+    fn test_rejects_invalid_spends() {
+        let (alice, bob) = (key(1), key(2));
+        let mut set = UtxoSet::new();
+        let cb = coinbase(&alice, 50, 0);
+        set.apply_block(std::slice::from_ref(&cb), 50).unwrap();
+        let before = set.clone();
+
+        let stolen = spend(&bob, &[out(&cb, 0)], &[(&bob, 50)]);
+        assert!(matches!(
+            set.apply_block(&[stolen], 0),
+            Err(UtxoError::BadSignature { input: 0, .. })
+        ));
+
+        let inflated = spend(&alice, &[out(&cb, 0)], &[(&bob, 51)]);
+        assert!(matches!(
+            set.apply_block(&[inflated], 0),
+            Err(UtxoError::InsufficientInputs {
+                inputs: 50,
+                outputs: 51,
+                ..
+            })
+        ));
+
+        let first = spend(&alice, &[out(&cb, 0)], &[(&bob, 50)]);
+        let double = spend(&alice, &[out(&cb, 0)], &[(&alice, 50)]);
+        assert_eq!(
+            set.apply_block(&[first, double], 0),
+            Err(UtxoError::MissingInput(out(&cb, 0)))
+        );
+
+        let greedy = coinbase(&bob, 11, 1);
+        let fee_payer = spend(&alice, &[out(&cb, 0)], &[(&bob, 40)]);
+        assert_eq!(
+            set.apply_block(&[greedy, fee_payer], 0),
+            Err(UtxoError::CoinbaseTooLarge {
+                allowed: 10,
+                claimed: 11
+            })
+        );
+
+        let late = coinbase(&bob, 1, 2);
+        assert!(matches!(
+            set.apply_block(&[spend(&alice, &[out(&cb, 0)], &[]), late], 0),
+            Err(UtxoError::MisplacedCoinbase(_))
+        ));
+
+        assert_eq!(set, before);
+    }
+
+    #[test]
+    fn test_undo_walks_back_reorged_blocks() {
+        let (alice, bob) = (key(1), key(2));
+        let mut set = UtxoSet::new();
+        let cb = coinbase(&alice, 50, 0);
+        set.apply_block(std::slice::from_ref(&cb), 50).unwrap();
+        let genesis_state = set.clone();
+
+        let pay = spend(&alice, &[out(&cb, 0)], &[(&bob, 50)]);
+        let undo1 = set
+            .apply_block(&[coinbase(&bob, 50, 1), pay.clone()], 50)
+            .unwrap();
+        let after_one = set.clone();
+        let back = spend(&bob, &[out(&pay, 0)], &[(&alice, 20), (&bob, 30)]);
+        let again = spend(&alice, &[out(&back, 0)], &[(&bob, 20)]);
+        let undo2 = set.apply_block(&[back, again], 0).unwrap();
+        assert_eq!(undo2.spent.len(), 2);
+        assert_eq!(undo2.created.len(), 3);
+
+        set.revert_block(&undo2);
+        assert_eq!(set, after_one);
+        set.revert_block(&undo1);
+        assert_eq!(set, genesis_state);
+
+        // The competing branch can now spend the same coin differently.
+        let other = spend(&alice, &[out(&cb, 0)], &[(&alice, 50)]);
+        set.apply_block(&[other], 0).unwrap();
+        assert_eq!(set.balance(&address(&bob.verifying_key())), 0);
+    }
+}
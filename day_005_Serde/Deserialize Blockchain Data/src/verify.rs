@@ -0,0 +1,422 @@
+use std::fmt;
+
+use crate::{meets_difficulty, Block, BlockHeader};
+use crate::merkle::merkle_root;
+use crate::params::{ConsensusParams, LimitError};
+use crate::spec::ConsensusConfig;
+use crate::timestamp::{Clock, SystemClock, TimestampRules};
+
+/// `prev_hash` every genesis block must carry.
+pub const GENESIS_PREV_HASH: &str = "0";
+
+/// Summary of a chain that passed every check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainReport {
+    pub blocks_verified: usize,
+    /// Recomputed hash of the last block, `None` for an empty chain.
+    pub tip_hash: Option<String>,
+}
+
+/// Why a block broke the chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BreakReason {
+    /// The first block is not id 0.
+    GenesisId(u32),
+    /// The first block does not point at `GENESIS_PREV_HASH`.
+    GenesisPrevHash(String),
+    /// The block carries a hash that does not match its contents.
+    HashMismatch { stored: String, computed: String },
+    /// `merkle_root` does not commit to the block's transactions.
+    MerkleRootMismatch { stored: String, computed: String },
+    /// `prev_hash` does not match the recomputed hash of the previous block.
+    PrevHashMismatch { expected: String, found: String },
+    /// Block ids must increase by exactly one.
+    HeightGap { expected: u32, found: u32 },
+    /// The parent is already at the highest id a block can have.
+    HeightOverflow { parent: u32 },
+    /// The block is not later than the median time of its recent ancestors.
+    TimestampNotAfterMedian { median: u64, found: u64 },
+    /// The block claims a time too far ahead of the local clock.
+    TimestampTooFarAhead { latest: u64, found: u64 },
+    /// The hash does not have the leading zeros its difficulty asks for.
+    InsufficientWork { difficulty: u32 },
+    /// The difficulty is below the chain's minimum.
+    DifficultyBelowMinimum { min: u32, found: u32 },
+    /// The difficulty moved by more than one step from the parent's.
+    DifficultyJump { parent: u32, found: u32 },
+    /// The block breaks a size limit from `ConsensusParams`.
+    LimitExceeded(LimitError),
+}
+
+/// The first block that failed verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainBreak {
+    /// Position in the slice; also the number of blocks verified before it.
+    pub index: usize,
+    pub block_id: u32,
+    pub reason: BreakReason,
+}
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakReason::GenesisId(id) => write!(f, "genesis block has id {} instead of 0", id),
+            BreakReason::GenesisPrevHash(found) => {
+                write!(f, "genesis prev_hash is {:?} instead of {:?}", found, GENESIS_PREV_HASH)
+            }
+            BreakReason::HashMismatch { stored, computed } => {
+                write!(f, "stored hash {} does not match contents ({})", stored, computed)
+            }
+            BreakReason::MerkleRootMismatch { stored, computed } => {
+                write!(f, "merkle root {} does not match transactions ({})", stored, computed)
+            }
+            BreakReason::PrevHashMismatch { expected, found } => {
+                write!(f, "prev_hash {} does not match parent hash {}", found, expected)
+            }
+            BreakReason::HeightGap { expected, found } => {
+                write!(f, "expected block id {}, found {}", expected, found)
+            }
+            BreakReason::HeightOverflow { parent } => {
+                write!(f, "parent block id {} has no successor", parent)
+            }
+            BreakReason::TimestampNotAfterMedian { median, found } => {
+                write!(f, "timestamp {} is not after median time past {}", found, median)
+            }
+            BreakReason::TimestampTooFarAhead { latest, found } => {
+                write!(f, "timestamp {} is ahead of the latest allowed {}", found, latest)
+            }
+            BreakReason::InsufficientWork { difficulty } => {
+                write!(f, "hash does not meet difficulty {}", difficulty)
+            }
+            BreakReason::DifficultyBelowMinimum { min, found } => {
+                write!(f, "difficulty {} is below the minimum {}", found, min)
+            }
+            BreakReason::DifficultyJump { parent, found } => {
+                write!(f, "difficulty jumps from {} to {}", parent, found)
+            }
+            BreakReason::LimitExceeded(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl fmt::Display for ChainBreak {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "block {} (index {}): {}", self.block_id, self.index, self.reason)
+    }
+}
+
+impl std::error::Error for ChainBreak {}
+
+/// Checks a single block against its parent, or against the genesis rules
+/// when `parent` is `None`. `parent_hash` is the recomputed hash of `parent`.
+///
+/// Returns the recomputed hash of `block`.
+pub fn verify_block(block: &Block, parent: Option<(&Block, &str)>) -> Result<String, BreakReason> {
+    let parent_header = parent.map(|(p, hash)| (p.header(), hash));
+    verify_block_on(block, parent_header.as_ref().map(|(h, hash)| (h, *hash)))
+}
+
+/// `verify_block` when only the parent's header is at hand, e.g. right after
+/// restoring from a snapshot.
+pub fn verify_block_on(
+    block: &Block,
+    parent: Option<(&BlockHeader, &str)>,
+) -> Result<String, BreakReason> {
+    verify_block_within(block, parent, &ConsensusParams::default(), &ConsensusConfig::default())
+}
+
+/// `verify_block_on` with the size limits and difficulty schedule of a
+/// particular chain.
+pub fn verify_block_within(
+    block: &Block,
+    parent: Option<(&BlockHeader, &str)>,
+    params: &ConsensusParams,
+    consensus: &ConsensusConfig,
+) -> Result<String, BreakReason> {
+    params.check_block(block).map_err(BreakReason::LimitExceeded)?;
+    let computed = block.compute_hash();
+    if !block.hash.is_empty() && block.hash != computed {
+        return Err(BreakReason::HashMismatch { stored: block.hash.clone(), computed });
+    }
+    let root = merkle_root(&block.transactions);
+    if block.merkle_root != root {
+        return Err(BreakReason::MerkleRootMismatch {
+            stored: block.merkle_root.clone(),
+            computed: root,
+        });
+    }
+
+    let header = block.header();
+    verify_header(&header, &computed, parent)?;
+    consensus.check_difficulty(&header, parent.map(|(p, _)| p))?;
+    Ok(computed)
+}
+
+/// The header-only part of `verify_block`: proof of work and linkage to the
+/// parent header (or the genesis rules). `hash` is the header's hash.
+/// Timestamps are left to `TimestampRules`, which needs more than the parent.
+pub fn verify_header(
+    header: &BlockHeader,
+    hash: &str,
+    parent: Option<(&BlockHeader, &str)>,
+) -> Result<(), BreakReason> {
+    if !meets_difficulty(hash, header.difficulty) {
+        return Err(BreakReason::InsufficientWork { difficulty: header.difficulty });
+    }
+
+    match parent {
+        None => {
+            if header.id != 0 {
+                return Err(BreakReason::GenesisId(header.id));
+            }
+            if header.prev_hash != GENESIS_PREV_HASH {
+                return Err(BreakReason::GenesisPrevHash(header.prev_hash.clone()));
+            }
+        }
+        Some((parent, parent_hash)) => {
+            let expected = parent
+                .id
+                .checked_add(1)
+                .ok_or(BreakReason::HeightOverflow { parent: parent.id })?;
+            if header.id != expected {
+                return Err(BreakReason::HeightGap { expected, found: header.id });
+            }
+            if header.prev_hash != parent_hash {
+                return Err(BreakReason::PrevHashMismatch {
+                    expected: parent_hash.to_string(),
+                    found: header.prev_hash.clone(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Difficulty must stay at or above `min_difficulty` and, past genesis, move
+/// by at most one step from the parent's.
+pub fn check_difficulty(
+    header: &BlockHeader,
+    parent: Option<&BlockHeader>,
+    min_difficulty: u32,
+) -> Result<(), BreakReason> {
+    if header.difficulty < min_difficulty {
+        return Err(BreakReason::DifficultyBelowMinimum { min: min_difficulty, found: header.difficulty });
+    }
+    if let Some(parent) = parent
+        && header.difficulty.abs_diff(parent.difficulty) > 1
+    {
+        return Err(BreakReason::DifficultyJump { parent: parent.difficulty, found: header.difficulty });
+    }
+    Ok(())
+}
+
+/// Verify a chain starting at genesis, recomputing every hash from block contents.
+/// Timestamps are held to the default `TimestampRules` against the system clock.
+///
+/// Stops at the first broken block and reports which one and why.
+pub fn verify_chain_report(chain: &[Block]) -> Result<ChainReport, ChainBreak> {
+    let (params, consensus) = (ConsensusParams::default(), ConsensusConfig::default());
+    verify_chain_report_within(chain, &params, &consensus, &TimestampRules::default(), &SystemClock)
+}
+
+/// `verify_chain_report` with the size limits, difficulty schedule and
+/// timestamp rules of a particular chain, and `clock` for the future drift
+/// limit.
+pub fn verify_chain_report_within(
+    chain: &[Block],
+    params: &ConsensusParams,
+    consensus: &ConsensusConfig,
+    rules: &TimestampRules,
+    clock: &dyn Clock,
+) -> Result<ChainReport, ChainBreak> {
+    let mut parent: Option<(BlockHeader, String)> = None;
+    let mut timestamps = Vec::with_capacity(chain.len());
+
+    for (index, block) in chain.iter().enumerate() {
+        let link = parent.as_ref().map(|(h, hash)| (h, hash.as_str()));
+        let hash = verify_block_within(block, link, params, consensus)
+            .and_then(|hash| rules.check(block.timestamp, &timestamps, clock).map(|()| hash))
+            .map_err(|reason| ChainBreak { index, block_id: block.id, reason })?;
+        timestamps.push(block.timestamp);
+        parent = Some((block.header(), hash));
+    }
+
+    Ok(ChainReport {
+        blocks_verified: chain.len(),
+        tip_hash: parent.map(|(_, hash)| hash),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transaction;
+    use crate::timestamp::ManualClock;
+
+    fn tx(id: u32, amount: u32, sender: &str) -> Transaction {
+        Transaction { id, amount, sender: sender.to_string(), receiver: String::new(), fee: 0, nonce: 0 }
+    }
+
+    fn sample_chain() -> Vec<Block> {
+        let genesis = Block::new(0, 1631234566, vec![tx(1, 50, "Genesis")], "0".to_string());
+        let block1 = Block::new(1, 1631234567, vec![tx(2, 100, "Alice")], genesis.hash.clone());
+        let block2 = Block::new(2, 1631234568, vec![tx(3, 200, "Bob")], block1.hash.clone());
+        vec![genesis, block1, block2]
+    }
+
+    #[test]
+    fn test_valid_chain_after_roundtrip() {
+        let chain = sample_chain();
+        let json = serde_json::to_string(&chain).unwrap();
+        let loaded: Vec<Block> = serde_json::from_str(&json).unwrap();
+        assert!(loaded.iter().all(|b| b.hash.is_empty()));
+
+        let report = verify_chain_report(&loaded).unwrap();
+        assert_eq!(report.blocks_verified, 3);
+        assert_eq!(report.tip_hash, Some(chain[2].hash.clone()));
+    }
+
+    #[test]
+    fn test_detects_tampered_transaction() {
+        let chain = sample_chain();
+        let json = serde_json::to_string(&chain).unwrap();
+        let mut loaded: Vec<Block> = serde_json::from_str(&json).unwrap();
+        loaded[1].transactions[0].amount = 1_000_000;
+
+        let err = verify_chain_report(&loaded).unwrap_err();
+        assert_eq!(err.index, 1);
+        assert!(matches!(err.reason, BreakReason::MerkleRootMismatch { .. }));
+    }
+
+    #[test]
+    fn test_detects_stale_stored_hash() {
+        let mut chain = sample_chain();
+        chain[2].timestamp += 1;
+        let err = verify_chain_report(&chain).unwrap_err();
+        assert_eq!(err.block_id, 2);
+        assert!(matches!(err.reason, BreakReason::HashMismatch { .. }));
+    }
+
+    #[test]
+    fn test_genesis_rules() {
+        let bad = Block::new(0, 1, vec![], "abc".to_string());
+        let err = verify_chain_report(&[bad]).unwrap_err();
+        assert_eq!(err.reason, BreakReason::GenesisPrevHash("abc".to_string()));
+
+        let bad = Block::new(3, 1, vec![], "0".to_string());
+        let err = verify_chain_report(&[bad]).unwrap_err();
+        assert_eq!(err.reason, BreakReason::GenesisId(3));
+    }
+
+    #[test]
+    fn test_height_and_timestamp() {
+        let chain = sample_chain();
+        let skipped = Block::new(5, 1631234569, vec![], chain[2].hash.clone());
+        let err = verify_chain_report(&[chain[0].clone(), chain[1].clone(), chain[2].clone(), skipped])
+            .unwrap_err();
+        assert_eq!(err.reason, BreakReason::HeightGap { expected: 3, found: 5 });
+
+        // The id does not wrap back to 0 after the highest one.
+        let mut last = chain[2].header();
+        last.id = u32::MAX;
+        let last_hash = last.compute_hash();
+        let wrapped = Block::new(0, 1631234569, vec![], last_hash.clone());
+        let err = verify_header(&wrapped.header(), &wrapped.hash, Some((&last, &last_hash)));
+        assert_eq!(err, Err(BreakReason::HeightOverflow { parent: u32::MAX }));
+
+        let old = Block::new(3, 1, vec![], chain[2].hash.clone());
+        let err = verify_chain_report(&[chain[0].clone(), chain[1].clone(), chain[2].clone(), old])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "block 3 (index 3): timestamp 1 is not after median time past 1631234567");
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let chain = sample_chain();
+        let params = ConsensusParams { max_block_transactions: 1, ..ConsensusParams::default() };
+        let (consensus, rules) = (ConsensusConfig::default(), TimestampRules::default());
+        assert!(verify_chain_report_within(&chain, &params, &consensus, &rules, &SystemClock).is_ok());
+
+        let two = vec![tx(4, 1, "Carol"), tx(5, 1, "Dave")];
+        let mut longer = chain.clone();
+        longer.push(Block::new(3, 1631234569, two, chain[2].hash.clone()));
+        assert!(verify_chain_report(&longer).is_ok());
+        let err = verify_chain_report_within(&longer, &params, &consensus, &rules, &SystemClock).unwrap_err();
+        assert_eq!(err.index, 3);
+        assert_eq!(
+            err.reason,
+            BreakReason::LimitExceeded(LimitError::TooManyTransactions { count: 2, max: 1 })
+        );
+    }
+
+    #[test]
+    fn test_proof_of_work() {
+        let chain = sample_chain();
+        let mined = Block::new(3, 1631234569, vec![], chain[2].hash.clone()).mine(1).unwrap();
+        assert!(mined.hash.starts_with('0'));
+        let mut full = chain.clone();
+        full.push(mined.clone());
+        assert!(verify_chain_report(&full).is_ok());
+
+        let mut lazy = mined;
+        lazy.difficulty = 2;
+        lazy.hash.clear();
+        full[3] = lazy;
+        let err = verify_chain_report(&full).unwrap_err();
+        assert_eq!(err.reason, BreakReason::InsufficientWork { difficulty: 2 });
+    }
+
+    #[test]
+    fn test_difficulty_schedule() {
+        let chain = sample_chain();
+        let params = ConsensusParams::default();
+        let rules = TimestampRules::default();
+
+        // Real work, but more than the retarget rule allows after difficulty 0.
+        let mut jumped = chain.clone();
+        jumped.push(Block::new(3, 1631234569, vec![], chain[2].hash.clone()).mine(2).unwrap());
+        let err = verify_chain_report(&jumped).unwrap_err();
+        assert_eq!(err.index, 3);
+        assert_eq!(err.reason, BreakReason::DifficultyJump { parent: 0, found: 2 });
+
+        // The sample chain is unmined, so a chain with a minimum rejects it at genesis.
+        let strict = ConsensusConfig::Pow { difficulty: 1, min_difficulty: 1, target_block_time: 10 };
+        let err = verify_chain_report_within(&chain, &params, &strict, &rules, &SystemClock).unwrap_err();
+        assert_eq!(err.index, 0);
+        assert_eq!(err.reason, BreakReason::DifficultyBelowMinimum { min: 1, found: 0 });
+
+        // Proof of stake has no difficulty schedule.
+        let pos = ConsensusConfig::Pos { validators: Default::default(), min_stake: 0, slot_duration: 10 };
+        assert!(verify_chain_report_within(&jumped, &params, &pos, &rules, &SystemClock).is_ok());
+    }
+
+    #[test]
+    fn test_timestamp_rules() {
+        let chain = sample_chain();
+        let params = ConsensusParams::default();
+        let consensus = ConsensusConfig::default();
+        let rules = TimestampRules { median_window: 3, max_future_drift: 60 };
+
+        // Not before its parent, but not after the median of its ancestors either.
+        let mut stalled = chain[..2].to_vec();
+        stalled.push(Block::new(2, 1631234567, vec![], chain[1].hash.clone()));
+        stalled.push(Block::new(3, 1631234567, vec![], stalled[2].hash.clone()));
+        let err = verify_chain_report_within(&stalled, &params, &consensus, &rules, &SystemClock).unwrap_err();
+        assert_eq!(err.index, 3);
+        assert_eq!(
+            err.reason,
+            BreakReason::TimestampNotAfterMedian { median: 1631234567, found: 1631234567 }
+        );
+        assert!(verify_chain_report(&stalled).is_err());
+
+        let clock = ManualClock::new(1631234506);
+        let err = verify_chain_report_within(&chain, &params, &consensus, &rules, &clock).unwrap_err();
+        assert_eq!(err.index, 1);
+        assert_eq!(
+            err.reason,
+            BreakReason::TimestampTooFarAhead { latest: 1631234566, found: 1631234567 }
+        );
+        clock.set(1631234508);
+        assert!(verify_chain_report_within(&chain, &params, &consensus, &rules, &clock).is_ok());
+    }
+}
@@ -0,0 +1,451 @@
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+
+use crate::{Block, Transaction};
+
+/// Upgrades a document from one version to the next.
+pub type Migration = fn(Value) -> Result<Value, String>;
+
+/// A type that is written to disk with a format version.
+///
+/// Documents are stored in an envelope:
+///
+/// ```json
+/// { "kind": "block", "version": 2, "data": { "id": 1, ... } }
+/// ```
+///
+/// Anything without an envelope was written before versioning existed and
+/// is read as version 1. Changing the serialized layout of a type means
+/// appending a migration and adding a golden file for the new version, so
+/// files in every older format stay readable.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// Stored in the envelope so that, say, a keypair file is not read as a
+    /// block.
+    const KIND: &'static str;
+    /// `MIGRATIONS[i]` upgrades version `i + 1` to `i + 2`.
+    const MIGRATIONS: &'static [Migration];
+
+    fn current_version() -> u32 {
+        Self::MIGRATIONS.len() as u32 + 1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionError {
+    Parse(String),
+    WrongKind {
+        expected: String,
+        found: String,
+    },
+    /// Written by newer software than this.
+    Unsupported {
+        version: u32,
+        current: u32,
+    },
+    Migration {
+        from: u32,
+        reason: String,
+    },
+}
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VersionError::Parse(msg) => write!(f, "cannot parse document: {}", msg),
+            VersionError::WrongKind { expected, found } => {
+                write!(f, "expected a {} document, found a {}", expected, found)
+            }
+            VersionError::Unsupported { version, current } => write!(
+                f,
+                "format version {} is not supported (current is {})",
+                version, current
+            ),
+            VersionError::Migration { from, reason } => {
+                write!(f, "cannot migrate from version {}: {}", from, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VersionError {}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    kind: String,
+    version: u32,
+    data: T,
+}
+
+fn parse_error(e: serde_json::Error) -> VersionError {
+    VersionError::Parse(e.to_string())
+}
+
+fn envelope<T: Versioned>(value: &T) -> Envelope<&T> {
+    Envelope {
+        kind: T::KIND.to_string(),
+        version: T::current_version(),
+        data: value,
+    }
+}
+
+/// Wraps `value` in an envelope at the current version.
+pub fn to_value<T: Versioned>(value: &T) -> Result<Value, VersionError> {
+    serde_json::to_value(envelope(value)).map_err(parse_error)
+}
+
+pub fn encode<T: Versioned>(value: &T) -> Result<Vec<u8>, VersionError> {
+    serde_json::to_vec(&envelope(value)).map_err(parse_error)
+}
+
+pub fn encode_pretty<T: Versioned>(value: &T) -> Result<String, VersionError> {
+    serde_json::to_string_pretty(&envelope(value)).map_err(parse_error)
+}
+
+fn is_envelope(document: &Value) -> bool {
+    document.as_object().is_some_and(|object| {
+        object.len() == 3
+            && ["kind", "version", "data"]
+                .iter()
+                .all(|k| object.contains_key(*k))
+    })
+}
+
+/// Splits a document into its version and payload. A document without an
+/// envelope is version 1.
+fn unwrap_envelope<T: Versioned>(document: Value) -> Result<(u32, Value), VersionError> {
+    if !is_envelope(&document) {
+        return Ok((1, document));
+    }
+    let envelope: Envelope<Value> = serde_json::from_value(document).map_err(parse_error)?;
+    if envelope.kind != T::KIND {
+        return Err(VersionError::WrongKind {
+            expected: T::KIND.to_string(),
+            found: envelope.kind,
+        });
+    }
+    Ok((envelope.version, envelope.data))
+}
+
+/// Like `decode`, for formats that have carried an envelope from the start:
+/// a document without one is an error instead of version 1.
+pub fn decode_enveloped<T: Versioned>(bytes: &[u8]) -> Result<T, VersionError> {
+    let document: Value = serde_json::from_slice(bytes).map_err(parse_error)?;
+    if !is_envelope(&document) {
+        return Err(VersionError::Parse(format!(
+            "{} document has no version envelope",
+            T::KIND
+        )));
+    }
+    from_value(document)
+}
+
+/// Runs the migrations that take `data` from `version` to the current one.
+pub fn migrate<T: Versioned>(mut data: Value, version: u32) -> Result<Value, VersionError> {
+    let current = T::current_version();
+    if version == 0 || version > current {
+        return Err(VersionError::Unsupported { version, current });
+    }
+    for (from, migration) in (version..).zip(&T::MIGRATIONS[version as usize - 1..]) {
+        data = migration(data).map_err(|reason| VersionError::Migration { from, reason })?;
+    }
+    Ok(data)
+}
+
+/// Reads a document of any supported version, upgrading it as needed.
+pub fn from_value<T: Versioned>(document: Value) -> Result<T, VersionError> {
+    let (version, data) = unwrap_envelope::<T>(document)?;
+    serde_json::from_value(migrate::<T>(data, version)?).map_err(parse_error)
+}
+
+pub fn decode<T: Versioned>(bytes: &[u8]) -> Result<T, VersionError> {
+    from_value(serde_json::from_slice(bytes).map_err(parse_error)?)
+}
+
+// ----------------------------
+// Formats
+// ----------------------------
+
+fn as_object(value: &mut Value) -> Result<&mut Map<String, Value>, String> {
+    value
+        .as_object_mut()
+        .ok_or_else(|| "expected an object".to_string())
+}
+
+/// Fills in a field that older writers left out. Version 1 covers every
+/// layout written before documents were versioned, so the field may or may
+/// not be there already.
+fn default_field(object: &mut Map<String, Value>, key: &str, value: Value) {
+    object.entry(key).or_insert(value);
+}
+
+/// Transaction 1 -> 2: the account ledger added `receiver`.
+fn transaction_v2(mut tx: Value) -> Result<Value, String> {
+    default_field(as_object(&mut tx)?, "receiver", json!(""));
+    Ok(tx)
+}
+
+/// Block 1 -> 2: the Merkle root, state root and proof of work fields, and
+/// transactions at their version 2.
+///
+/// A block from before the Merkle root keeps an empty one, and chains of such
+/// blocks are rejected by `verify` rather than re-rooted: their `prev_hash`
+/// links commit to the old hash of the whole block, so re-rooting and
+/// re-hashing would only move the break to the next link.
+fn block_v2(mut block: Value) -> Result<Value, String> {
+    let object = as_object(&mut block)?;
+    if let Some(txs) = object.get_mut("transactions").and_then(Value::as_array_mut) {
+        for tx in txs.iter_mut() {
+            *tx = transaction_v2(tx.take())?;
+        }
+    }
+    default_field(object, "merkle_root", json!(""));
+    default_field(object, "state_root", json!(""));
+    default_field(object, "difficulty", json!(0));
+    default_field(object, "nonce", json!(0));
+    Ok(block)
+}
+
+/// Transaction 2 -> 3: `fee`, left out when zero.
+fn transaction_v3(mut tx: Value) -> Result<Value, String> {
+    default_field(as_object(&mut tx)?, "fee", json!(0));
+    Ok(tx)
+}
+
+/// Block 2 -> 3: transactions at their version 3.
+fn block_v3(mut block: Value) -> Result<Value, String> {
+    let object = as_object(&mut block)?;
+    if let Some(txs) = object.get_mut("transactions").and_then(Value::as_array_mut) {
+        for tx in txs.iter_mut() {
+            *tx = transaction_v3(tx.take())?;
+        }
+    }
+    Ok(block)
+}
+
+/// Transaction 3 -> 4: `nonce`, left out when zero.
+fn transaction_v4(mut tx: Value) -> Result<Value, String> {
+    default_field(as_object(&mut tx)?, "nonce", json!(0));
+    Ok(tx)
+}
+
+/// Block 3 -> 4: transactions at their version 4.
+fn block_v4(mut block: Value) -> Result<Value, String> {
+    let object = as_object(&mut block)?;
+    if let Some(txs) = object.get_mut("transactions").and_then(Value::as_array_mut) {
+        for tx in txs.iter_mut() {
+            *tx = transaction_v4(tx.take())?;
+        }
+    }
+    Ok(block)
+}
+
+impl Versioned for Transaction {
+    const KIND: &'static str = "transaction";
+    const MIGRATIONS: &'static [Migration] = &[transaction_v2, transaction_v3, transaction_v4];
+}
+
+impl Versioned for Block {
+    const KIND: &'static str = "block";
+    const MIGRATIONS: &'static [Migration] = &[block_v2, block_v3, block_v4];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    use sha2::{Digest, Sha256};
+
+    use crate::merkle::merkle_root;
+    use crate::verify::{BreakReason, verify_chain_report};
+
+    fn golden(name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("golden")
+            .join(name);
+        std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+    }
+
+    fn tx(id: u32, amount: u32, sender: &str, receiver: &str) -> Transaction {
+        Transaction {
+            id,
+            amount,
+            sender: sender.to_string(),
+            receiver: receiver.to_string(),
+            fee: 0,
+            nonce: 0,
+        }
+    }
+
+    /// Block 1 of the demo chain in the current format.
+    fn current_block() -> Block {
+        Block::new(
+            1,
+            1631234567,
+            vec![tx(2, 100, "Alice", "Bob"), tx(3, 200, "Bob", "Carol")],
+            "0".to_string(),
+        )
+        .mine(1)
+        .unwrap()
+    }
+
+    /// The same block with a fee on its second transaction.
+    fn fee_block() -> Block {
+        let mut txs = current_block().transactions;
+        txs[1].fee = 7;
+        Block::new(1, 1631234567, txs, "0".to_string())
+            .mine(1)
+            .unwrap()
+    }
+
+    /// The fee block with its second transaction spending nonce 4.
+    fn nonce_block() -> Block {
+        let mut txs = fee_block().transactions;
+        txs[1].nonce = 4;
+        Block::new(1, 1631234567, txs, "0".to_string())
+            .mine(1)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_golden_files_decode() {
+        // The first block format: no receivers and nothing but the hash link.
+        let block: Block = decode(&golden("block_v1.json")).unwrap();
+        assert_eq!(block.transactions[0].receiver, "");
+        assert_eq!(block.transactions[1].amount, 200);
+        assert_eq!((block.merkle_root.as_str(), block.difficulty), ("", 0));
+
+        // Written without an envelope, but already with every later field.
+        let block: Block = decode(&golden("block_v1_unversioned.json")).unwrap();
+        assert_eq!(block.compute_hash(), current_block().hash);
+
+        let block: Block = decode(&golden("block_v2.json")).unwrap();
+        assert_eq!(block.compute_hash(), current_block().hash);
+        assert_eq!(block.transactions[1].receiver, "Carol");
+
+        let old: Transaction = decode(&golden("transaction_v1.json")).unwrap();
+        assert_eq!((old.id, old.receiver.as_str()), (1, ""));
+        let new: Transaction = decode(&golden("transaction_v2.json")).unwrap();
+        assert_eq!((new.receiver.as_str(), new.fee), ("Bob", 0));
+
+        let block: Block = decode(&golden("block_v3.json")).unwrap();
+        assert_eq!(block.compute_hash(), fee_block().hash);
+        assert_eq!(block.transactions[1].fee, 7);
+        let paid: Transaction = decode(&golden("transaction_v3.json")).unwrap();
+        assert_eq!((paid.fee, paid.nonce), (7, 0));
+
+        let block: Block = decode(&golden("block_v4.json")).unwrap();
+        assert_eq!(block.compute_hash(), nonce_block().hash);
+        assert_eq!(block.transactions[0].nonce, 0);
+        let spent: Transaction = decode(&golden("transaction_v4.json")).unwrap();
+        assert_eq!(spent.nonce, 4);
+    }
+
+    /// The newest golden file must match what is written today, so changing
+    /// a layout without adding a version fails here.
+    #[test]
+    fn test_current_format_matches_latest_golden_file() {
+        let block = encode_pretty(&nonce_block()).unwrap();
+        assert_eq!(block.as_bytes(), golden("block_v4.json").trim_ascii_end());
+        let tx = encode_pretty(&nonce_block().transactions[1]).unwrap();
+        assert_eq!(
+            tx.as_bytes(),
+            golden("transaction_v4.json").trim_ascii_end()
+        );
+    }
+
+    #[test]
+    fn test_every_version_has_a_golden_file() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("golden");
+        for (kind, current) in [
+            (Block::KIND, Block::current_version()),
+            (Transaction::KIND, Transaction::current_version()),
+        ] {
+            for version in 1..=current {
+                assert!(
+                    dir.join(format!("{}_v{}.json", kind, version)).exists(),
+                    "no golden file for {} version {}",
+                    kind,
+                    version
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_unknown_versions_and_kinds() {
+        let mut document = to_value(&current_block()).unwrap();
+        document["version"] = json!(5);
+        assert_eq!(
+            from_value::<Block>(document.clone()).unwrap_err(),
+            VersionError::Unsupported {
+                version: 5,
+                current: 4
+            }
+        );
+        document["version"] = json!(0);
+        assert!(matches!(
+            from_value::<Block>(document),
+            Err(VersionError::Unsupported { version: 0, .. })
+        ));
+
+        let tx = encode(&tx(1, 1, "a", "b")).unwrap();
+        assert!(matches!(
+            decode::<Block>(&tx),
+            Err(VersionError::WrongKind { .. })
+        ));
+
+        // A block with no transactions is still an error, not a migration.
+        let missing = br#"{"id":1,"timestamp":1631234567}"#;
+        assert!(matches!(
+            decode::<Block>(missing),
+            Err(VersionError::Parse(_))
+        ));
+        let bare = serde_json::to_vec(&current_block()).unwrap();
+        assert!(decode::<Block>(&bare).is_ok());
+        assert!(matches!(
+            decode_enveloped::<Block>(&bare),
+            Err(VersionError::Parse(_))
+        ));
+        let enveloped = encode(&current_block()).unwrap();
+        assert!(decode_enveloped::<Block>(&enveloped).is_ok());
+
+        let broken = br#"{"kind":"block","version":1,"data":[1]}"#;
+        assert!(matches!(
+            decode::<Block>(broken),
+            Err(VersionError::Migration { from: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_pre_merkle_chains_are_rejected() {
+        // Written before blocks committed to a Merkle root: block 1 links to
+        // the hash of block 0's whole document.
+        let genesis = r#"{"id":0,"timestamp":1631234566,"transactions":[{"id":0,"amount":50,"sender":"Genesis"}],"prev_hash":"0"}"#;
+        let next = format!(
+            r#"{{"id":1,"timestamp":1631234567,"transactions":[],"prev_hash":"{}"}}"#,
+            hex::encode(Sha256::digest(genesis))
+        );
+        let chain: Vec<Block> = [genesis.to_string(), next]
+            .iter()
+            .map(|doc| decode(doc.as_bytes()).unwrap())
+            .collect();
+        assert_eq!(chain[0].merkle_root, "");
+        let err = verify_chain_report(&chain).unwrap_err();
+        assert_eq!(err.index, 0);
+        assert!(matches!(err.reason, BreakReason::MerkleRootMismatch { .. }));
+
+        // Re-rooting does not rescue it: the link still commits to the old
+        // hash.
+        let mut rerooted = chain.clone();
+        for block in &mut rerooted {
+            block.merkle_root = merkle_root(&block.transactions);
+        }
+        let err = verify_chain_report(&rerooted).unwrap_err();
+        assert_eq!(err.index, 1);
+        assert!(matches!(err.reason, BreakReason::PrevHashMismatch { .. }));
+    }
+}
@@ -0,0 +1,446 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// First bytes of every log file.
+pub const MAGIC: &[u8; 8] = b"CHAINWAL";
+
+/// Length prefix plus checksum.
+pub const HEADER_LEN: u64 = 8;
+
+fn checksum(body: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(body);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+/// Encodes one record: `[u32 len][4-byte checksum][body]`, where the
+/// checksum is the start of the body's SHA-256.
+pub fn encode_record(body: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(body.len() + HEADER_LEN as usize);
+    record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    record.extend_from_slice(&checksum(body));
+    record.extend_from_slice(body);
+    record
+}
+
+/// What `read_record` found at the reader's position.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Record {
+    Intact(Vec<u8>),
+    /// Clean end of input.
+    End,
+    /// Input ends inside the record, as a crash in the middle of an append
+    /// leaves it.
+    Torn,
+    /// The record is all there but fails its checksum.
+    Corrupt {
+        len: u64,
+    },
+}
+
+/// Reads the next record.
+pub fn read_record(reader: &mut impl Read) -> io::Result<Record> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..])? {
+            0 if filled == 0 => return Ok(Record::End),
+            0 => return Ok(Record::Torn),
+            n => filled += n,
+        }
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+    let mut body = Vec::new();
+    reader.take(len).read_to_end(&mut body)?;
+    if body.len() as u64 != len {
+        return Ok(Record::Torn);
+    }
+    if checksum(&body) != header[4..] {
+        return Ok(Record::Corrupt { len });
+    }
+    Ok(Record::Intact(body))
+}
+
+/// fsyncs a directory so a rename or a newly created file in it survives a
+/// crash. A no-op where directories cannot be opened (Windows).
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    match File::open(dir) {
+        Ok(dir) => dir.sync_all(),
+        Err(_) if cfg!(windows) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Replaces `path` with `data` so that after a crash the file holds either
+/// the old contents or the new, never a mix: write a temporary file, fsync
+/// it, rename it over `path`, then fsync the directory.
+pub fn write_atomic(path: impl AsRef<Path>, data: &[u8]) -> io::Result<()> {
+    write_atomic_with(path.as_ref(), data, &mut Direct)
+}
+
+/// The steps of `write_atomic` that tests stop as if the process had died
+/// there. The defaults are what production does.
+pub(crate) trait AtomicSteps {
+    fn write(&mut self, file: &mut File, data: &[u8]) -> io::Result<()> {
+        file.write_all(data)
+    }
+
+    /// Called before each step after the write.
+    fn before(&mut self, _step: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Direct;
+
+impl AtomicSteps for Direct {}
+
+pub(crate) fn write_atomic_with(
+    path: &Path,
+    data: &[u8],
+    steps: &mut dyn AtomicSteps,
+) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = File::create(&tmp)?;
+    steps.write(&mut file, data)?;
+    steps.before("sync")?;
+    file.sync_all()?;
+    steps.before("rename")?;
+    fs::rename(&tmp, path)?;
+    steps.before("sync_dir")?;
+    sync_dir(parent_dir(path))
+}
+
+/// Contents of a log file holding exactly `records`.
+pub fn encode_log<'a>(records: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    for body in records {
+        data.extend_from_slice(&encode_record(body));
+    }
+    data
+}
+
+/// Append-only log of checksummed records.
+///
+/// Every append is fsynced before it returns, so a record that was
+/// acknowledged is on disk. On open, a final record that is incomplete or
+/// fails its checksum is cut off: a torn write at the tail is discarded
+/// instead of breaking every later start. A bad record with more data after
+/// it cannot be a torn append, so it fails the open instead of silently
+/// losing the acknowledged records behind it.
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+/// What `Wal::open` found on disk.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Recovered {
+    pub records: Vec<Vec<u8>>,
+    /// Bytes of torn tail that were cut off.
+    pub truncated: u64,
+}
+
+impl Wal {
+    /// Opens or creates the log at `path` and returns its intact records.
+    ///
+    /// Fails with `InvalidData` if the file exists but is not a log, or if
+    /// a record before the last one is corrupt.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<(Wal, Recovered)> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let file_len = file.metadata()?.len();
+
+        // A file shorter than the magic was cut off while being created.
+        if file_len < MAGIC.len() as u64 {
+            file.set_len(0)?;
+            file.write_all(MAGIC)?;
+            file.sync_all()?;
+            sync_dir(parent_dir(&path))?;
+            let recovered = Recovered {
+                records: Vec::new(),
+                truncated: file_len,
+            };
+            let len = MAGIC.len() as u64;
+            return Ok((Wal { path, file, len }, recovered));
+        }
+
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a write-ahead log", path.display()),
+            ));
+        }
+
+        let mut records = Vec::new();
+        let mut len = MAGIC.len() as u64;
+        let mut reader = io::BufReader::new(&mut file);
+        loop {
+            match read_record(&mut reader)? {
+                Record::Intact(body) => {
+                    len += HEADER_LEN + body.len() as u64;
+                    records.push(body);
+                }
+                Record::End | Record::Torn => break,
+                Record::Corrupt { len: body_len } if len + HEADER_LEN + body_len == file_len => {
+                    break;
+                }
+                Record::Corrupt { .. } => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} is corrupt at byte {}", path.display(), len),
+                    ));
+                }
+            }
+        }
+        drop(reader);
+
+        let truncated = file_len - len;
+        if truncated > 0 {
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(len))?;
+        Ok((Wal { path, file, len }, Recovered { records, truncated }))
+    }
+
+    /// Appends a record and fsyncs it.
+    pub fn append(&mut self, body: &[u8]) -> io::Result<()> {
+        let record = encode_record(body);
+        if let Err(e) = self
+            .file
+            .write_all(&record)
+            .and_then(|_| self.file.sync_data())
+        {
+            // Drop whatever part of the record made it, so the next append
+            // does not land behind garbage.
+            let _ = self.file.set_len(self.len);
+            let _ = self.file.seek(SeekFrom::Start(self.len));
+            return Err(e);
+        }
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    /// Atomically replaces the whole log with `records`, e.g. to compact it.
+    pub fn rewrite<'a>(&mut self, records: impl IntoIterator<Item = &'a [u8]>) -> io::Result<()> {
+        let data = encode_log(records);
+        write_atomic(&self.path, &data)?;
+        self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.len = data.len() as u64;
+        self.file.seek(SeekFrom::Start(self.len))?;
+        Ok(())
+    }
+
+    /// Bytes in the log, including the magic.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == MAGIC.len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(n: u8) -> Vec<Vec<u8>> {
+        (0..n).map(|i| vec![i; i as usize * 7 + 1]).collect()
+    }
+
+    #[test]
+    fn test_append_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let (mut wal, recovered) = Wal::open(&path).unwrap();
+        assert_eq!(recovered, Recovered::default());
+        assert!(wal.is_empty());
+        for record in records(5) {
+            wal.append(&record).unwrap();
+        }
+        drop(wal);
+
+        let (mut wal, recovered) = Wal::open(&path).unwrap();
+        assert_eq!(recovered.records, records(5));
+        assert_eq!(recovered.truncated, 0);
+
+        wal.rewrite(recovered.records[3..].iter().map(Vec::as_slice))
+            .unwrap();
+        wal.append(b"after").unwrap();
+        let (_, recovered) = Wal::open(&path).unwrap();
+        let mut expected = records(5)[3..].to_vec();
+        expected.push(b"after".to_vec());
+        assert_eq!(recovered.records, expected);
+    }
+
+    #[test]
+    fn test_crash_at_every_byte_of_an_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        for record in records(3) {
+            wal.append(&record).unwrap();
+        }
+        let committed = fs::read(&path).unwrap();
+        wal.append(b"the last record").unwrap();
+        let full = fs::read(&path).unwrap();
+        drop(wal);
+
+        // Every prefix of the file is a state a crash could leave behind,
+        // including one cut inside the magic of a new file.
+        for cut in 0..full.len() {
+            fs::write(&path, &full[..cut]).unwrap();
+            let (mut wal, recovered) = Wal::open(&path).unwrap();
+            let expected = if cut < committed.len() {
+                let mut prefix = MAGIC.len();
+                let mut n = 0;
+                for record in records(3) {
+                    prefix += HEADER_LEN as usize + record.len();
+                    if prefix > cut {
+                        break;
+                    }
+                    n += 1;
+                }
+                records(3)[..n].to_vec()
+            } else {
+                records(3)
+            };
+            assert_eq!(recovered.records, expected, "cut at {}", cut);
+
+            // The log is usable again straight away.
+            wal.append(b"next").unwrap();
+            let (_, recovered) = Wal::open(&path).unwrap();
+            assert_eq!(recovered.records.last().unwrap(), b"next");
+            assert_eq!(recovered.records.len(), expected.len() + 1);
+        }
+    }
+
+    #[test]
+    fn test_bit_flip_in_last_record_is_cut_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        for record in records(3) {
+            wal.append(&record).unwrap();
+        }
+        drop(wal);
+
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0x40;
+        fs::write(&path, &data).unwrap();
+        let (_, recovered) = Wal::open(&path).unwrap();
+        assert_eq!(recovered.records, records(2));
+        assert_eq!(recovered.truncated, HEADER_LEN + 15);
+
+        fs::write(&path, b"[{\"id\":0}]").unwrap();
+        assert_eq!(
+            Wal::open(&path).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_corruption_before_the_tail_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        for record in records(3) {
+            wal.append(&record).unwrap();
+        }
+        drop(wal);
+
+        // Flip a byte of the second record's body.
+        let mut data = fs::read(&path).unwrap();
+        let second = MAGIC.len() + HEADER_LEN as usize + records(3)[0].len();
+        data[second + HEADER_LEN as usize] ^= 0x40;
+        fs::write(&path, &data).unwrap();
+
+        let err = Wal::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(&format!("byte {}", second)));
+        // Nothing was cut off.
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    /// Dies after writing `cut` bytes, or before `step`.
+    struct Crash {
+        cut: Option<usize>,
+        step: &'static str,
+    }
+
+    impl AtomicSteps for Crash {
+        fn write(&mut self, file: &mut File, data: &[u8]) -> io::Result<()> {
+            match self.cut {
+                Some(cut) => {
+                    file.write_all(&data[..cut])?;
+                    Err(io::Error::other("crash"))
+                }
+                None => file.write_all(data),
+            }
+        }
+
+        fn before(&mut self, step: &str) -> io::Result<()> {
+            if step == self.step {
+                Err(io::Error::other("crash"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn test_atomic_write_survives_crash_at_every_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("block.json");
+        write_atomic(&path, b"old contents").unwrap();
+        let new = b"new contents, longer";
+
+        let crashes = (0..=new.len())
+            .map(|cut| Crash {
+                cut: Some(cut),
+                step: "",
+            })
+            .chain(["sync", "rename", "sync_dir"].map(|step| Crash { cut: None, step }));
+        for mut crash in crashes {
+            let result = write_atomic_with(&path, new, &mut crash);
+            assert!(result.is_err());
+            let contents = fs::read(&path).unwrap();
+            let expected: &[u8] = if crash.step == "sync_dir" {
+                new
+            } else {
+                b"old contents"
+            };
+            assert_eq!(
+                contents, expected,
+                "crash at {:?} before {}",
+                crash.cut, crash.step
+            );
+
+            // A leftover temporary file does not get in the way.
+            write_atomic(&path, b"old contents").unwrap();
+        }
+    }
+}
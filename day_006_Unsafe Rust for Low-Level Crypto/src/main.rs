@@ -1,7 +1,19 @@
-use serde::{Serialize, Deserialize};
+use day_005::{
+    Account, Block, ChainSpec, ConsensusParams, Ledger, LimitError, SystemClock, TemplateBuilder,
+    rate_cmp,
+};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::{error::Error, sync::mpsc};
+use std::sync::{Arc, PoisonError};
 use std::thread;
+use std::{env, error::Error, fmt, sync::mpsc};
+
+// Under `--cfg loom` the mempool lock comes from loom, so its tests can
+// explore every interleaving.
+#[cfg(loom)]
+use loom::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(not(loom))]
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 /// SafeNumber wraps a raw pointer to an i32 value.
 struct SafeNumber {
@@ -26,84 +38,294 @@ impl SafeNumber {
 
 impl Drop for SafeNumber {
     fn drop(&mut self) {
-        unsafe { Box::from_raw(self.ptr); }
+        unsafe {
+            drop(Box::from_raw(self.ptr));
+        }
     }
 }
 
-/// Represents a transaction with id, amount, and sender.
+/// Represents a transaction with id, amount, sender, receiver, the fee it
+/// pays and its position in the sender's sequence of transactions.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Transaction {
     id: u32,
     amount: u64,
     sender: String,
+    receiver: String,
+    fee: u64,
+    nonce: u64,
+}
+
+/// Relay fee per 1000 bytes an empty mempool asks for.
+const DEFAULT_RELAY_FEE_PER_KB: u64 = 10;
+
+/// Why the mempool refused or could not find a transaction.
+#[derive(Debug, PartialEq, Eq)]
+enum MempoolError {
+    /// The pool is full and the transaction pays no better rate than the
+    /// cheapest one in it.
+    Full {
+        capacity: usize,
+    },
+    FeeTooLow {
+        required: u64,
+        found: u64,
+    },
+    Limit(LimitError),
+    NotFound(u32),
+    /// A pending transaction from the same sender with the same nonce pays
+    /// at least as good a rate.
+    ReplacementUnderpriced {
+        id: u32,
+    },
+    /// Blocks carry amounts as `u32`, so this one could never be mined.
+    AmountTooLarge {
+        id: u32,
+        amount: u64,
+    },
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::Full { capacity } => {
+                write!(f, "Mempool full ({} transactions)", capacity)
+            }
+            MempoolError::FeeTooLow { required, found } => {
+                write!(f, "Fee {} below the minimum relay fee {}", found, required)
+            }
+            MempoolError::Limit(e) => write!(f, "Transaction rejected: {}", e),
+            MempoolError::NotFound(id) => write!(f, "Transaction {} not found", id),
+            MempoolError::ReplacementUnderpriced { id } => write!(
+                f,
+                "Transaction {} has the same sender and nonce and pays at least as good a rate",
+                id
+            ),
+            MempoolError::AmountTooLarge { id, amount } => {
+                write!(
+                    f,
+                    "Transaction {} amount {} does not fit in a block",
+                    id, amount
+                )
+            }
+        }
+    }
+}
+
+impl Error for MempoolError {}
+
+/// A pending transaction and its serialized size, which sets its fee rate.
+struct Entry {
+    tx: Transaction,
+    bytes: usize,
 }
 
-/// Mempool holds transactions in a raw pointer vector.
+/// Mempool holds pending transactions behind a read-write lock, so it can be
+/// shared across threads or Tokio tasks in an `Arc`. Readers run concurrently
+/// and writers take turns. Lookups hand out copies, which stay valid whatever
+/// later writes do, and no guard ever escapes a method, so none is held across
+/// an `.await`.
+///
+/// Transactions are kept in fee rate order, highest first and oldest first
+/// among equals. A full pool makes room for a better-paying transaction by
+/// evicting the cheapest one together with its sender's later transactions,
+/// which could not be mined without it. A transaction with the same sender
+/// and nonce as a pending one replaces it if it pays a better rate.
 struct Mempool {
-    ptr: *mut Vec<Transaction>,
-    capacity: usize,
+    entries: RwLock<Vec<Entry>>,
+    params: ConsensusParams,
+    relay_fee_per_kb: u64,
 }
 
 impl Mempool {
-    /// Create a new empty mempool with reserved capacity.
-    fn new(capacity: usize) -> Self {
-        let vec = Vec::with_capacity(capacity);
-        let boxed = Box::new(vec);
-        let ptr = Box::into_raw(boxed);
-        Mempool { ptr, capacity }
+    /// Create a new empty mempool holding up to `params.mempool_capacity`
+    /// transactions.
+    fn new(params: ConsensusParams) -> Self {
+        let entries = RwLock::new(Vec::with_capacity(params.mempool_capacity));
+        Mempool {
+            entries,
+            params,
+            relay_fee_per_kb: DEFAULT_RELAY_FEE_PER_KB,
+        }
     }
 
-    /// Add a transaction if capacity allows.
-    fn add_transaction(&mut self, tx: Transaction) -> Result<(), String> {
-        unsafe {
-            if (*self.ptr).len() >= self.capacity {
-                return Err("Mempool full".to_string());
-            }
-            (*self.ptr).push(tx);
-            Ok(())
+    /// Set the relay fee per 1000 bytes asked for while the pool is empty.
+    fn relay_fee_per_kb(mut self, fee: u64) -> Self {
+        self.relay_fee_per_kb = fee;
+        self
+    }
+
+    // Every write leaves the vector whole, even one that panics, so a
+    // poisoned lock is still safe to use.
+    fn read(&self) -> RwLockReadGuard<'_, Vec<Entry>> {
+        self.entries.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Vec<Entry>> {
+        self.entries.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Fee a transaction of `bytes` must pay to enter a pool holding `len`
+    /// transactions. The rate rises linearly from the base rate when the pool
+    /// is empty to four times that when it is full.
+    fn required_fee(&self, bytes: usize, len: usize) -> u64 {
+        let capacity = self.params.mempool_capacity.max(1) as u128;
+        let numerator =
+            self.relay_fee_per_kb as u128 * bytes as u128 * (capacity + 3 * len as u128);
+        numerator.div_ceil(1000 * capacity) as u64
+    }
+
+    /// Minimum relay fee for a transaction of `bytes` right now.
+    fn min_relay_fee(&self, bytes: usize) -> u64 {
+        self.required_fee(bytes, self.len())
+    }
+
+    /// Add a transaction if it is within the size limit and pays the minimum
+    /// relay fee, returning the transactions it pushed out.
+    ///
+    /// A pending transaction with the same sender and nonce is replaced if the
+    /// new one pays a better rate. Otherwise, if the pool is full, the new one
+    /// must pay a better rate than the cheapest transaction, which is evicted
+    /// along with its sender's later ones. A transaction that would only be
+    /// left waiting on an evicted one is refused instead.
+    fn add_transaction(&self, tx: Transaction) -> Result<Vec<Transaction>, MempoolError> {
+        if u32::try_from(tx.amount).is_err() {
+            return Err(MempoolError::AmountTooLarge {
+                id: tx.id,
+                amount: tx.amount,
+            });
+        }
+        let bytes = self
+            .params
+            .check_transaction(&tx)
+            .map_err(MempoolError::Limit)?;
+        let mut entries = self.write();
+        let required = self.required_fee(bytes, entries.len());
+        if tx.fee < required {
+            return Err(MempoolError::FeeTooLow {
+                required,
+                found: tx.fee,
+            });
         }
+        let dropped = if let Some(pos) = entries
+            .iter()
+            .position(|e| e.tx.sender == tx.sender && e.tx.nonce == tx.nonce)
+        {
+            let existing = &entries[pos];
+            if !rate_cmp(tx.fee, bytes, existing.tx.fee, existing.bytes).is_gt() {
+                return Err(MempoolError::ReplacementUnderpriced { id: existing.tx.id });
+            }
+            vec![entries.remove(pos).tx]
+        } else if entries.len() >= self.params.mempool_capacity {
+            match entries.last() {
+                Some(lowest)
+                    if rate_cmp(tx.fee, bytes, lowest.tx.fee, lowest.bytes).is_gt()
+                        && !(lowest.tx.sender == tx.sender && lowest.tx.nonce < tx.nonce) =>
+                {
+                    let (sender, nonce) = (lowest.tx.sender.clone(), lowest.tx.nonce);
+                    let (evicted, kept): (Vec<Entry>, Vec<Entry>) = std::mem::take(&mut *entries)
+                        .into_iter()
+                        .partition(|e| e.tx.sender == sender && e.tx.nonce >= nonce);
+                    *entries = kept;
+                    evicted.into_iter().map(|entry| entry.tx).collect()
+                }
+                _ => {
+                    return Err(MempoolError::Full {
+                        capacity: self.params.mempool_capacity,
+                    });
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        let pos = entries.partition_point(|e| rate_cmp(e.tx.fee, e.bytes, tx.fee, bytes).is_ge());
+        entries.insert(pos, Entry { tx, bytes });
+        Ok(dropped)
     }
 
     /// Remove transaction by id.
-    fn remove_transaction(&mut self, id: u32) -> Result<(), String> {
-        unsafe {
-            if let Some(pos) = (*self.ptr).iter().position(|tx| tx.id == id) {
-                (*self.ptr).remove(pos);
-                Ok(())
-            } else {
-                Err("Transaction not found".to_string())
-            }
+    fn remove_transaction(&self, id: u32) -> Result<(), MempoolError> {
+        let mut entries = self.write();
+        if let Some(pos) = entries.iter().position(|e| e.tx.id == id) {
+            entries.remove(pos);
+            Ok(())
+        } else {
+            Err(MempoolError::NotFound(id))
         }
     }
 
-    /// Serialize transactions with amount < 1000.
+    /// Number of pending transactions.
+    fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    /// Serialize all pending transactions, highest fee rate first.
+    fn serialize(&self) -> Result<String, serde_json::Error> {
+        let entries = self.read();
+        serde_json::to_string_pretty(&entries.iter().map(|e| &e.tx).collect::<Vec<_>>())
+    }
+
+    /// Serialize the transactions that still pass the size limit, e.g. after
+    /// the params were tightened.
     fn serialize_valid(&self) -> Result<String, serde_json::Error> {
-        unsafe {
-            let valid: Vec<Transaction> =
-                (*self.ptr).iter().filter(|tx| tx.amount < 1000).cloned().collect();
-            serde_json::to_string_pretty(&valid)
-        }
+        let valid: Vec<Transaction> = self
+            .read()
+            .iter()
+            .map(|e| &e.tx)
+            .filter(|tx| self.params.check_transaction(tx).is_ok())
+            .cloned()
+            .collect();
+        serde_json::to_string_pretty(&valid)
+    }
+
+    /// Get a copy of the transaction at `index` in fee rate order.
+    fn get_transaction(&self, index: usize) -> Option<Transaction> {
+        self.read().get(index).map(|e| e.tx.clone())
     }
 
-    /// Get a transaction by index.
-    fn get_transaction(&self, index: usize) -> Option<&Transaction> {
-        unsafe { (&*self.ptr).get(index) }
+    /// Pending transactions as block template candidates.
+    fn candidates(&self) -> Vec<day_005::Transaction> {
+        self.read()
+            .iter()
+            .map(|e| day_005::Transaction {
+                id: e.tx.id,
+                amount: u32::try_from(e.tx.amount).expect("checked by add_transaction"),
+                sender: e.tx.sender.clone(),
+                receiver: e.tx.receiver.clone(),
+                fee: e.tx.fee,
+                nonce: e.tx.nonce,
+            })
+            .collect()
     }
 
     /// Compute SHA-256 hash of the mempool.
     fn compute_hash(&self) -> String {
-        unsafe {
-            let serialized = serde_json::to_string(&*self.ptr).expect("Serialization failed");
-            let mut hasher = Sha256::new();
-            hasher.update(serialized);
-            format!("{:x}", hasher.finalize())
-        }
+        let entries = self.read();
+        let serialized = serde_json::to_string(&entries.iter().map(|e| &e.tx).collect::<Vec<_>>())
+            .expect("Serialization failed");
+        let mut hasher = Sha256::new();
+        hasher.update(serialized);
+        format!("{:x}", hasher.finalize())
     }
 }
 
-impl Drop for Mempool {
-    fn drop(&mut self) {
-        unsafe { Box::from_raw(self.ptr); }
+/// Limits from the chain spec given as the first argument, or a small demo
+/// mempool if there is none.
+fn load_params() -> Result<ConsensusParams, Box<dyn Error>> {
+    match env::args().nth(1) {
+        Some(path) => Ok(ChainSpec::from_file(path)?.params),
+        None => Ok(ConsensusParams {
+            mempool_capacity: 3,
+            ..ConsensusParams::default()
+        }),
+    }
+}
+
+/// Relay fee per 1000 bytes from the second argument, or the default.
+fn load_relay_fee() -> Result<u64, Box<dyn Error>> {
+    match env::args().nth(2) {
+        Some(fee) => Ok(fee.parse()?),
+        None => Ok(DEFAULT_RELAY_FEE_PER_KB),
     }
 }
 
@@ -119,31 +341,148 @@ fn main() -> Result<(), Box<dyn Error>> {
     });
 
     // ------------------ Mempool demonstration ------------------
-    let mut mempool = Mempool::new(3);
+    let mempool = Arc::new(Mempool::new(load_params()?).relay_fee_per_kb(load_relay_fee()?));
 
-    let t1 = Transaction { id: 1, amount: 100, sender: "Alice".into() };
-    let t2 = Transaction { id: 2, amount: 200, sender: "Bob".into() };
+    let t1 = Transaction {
+        id: 1,
+        amount: 100,
+        sender: "Alice".into(),
+        receiver: "Bob".into(),
+        fee: 1,
+        nonce: 0,
+    };
+    let t2 = Transaction {
+        id: 2,
+        amount: 200,
+        sender: "Bob".into(),
+        receiver: "Carol".into(),
+        fee: 5,
+        nonce: 0,
+    };
 
     mempool.add_transaction(t1.clone())?;
     mempool.add_transaction(t2.clone())?;
 
+    // Assemble a block on top of a genesis that funded Alice and Bob.
+    let ledger = Ledger::from_accounts([
+        (
+            "Alice".to_string(),
+            Account {
+                balance: 500,
+                nonce: 0,
+            },
+        ),
+        (
+            "Bob".to_string(),
+            Account {
+                balance: 500,
+                nonce: 0,
+            },
+        ),
+    ])
+    .with_subsidy(mempool.params.block_subsidy);
+    let genesis = Block::new(0, 0, vec![], "0".to_string());
+    let template = TemplateBuilder::new("Miner", mempool.params).build(
+        &genesis.header(),
+        &[genesis.timestamp],
+        &ledger,
+        mempool.candidates(),
+        &SystemClock,
+    )?;
+    println!(
+        "Block template ({} fees): {:?}",
+        template.fees, template.block.transactions
+    );
+
     if let Some(tx) = mempool.get_transaction(0) {
         println!("Transaction 0: {:?}", tx);
     }
 
     mempool.remove_transaction(1)?;
-    println!("After removal, transaction 0: {:?}", mempool.get_transaction(0));
+    println!(
+        "After removal, transaction 0: {:?}",
+        mempool.get_transaction(0)
+    );
 
-    println!("Valid transactions serialized:\n{}", mempool.serialize_valid()?);
+    // Submit from several threads at once; each add is checked and applied
+    // under one write lock, so the capacity holds.
+    let submitters: Vec<_> = (4..6)
+        .map(|id| {
+            let mempool = Arc::clone(&mempool);
+            thread::spawn(move || {
+                let tx = Transaction {
+                    id,
+                    amount: 10,
+                    sender: "Carol".into(),
+                    receiver: "Dave".into(),
+                    fee: 4,
+                    nonce: id as u64 - 4,
+                };
+                mempool.add_transaction(tx)
+            })
+        })
+        .collect();
+    for submitter in submitters {
+        submitter.join().expect("submitter panicked")?;
+    }
+    println!("Mempool holds {} transactions", mempool.len());
+
+    // The fuller the pool, the more it asks; once full, a better-paying
+    // transaction pushes out the cheapest one.
+    let cheap = Transaction {
+        id: 6,
+        amount: 10,
+        sender: "Dave".into(),
+        receiver: "Erin".into(),
+        fee: 2,
+        nonce: 0,
+    };
+    let bytes = mempool.params.check_transaction(&cheap)?;
+    println!(
+        "Minimum relay fee for {} bytes: {}",
+        bytes,
+        mempool.min_relay_fee(bytes)
+    );
+    if let Err(e) = mempool.add_transaction(cheap) {
+        println!("{}", e);
+    }
+    let generous = Transaction {
+        id: 7,
+        amount: 10,
+        sender: "Dave".into(),
+        receiver: "Erin".into(),
+        fee: 20,
+        nonce: 0,
+    };
+    for evicted in mempool.add_transaction(generous)? {
+        println!("Evicted to make room: {:?}", evicted);
+    }
+
+    println!(
+        "Valid transactions serialized:\n{}",
+        mempool.serialize_valid()?
+    );
+
+    let oversized = Transaction {
+        id: 3,
+        amount: 1,
+        sender: "x".repeat(2000),
+        receiver: "y".into(),
+        fee: 0,
+        nonce: 0,
+    };
+    if let Err(e) = mempool.add_transaction(oversized) {
+        println!("{}", e);
+    }
 
     // Send a transaction to the validation thread
     if let Some(tx) = mempool.get_transaction(0) {
-        tx_channel.send(tx.clone())?;
+        tx_channel.send(tx)?;
     }
 
     println!("Mempool hash: {}", mempool.compute_hash());
 
-    let serialized = unsafe { serde_json::to_string_pretty(&*mempool.ptr)? };
+    let serialized = mempool.serialize()?;
     println!("Serialized mempool:\n{}", serialized);
 
     let deserialized: Vec<Transaction> = serde_json::from_str(&serialized)?;
@@ -168,3 +507,313 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+fn test_tx(id: u32) -> Transaction {
+    Transaction {
+        id,
+        amount: 1,
+        sender: "Alice".into(),
+        receiver: "Bob".into(),
+        fee: 1,
+        nonce: id as u64,
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    // Miri runs every step, so keep the workload small there.
+    const PER_THREAD: u32 = if cfg!(miri) { 4 } else { 100 };
+
+    // No relay fee, so every test transaction gets in while there is room.
+    fn pool(capacity: usize) -> Arc<Mempool> {
+        let params = ConsensusParams {
+            mempool_capacity: capacity,
+            ..ConsensusParams::default()
+        };
+        Arc::new(Mempool::new(params).relay_fee_per_kb(0))
+    }
+
+    #[test]
+    fn test_concurrent_readers_and_writers() {
+        let mempool = pool(4 * PER_THREAD as usize);
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let mempool = Arc::clone(&mempool);
+                thread::spawn(move || {
+                    for i in 0..PER_THREAD {
+                        let id = t * PER_THREAD + i;
+                        mempool.add_transaction(test_tx(id)).unwrap();
+                        if i % 2 == 1 {
+                            mempool.remove_transaction(id).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let mempool = Arc::clone(&mempool);
+                thread::spawn(move || {
+                    for _ in 0..PER_THREAD {
+                        // Every snapshot is a whole, parseable pool.
+                        let txs: Vec<Transaction> =
+                            serde_json::from_str(&mempool.serialize().unwrap()).unwrap();
+                        assert!(txs.len() <= 4 * PER_THREAD as usize);
+                        assert_eq!(mempool.compute_hash().len(), 64);
+                        if let Some(tx) = mempool.get_transaction(0) {
+                            assert_eq!(tx.id % 2, 0);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(mempool.len(), 2 * PER_THREAD as usize);
+        let mut ids: Vec<u32> = (0..mempool.len())
+            .map(|i| mempool.get_transaction(i).unwrap().id)
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (0..4 * PER_THREAD).step_by(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_capacity_holds_under_contention() {
+        let mempool = pool(10);
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let mempool = Arc::clone(&mempool);
+                thread::spawn(move || {
+                    (0..5)
+                        .filter(|i| mempool.add_transaction(free(t * 5 + i)).is_ok())
+                        .count()
+                })
+            })
+            .collect();
+        let added: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(added, 10);
+        assert_eq!(mempool.len(), 10);
+        // A zero rate never beats another, whatever the sizes.
+        assert_eq!(
+            mempool.add_transaction(free(99)).unwrap_err(),
+            MempoolError::Full { capacity: 10 }
+        );
+    }
+
+    fn free(id: u32) -> Transaction {
+        Transaction {
+            fee: 0,
+            ..test_tx(id)
+        }
+    }
+
+    // One sender per id, so no transaction waits on another.
+    fn paying(id: u32, fee: u64) -> Transaction {
+        Transaction {
+            fee,
+            sender: format!("Sender{}", id),
+            ..test_tx(id)
+        }
+    }
+
+    fn from(id: u32, sender: &str, nonce: u64, fee: u64) -> Transaction {
+        Transaction {
+            sender: sender.into(),
+            nonce,
+            fee,
+            ..test_tx(id)
+        }
+    }
+
+    fn ids(mempool: &Mempool) -> Vec<u32> {
+        (0..mempool.len())
+            .map(|i| mempool.get_transaction(i).unwrap().id)
+            .collect()
+    }
+
+    #[test]
+    fn test_orders_by_fee_rate_and_evicts_cheapest() {
+        let mempool = pool(3);
+        mempool.add_transaction(paying(1, 2)).unwrap();
+        mempool.add_transaction(paying(2, 9)).unwrap();
+        mempool.add_transaction(paying(3, 2)).unwrap();
+        // Highest rate first, then oldest among equals.
+        assert_eq!(ids(&mempool), [2, 1, 3]);
+
+        // A large transaction pays a lower rate than a small one with the same fee.
+        let large = Transaction {
+            receiver: "B".repeat(200),
+            ..paying(4, 9)
+        };
+        let evicted = mempool.add_transaction(large).unwrap();
+        assert_eq!(evicted.iter().map(|tx| tx.id).collect::<Vec<_>>(), [3]);
+        assert_eq!(ids(&mempool), [2, 4, 1]);
+
+        assert_eq!(
+            mempool.add_transaction(paying(5, 2)).unwrap_err(),
+            MempoolError::Full { capacity: 3 }
+        );
+        assert_eq!(mempool.add_transaction(paying(6, 3)).unwrap()[0].id, 1);
+        assert_eq!(ids(&mempool), [2, 6, 4]);
+    }
+
+    #[test]
+    fn test_min_relay_fee_rises_as_pool_fills() {
+        let params = ConsensusParams {
+            mempool_capacity: 4,
+            ..ConsensusParams::default()
+        };
+        let mempool = Mempool::new(params).relay_fee_per_kb(2000);
+        // The fee is part of the transaction, so keep every fee here at three
+        // digits for the size to stay put.
+        let bytes = params.check_transaction(&paying(0, 100)).unwrap();
+        // Two units per byte when empty, eight when full.
+        assert_eq!(mempool.min_relay_fee(bytes), 2 * bytes as u64);
+
+        let mut required = Vec::new();
+        for id in 0..4 {
+            let fee = mempool.min_relay_fee(bytes);
+            assert_eq!(
+                mempool.add_transaction(paying(id, fee - 1)).unwrap_err(),
+                MempoolError::FeeTooLow {
+                    required: fee,
+                    found: fee - 1
+                }
+            );
+            mempool.add_transaction(paying(id, fee)).unwrap();
+            required.push(fee);
+        }
+        assert!(required.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(mempool.min_relay_fee(bytes), 8 * bytes as u64);
+
+        // The newcomer still has to beat the cheapest entry, which paid less.
+        let evicted = mempool
+            .add_transaction(paying(9, 9 * bytes as u64))
+            .unwrap();
+        assert_eq!(evicted[0].id, 0);
+    }
+
+    #[test]
+    fn test_evicts_descendants_and_replaces_by_nonce() {
+        let mempool = pool(3);
+        mempool.add_transaction(from(1, "Alice", 0, 1)).unwrap();
+        mempool.add_transaction(from(2, "Alice", 1, 9)).unwrap();
+        mempool.add_transaction(from(3, "Bob", 0, 3)).unwrap();
+
+        // Alice's second transaction pays well but cannot be mined without her first.
+        let evicted = mempool.add_transaction(from(4, "Carol", 0, 5)).unwrap();
+        assert_eq!(evicted.iter().map(|tx| tx.id).collect::<Vec<_>>(), [2, 1]);
+        assert_eq!(ids(&mempool), [4, 3]);
+
+        // Evicting its own parent would leave the newcomer stuck.
+        mempool.add_transaction(from(5, "Dave", 0, 2)).unwrap();
+        assert_eq!(
+            mempool.add_transaction(from(6, "Dave", 1, 9)).unwrap_err(),
+            MempoolError::Full { capacity: 3 }
+        );
+
+        // Same sender and nonce: a full pool still takes a better-paying replacement.
+        assert_eq!(
+            mempool.add_transaction(from(7, "Bob", 0, 3)).unwrap_err(),
+            MempoolError::ReplacementUnderpriced { id: 3 }
+        );
+        let replaced = mempool.add_transaction(from(8, "Bob", 0, 8)).unwrap();
+        assert_eq!(replaced.iter().map(|tx| tx.id).collect::<Vec<_>>(), [3]);
+        assert_eq!(ids(&mempool), [8, 4, 5]);
+    }
+
+    #[test]
+    fn test_rejects_amounts_blocks_cannot_carry() {
+        let mempool = pool(3);
+        let too_large = Transaction {
+            amount: u32::MAX as u64 + 1,
+            ..test_tx(1)
+        };
+        assert_eq!(
+            mempool.add_transaction(too_large).unwrap_err(),
+            MempoolError::AmountTooLarge {
+                id: 1,
+                amount: u32::MAX as u64 + 1
+            }
+        );
+        let largest = Transaction {
+            amount: u32::MAX as u64,
+            ..test_tx(2)
+        };
+        mempool.add_transaction(largest).unwrap();
+        let candidates = mempool.candidates();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].amount, u32::MAX);
+    }
+
+    #[test]
+    fn test_copies_outlive_removal() {
+        let mempool = pool(3);
+        mempool.add_transaction(test_tx(1)).unwrap();
+        let tx = mempool.get_transaction(0).unwrap();
+        mempool.remove_transaction(1).unwrap();
+        assert_eq!(tx.id, 1);
+        assert_eq!(mempool.get_transaction(0).map(|tx| tx.id), None);
+        assert_eq!(
+            mempool.remove_transaction(1),
+            Err(MempoolError::NotFound(1))
+        );
+    }
+}
+
+/// Run with `RUSTFLAGS="--cfg loom" cargo test --release`.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+
+    // No relay fee, so every test transaction gets in while there is room.
+    fn pool(capacity: usize) -> Arc<Mempool> {
+        let params = ConsensusParams {
+            mempool_capacity: capacity,
+            ..ConsensusParams::default()
+        };
+        Arc::new(Mempool::new(params).relay_fee_per_kb(0))
+    }
+
+    #[test]
+    fn loom_last_slot_goes_to_one_writer() {
+        loom::model(|| {
+            let mempool = pool(1);
+            let handles: Vec<_> = (0..2)
+                .map(|id| {
+                    let mempool = Arc::clone(&mempool);
+                    loom::thread::spawn(move || mempool.add_transaction(test_tx(id)).is_ok())
+                })
+                .collect();
+            let added = handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .filter(|ok| *ok)
+                .count();
+            assert_eq!(added, 1);
+            assert_eq!(mempool.len(), 1);
+        });
+    }
+
+    #[test]
+    fn loom_reader_sees_before_or_after() {
+        loom::model(|| {
+            let mempool = pool(2);
+            mempool.add_transaction(test_tx(1)).unwrap();
+            let writer = {
+                let mempool = Arc::clone(&mempool);
+                loom::thread::spawn(move || mempool.remove_transaction(1).unwrap())
+            };
+            let seen = mempool.get_transaction(0).map(|tx| tx.id);
+            assert!(seen == Some(1) || seen.is_none());
+            writer.join().unwrap();
+            assert_eq!(mempool.len(), 0);
+        });
+    }
+}
@@ -10,6 +10,7 @@
 
 use sha2::{Digest, Sha256};
 use serde::{Serialize, Deserialize};
+use day_005::{ConsensusParams, LimitError};
 
 // -----------------------------------------
 // Block Structure
@@ -30,6 +31,22 @@ pub struct Block {
     pub data: String,
 }
 
+// -----------------------------------------
+// Mining Errors
+// -----------------------------------------
+// Why a block was refused before any hashing was done, or why
+// the search came up empty.
+// -----------------------------------------
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MineError {
+    /// More leading zeros than a SHA-256 hex digest has
+    DifficultyTooHigh(usize),
+    /// The block breaks a consensus limit (e.g. its data payload is too large)
+    Limit(LimitError),
+    /// Every nonce was tried and none met the difficulty
+    NonceSpaceExhausted,
+}
+
 // -----------------------------------------
 // Mining Function
 // -----------------------------------------
@@ -37,20 +54,52 @@ pub struct Block {
 // starting with `difficulty` number of leading zeros.
 // Returns `Some(nonce)` if successful, or `None` if mining fails
 // (due to excessive difficulty or invalid block data).
+// Uses the default consensus limits; see `mine_block_with`.
 // -----------------------------------------
 pub fn mine_block(block: &Block, difficulty: usize) -> Option<u64> {
+    mine_block_with(block, difficulty, &ConsensusParams::default()).ok()
+}
+
+// -----------------------------------------
+// Mining With Explicit Limits
+// -----------------------------------------
+// Same as `mine_block`, but checks the block against the given
+// consensus parameters and reports which limit was exceeded.
+// -----------------------------------------
+pub fn mine_block_with(
+    block: &Block,
+    difficulty: usize,
+    params: &ConsensusParams,
+) -> Result<u64, MineError> {
     // Safety limits:
     // - Prevent extreme difficulty that could hang the miner
     // - Limit data size to prevent unnecessary computation or abuse
-    if difficulty > 64 || block.data.len() > 1000 {
-        return None;
+    if difficulty > 64 {
+        return Err(MineError::DifficultyTooHigh(difficulty));
     }
+    params
+        .check_payload(block.data.as_bytes())
+        .map_err(MineError::Limit)?;
 
     // Target hash prefix (e.g., "0000" for difficulty 4)
     let target = "0".repeat(difficulty);
 
+    search_nonces(block, &target, 0..=u64::MAX)
+}
+
+// -----------------------------------------
+// Nonce Search
+// -----------------------------------------
+// Tries each nonce in `nonces` until the block hash starts
+// with `target`.
+// -----------------------------------------
+fn search_nonces(
+    block: &Block,
+    target: &str,
+    nonces: impl IntoIterator<Item = u64>,
+) -> Result<u64, MineError> {
     // Brute-force search for a valid nonce
-    for nonce in 0..=u64::MAX {
+    for nonce in nonces {
         let mut test_block = block.clone();
         test_block.nonce = nonce;
 
@@ -58,13 +107,14 @@ pub fn mine_block(block: &Block, difficulty: usize) -> Option<u64> {
         let hash = compute_hash(&test_block);
 
         // Check if hash meets the difficulty requirement
-        if hash.starts_with(&target) {
-            return Some(nonce);
+        if hash.starts_with(target) {
+            return Ok(nonce);
         }
     }
 
-    // If no valid nonce is found (theoretically unreachable)
-    None
+    // No valid nonce in the range (for the full u64 range, practically
+    // impossible below difficulty 64)
+    Err(MineError::NonceSpaceExhausted)
 }
 
 // -----------------------------------------
@@ -148,4 +198,41 @@ mod tests {
             "Expected None for oversized data"
         );
     }
+
+    #[test]
+    fn test_mine_block_with_params() {
+        // A tighter payload limit from the chain's consensus parameters
+        let params = ConsensusParams {
+            max_payload_bytes: 4,
+            ..ConsensusParams::default()
+        };
+        let block = Block {
+            id: 1,
+            nonce: 0,
+            data: String::from("tests"),
+        };
+        assert_eq!(
+            mine_block_with(&block, 1, &params),
+            Err(MineError::Limit(LimitError::PayloadTooLarge { bytes: 5, max: 4 }))
+        );
+        assert_eq!(
+            mine_block_with(&block, 65, &params),
+            Err(MineError::DifficultyTooHigh(65))
+        );
+    }
+
+    #[test]
+    fn test_search_reports_exhausted_nonces() {
+        // No hash in a handful of nonces has 64 leading zeros
+        let block = Block {
+            id: 1,
+            nonce: 0,
+            data: String::from("test"),
+        };
+        assert_eq!(
+            search_nonces(&block, &"0".repeat(64), 0..=100),
+            Err(MineError::NonceSpaceExhausted)
+        );
+        assert!(search_nonces(&block, "", 0..=0).is_ok());
+    }
 }
@@ -2,17 +2,18 @@
 //!
 //! - Generates a 32-byte random "private key" using the OS CSPRNG.
 //! - Derives a "public key" by hashing the private key with SHA-256.
-//! - Serializes / deserializes the KeyPair with serde_json.
+//! - Serializes / deserializes the KeyPair with serde_json, in a versioned
+//!   envelope (`day_005::versioned`) so key files from older releases still load.
 //! - Provides `verify()` to re-compute hash(private_key) and compare to stored public key.
 //!
 //! NOTE: Hashing the private key is **not** how real asymmetric public keys are generated.
 //! For real keypairs (able to sign & verify), use an asymmetric scheme like Ed25519 (ed25519-dalek).
 
+use day_005::versioned::{self, Migration, Versioned};
 use rand::rngs::OsRng;
 use rand::RngCore;
 use sha2::{Digest, Sha256};
 use serde::{Deserialize, Serialize};
-use hex;
 
 /// A simple KeyPair struct holding hex-encoded keys.
 ///
@@ -27,6 +28,13 @@ struct KeyPair {
     public_key: String,
 }
 
+/// Only one layout so far; key files saved before versioning are read as
+/// version 1.
+impl Versioned for KeyPair {
+    const KIND: &'static str = "keypair";
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
 impl KeyPair {
     /// Generate a new KeyPair.
     ///
@@ -39,14 +47,14 @@ impl KeyPair {
         rng.fill_bytes(&mut private_key);
 
         // Convert private key bytes to hex string for storage/display.
-        let hex_private = hex::encode(&private_key);
+        let hex_private = hex::encode(private_key);
 
         // Derive a "public key" by hashing the private key with SHA-256.
         // IMPORTANT: This is NOT a proper asymmetric public key derivation.
         let mut hasher = Sha256::new();
-        hasher.update(&private_key);
+        hasher.update(private_key);
         let public_key_bytes = hasher.finalize();
-        let hex_public = hex::encode(&public_key_bytes);
+        let hex_public = hex::encode(public_key_bytes);
 
         KeyPair {
             private_key: hex_private,
@@ -88,12 +96,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("✖ KeyPair verification failed!");
     }
 
-    // Serialize to pretty JSON
-    let serialized = serde_json::to_string_pretty(&key_pair)?;
+    // Serialize to pretty JSON, tagged with the format version
+    let serialized = versioned::encode_pretty(&key_pair)?;
     println!("\nSerialized KeyPair (JSON):\n{}", serialized);
 
-    // Deserialize back from JSON
-    let deserialized: KeyPair = serde_json::from_str(&serialized)?;
+    // Deserialize back from JSON, migrating older formats if needed
+    let deserialized: KeyPair = versioned::decode(serialized.as_bytes())?;
     println!("\nDeserialized KeyPair: {:#?}", deserialized);
 
     // Basic sanity check
@@ -131,4 +139,18 @@ mod tests {
         assert_eq!(kp.private_key, deserialized.private_key);
         assert_eq!(kp.public_key, deserialized.public_key);
     }
+
+    #[test]
+    fn test_golden_files_load() {
+        // Saved before key files carried a version.
+        let old: KeyPair = versioned::decode(include_bytes!("../golden/keypair_unversioned.json"))
+            .expect("Unversioned key file failed to load");
+        assert!(old.verify());
+
+        let golden = include_str!("../golden/keypair_v1.json");
+        let current: KeyPair = versioned::decode(golden.as_bytes()).unwrap();
+        assert_eq!(current.private_key, old.private_key);
+        // Today's encoding must match the newest golden file byte for byte.
+        assert_eq!(versioned::encode_pretty(&current).unwrap(), golden.trim_end());
+    }
 }
@@ -0,0 +1,220 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use crate::{compute_hash, Block, Validator};
+
+/// Outcome of validating one block inside a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockVerdict {
+    /// Passed both the context-free and the contextual checks.
+    Valid,
+    /// Rejected by the context-free validator (PoW, signatures, ...).
+    InvalidBlock,
+    /// Rejected by the contextual validator (linkage, balances, ...).
+    InvalidContext,
+    /// Not checked in context because an earlier block in the batch failed.
+    InvalidAncestor,
+}
+
+/// Checks that depend on the previous block and therefore have to run in chain order.
+///
+/// Takes `&mut self` so implementations can carry state (e.g. balances) from one block
+/// to the next. `parent` is `None` for the first block when the batch has no known parent.
+pub trait ContextValidator {
+    fn validate_in_context(&mut self, block: &Block, parent: Option<&Block>) -> bool;
+}
+
+/// Requires every block to reference the hash of its parent and to have the next id.
+/// Nothing can follow a parent at `u32::MAX`.
+pub struct LinkageValidator;
+
+impl ContextValidator for LinkageValidator {
+    fn validate_in_context(&mut self, block: &Block, parent: Option<&Block>) -> bool {
+        match parent {
+            Some(parent) => {
+                block.prev_hash == compute_hash(parent)
+                    && parent.id.checked_add(1) == Some(block.id)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Validates a slice of blocks in two phases: context-free checks in parallel on a
+/// pool of worker threads, then contextual checks sequentially in input order.
+pub struct BatchValidator<V> {
+    validator: V,
+    workers: usize,
+}
+
+impl<V: Validator + Sync> BatchValidator<V> {
+    /// Uses one worker per available CPU.
+    pub fn new(validator: V) -> Self {
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        BatchValidator { validator, workers }
+    }
+
+    /// Overrides the number of worker threads (at least one is always used).
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Runs only the context-free validator over `blocks` in parallel.
+    /// The returned flags are in input order.
+    pub fn validate_parallel(&self, blocks: &[Block]) -> Vec<bool> {
+        let mut results = vec![false; blocks.len()];
+        if blocks.is_empty() {
+            return results;
+        }
+
+        let next = AtomicUsize::new(0);
+        let (tx, rx) = mpsc::channel::<(usize, bool)>();
+
+        thread::scope(|scope| {
+            for _ in 0..self.workers.min(blocks.len()) {
+                let tx = tx.clone();
+                let next = &next;
+                scope.spawn(move || {
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(block) = blocks.get(index) else { break };
+                        let valid = self.validator.validate(block);
+                        if tx.send((index, valid)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(tx);
+
+            for (index, valid) in rx {
+                results[index] = valid;
+            }
+        });
+
+        results
+    }
+
+    /// Validates `blocks` as a consecutive run of the chain whose first block extends
+    /// `parent`. Returns one verdict per block, in input order.
+    ///
+    /// Once a block fails, every block after it is reported as `InvalidAncestor`
+    /// without being passed to `context`.
+    pub fn validate_batch<C: ContextValidator>(
+        &self,
+        blocks: &[Block],
+        parent: Option<&Block>,
+        context: &mut C,
+    ) -> Vec<BlockVerdict> {
+        let stateless = self.validate_parallel(blocks);
+
+        let mut verdicts = Vec::with_capacity(blocks.len());
+        let mut prev = parent;
+        let mut broken = false;
+
+        for (block, passed) in blocks.iter().zip(stateless) {
+            let verdict = if broken {
+                BlockVerdict::InvalidAncestor
+            } else if !passed {
+                BlockVerdict::InvalidBlock
+            } else if !context.validate_in_context(block, prev) {
+                BlockVerdict::InvalidContext
+            } else {
+                BlockVerdict::Valid
+            };
+
+            broken = verdict != BlockVerdict::Valid;
+            verdicts.push(verdict);
+            prev = Some(block);
+        }
+
+        verdicts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PoWValidator;
+
+    fn mine(id: u32, data: &str, prev_hash: String, difficulty: usize) -> Block {
+        let validator = PoWValidator { difficulty };
+        let mut block = Block { id, nonce: 0, data: data.to_string(), prev_hash };
+        while !validator.validate(&block) {
+            block.nonce += 1;
+        }
+        block
+    }
+
+    fn mine_chain(len: u32) -> Vec<Block> {
+        let mut chain: Vec<Block> = Vec::new();
+        for id in 0..len {
+            let prev_hash = chain.last().map(compute_hash).unwrap_or_default();
+            chain.push(mine(id, &format!("block {}", id), prev_hash, 1));
+        }
+        chain
+    }
+
+    #[test]
+    fn test_parallel_matches_sequential() {
+        let mut blocks = mine_chain(16);
+        blocks[5].nonce += 1;
+        blocks[11].data.push('!');
+
+        let validator = PoWValidator { difficulty: 1 };
+        let expected: Vec<bool> = blocks.iter().map(|b| validator.validate(b)).collect();
+
+        let batch = BatchValidator::new(PoWValidator { difficulty: 1 }).with_workers(4);
+        assert_eq!(batch.validate_parallel(&blocks), expected);
+    }
+
+    #[test]
+    fn test_valid_chain() {
+        let blocks = mine_chain(8);
+        let batch = BatchValidator::new(PoWValidator { difficulty: 1 }).with_workers(3);
+        let verdicts = batch.validate_batch(&blocks, None, &mut LinkageValidator);
+        assert_eq!(verdicts, vec![BlockVerdict::Valid; 8]);
+    }
+
+    #[test]
+    fn test_broken_link_invalidates_descendants() {
+        let mut blocks = mine_chain(5);
+        blocks[2] = mine(2, "block 2", "bogus".to_string(), 1);
+
+        let batch = BatchValidator::new(PoWValidator { difficulty: 1 }).with_workers(2);
+        let verdicts = batch.validate_batch(&blocks, None, &mut LinkageValidator);
+        assert_eq!(
+            verdicts,
+            vec![
+                BlockVerdict::Valid,
+                BlockVerdict::Valid,
+                BlockVerdict::InvalidContext,
+                BlockVerdict::InvalidAncestor,
+                BlockVerdict::InvalidAncestor,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch_extends_parent() {
+        let chain = mine_chain(6);
+        let batch = BatchValidator::new(PoWValidator { difficulty: 1 });
+
+        let verdicts = batch.validate_batch(&chain[3..], Some(&chain[2]), &mut LinkageValidator);
+        assert_eq!(verdicts, vec![BlockVerdict::Valid; 3]);
+
+        let verdicts = batch.validate_batch(&chain[3..], Some(&chain[1]), &mut LinkageValidator);
+        assert_eq!(verdicts[0], BlockVerdict::InvalidContext);
+    }
+
+    #[test]
+    fn test_id_does_not_wrap() {
+        let last = mine(u32::MAX, "last", String::new(), 1);
+        let wrapped = mine(0, "wrapped", compute_hash(&last), 1);
+        let batch = BatchValidator::new(PoWValidator { difficulty: 1 });
+        let verdicts = batch.validate_batch(&[wrapped], Some(&last), &mut LinkageValidator);
+        assert_eq!(verdicts, vec![BlockVerdict::InvalidContext]);
+    }
+}
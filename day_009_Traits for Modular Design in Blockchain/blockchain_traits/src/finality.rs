@@ -0,0 +1,343 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::fork_choice::{BlockTree, ForkChoice};
+
+/// An epoch boundary block that validators vote on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Checkpoint {
+    pub hash: String,
+    pub epoch: u64,
+}
+
+/// A Casper FFG vote: a link from a justified `source` to a later `target`.
+#[derive(Debug, Clone)]
+pub struct CheckpointVote {
+    pub validator: String,
+    pub source: Checkpoint,
+    pub target: Checkpoint,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinalityStatus {
+    /// On the chain of the finalized checkpoint; can never be reverted.
+    Finalized,
+    /// On the chain of the latest justified checkpoint but not yet finalized.
+    Justified,
+    /// Descends from the finalized checkpoint but is not justified yet.
+    Pending,
+    /// Conflicts with the finalized checkpoint and can never become canonical.
+    Orphaned,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VoteError {
+    UnknownValidator(String),
+    UnknownBlock(String),
+    InvalidLink(String),
+    UnjustifiedSource(String),
+    /// The source is not on the chain of the finalized checkpoint.
+    ConflictsWithFinalized(String),
+    DoubleVote(String),
+    SurroundVote(String),
+}
+
+impl fmt::Display for VoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoteError::UnknownValidator(v) => write!(f, "Unknown validator: {}", v),
+            VoteError::UnknownBlock(hash) => write!(f, "Unknown checkpoint block: {}", hash),
+            VoteError::InvalidLink(msg) => write!(f, "Invalid checkpoint link: {}", msg),
+            VoteError::UnjustifiedSource(hash) => write!(f, "Source not justified: {}", hash),
+            VoteError::ConflictsWithFinalized(hash) => {
+                write!(f, "Source {} does not descend from the finalized checkpoint", hash)
+            }
+            VoteError::DoubleVote(v) => write!(f, "Slashable double vote by {}", v),
+            VoteError::SurroundVote(v) => write!(f, "Slashable surround vote by {}", v),
+        }
+    }
+}
+
+impl std::error::Error for VoteError {}
+
+/// Tracks stake-weighted checkpoint votes and the resulting justified and
+/// finalized checkpoints, Casper FFG style.
+///
+/// A checkpoint is justified once validators holding at least 2/3 of the stake
+/// vote for a link to it from a justified source. A justified checkpoint is
+/// finalized when the checkpoint of the very next epoch is justified from it.
+pub struct FinalityGadget {
+    stakes: HashMap<String, u64>,
+    total_stake: u64,
+    votes: HashMap<String, Vec<CheckpointVote>>,
+    link_stake: HashMap<(Checkpoint, Checkpoint), u64>,
+    justified: HashSet<Checkpoint>,
+    latest_justified: Checkpoint,
+    finalized: Checkpoint,
+}
+
+impl FinalityGadget {
+    /// Starts with the genesis checkpoint (epoch 0) justified and finalized.
+    pub fn new(genesis: &str, stakes: HashMap<String, u64>) -> Self {
+        let genesis = Checkpoint { hash: genesis.to_string(), epoch: 0 };
+        FinalityGadget {
+            total_stake: stakes.values().sum(),
+            stakes,
+            votes: HashMap::new(),
+            link_stake: HashMap::new(),
+            justified: HashSet::from([genesis.clone()]),
+            latest_justified: genesis.clone(),
+            finalized: genesis,
+        }
+    }
+
+    pub fn latest_justified(&self) -> &Checkpoint {
+        &self.latest_justified
+    }
+
+    pub fn finalized(&self) -> &Checkpoint {
+        &self.finalized
+    }
+
+    pub fn is_justified(&self, checkpoint: &Checkpoint) -> bool {
+        self.justified.contains(checkpoint)
+    }
+
+    /// Counts a vote, updating justification and finality.
+    ///
+    /// Votes that would be slashable against an earlier vote from the same
+    /// validator, or whose source does not descend from the finalized
+    /// checkpoint, are rejected and not counted.
+    pub fn process_vote(&mut self, tree: &BlockTree, vote: CheckpointVote) -> Result<(), VoteError> {
+        let stake = *self
+            .stakes
+            .get(&vote.validator)
+            .ok_or_else(|| VoteError::UnknownValidator(vote.validator.clone()))?;
+
+        for checkpoint in [&vote.source, &vote.target] {
+            if !tree.contains(&checkpoint.hash) {
+                return Err(VoteError::UnknownBlock(checkpoint.hash.clone()));
+            }
+        }
+        if vote.target.epoch <= vote.source.epoch {
+            return Err(VoteError::InvalidLink("target epoch must follow source epoch".into()));
+        }
+        if !tree.is_ancestor(&vote.source.hash, &vote.target.hash) {
+            return Err(VoteError::InvalidLink("target does not descend from source".into()));
+        }
+        if !self.justified.contains(&vote.source) {
+            return Err(VoteError::UnjustifiedSource(vote.source.hash.clone()));
+        }
+        // Without this, a link from a checkpoint justified before the current
+        // finalized one could justify a conflicting branch, and only the
+        // slashing checks below would stand between it and finality.
+        if !tree.is_ancestor(&self.finalized.hash, &vote.source.hash) {
+            return Err(VoteError::ConflictsWithFinalized(vote.source.hash.clone()));
+        }
+
+        let previous = self.votes.entry(vote.validator.clone()).or_default();
+        for prev in previous.iter() {
+            if prev.source == vote.source && prev.target == vote.target {
+                return Ok(());
+            }
+            if prev.target.epoch == vote.target.epoch {
+                return Err(VoteError::DoubleVote(vote.validator));
+            }
+            let surrounds = |outer: &CheckpointVote, inner: &CheckpointVote| {
+                outer.source.epoch < inner.source.epoch && inner.target.epoch < outer.target.epoch
+            };
+            if surrounds(prev, &vote) || surrounds(&vote, prev) {
+                return Err(VoteError::SurroundVote(vote.validator));
+            }
+        }
+        previous.push(vote.clone());
+
+        let link = (vote.source, vote.target);
+        let tally = self.link_stake.entry(link.clone()).or_default();
+        *tally += stake;
+
+        if *tally as u128 * 3 >= self.total_stake as u128 * 2 {
+            let (source, target) = link;
+            self.justify(target.clone());
+            if target.epoch == source.epoch + 1 && source.epoch >= self.finalized.epoch {
+                self.finalized = source;
+            }
+        }
+        Ok(())
+    }
+
+    fn justify(&mut self, checkpoint: Checkpoint) {
+        if checkpoint.epoch > self.latest_justified.epoch {
+            self.latest_justified = checkpoint.clone();
+        }
+        self.justified.insert(checkpoint);
+    }
+
+    /// Runs `rule` from the finalized checkpoint, so the head can never be on a
+    /// branch that would revert finalized blocks.
+    pub fn choose_head(&self, rule: &impl ForkChoice, tree: &BlockTree) -> String {
+        rule.choose_head(tree, &self.finalized.hash)
+    }
+
+    /// Returns `true` if switching to `head` keeps every finalized block.
+    pub fn allows_head(&self, tree: &BlockTree, head: &str) -> bool {
+        tree.is_ancestor(&self.finalized.hash, head)
+    }
+
+    /// Reports how settled `hash` is, or `None` if the block is unknown.
+    pub fn status(&self, tree: &BlockTree, hash: &str) -> Option<FinalityStatus> {
+        tree.get(hash)?;
+        let status = if tree.is_ancestor(hash, &self.finalized.hash) {
+            FinalityStatus::Finalized
+        } else if !tree.is_ancestor(&self.finalized.hash, hash) {
+            FinalityStatus::Orphaned
+        } else if tree.is_ancestor(hash, &self.latest_justified.hash) {
+            FinalityStatus::Justified
+        } else {
+            FinalityStatus::Pending
+        };
+        Some(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fork_choice::HeaviestChain;
+
+    /// genesis ─ a1 ─ a2 ─ a3
+    ///         └ b1 ─ b2 ─ b3 ─ b4   (heavier)
+    fn setup() -> (BlockTree, FinalityGadget) {
+        let mut tree = BlockTree::new("genesis", 1);
+        tree.insert("a1", "genesis", 1).unwrap();
+        tree.insert("a2", "a1", 1).unwrap();
+        tree.insert("a3", "a2", 1).unwrap();
+        tree.insert("b1", "genesis", 10).unwrap();
+        tree.insert("b2", "b1", 10).unwrap();
+        tree.insert("b3", "b2", 10).unwrap();
+        tree.insert("b4", "b3", 10).unwrap();
+
+        let stakes = HashMap::from([
+            ("v1".to_string(), 40),
+            ("v2".to_string(), 30),
+            ("v3".to_string(), 30),
+        ]);
+        (tree, FinalityGadget::new("genesis", stakes))
+    }
+
+    fn cp(hash: &str, epoch: u64) -> Checkpoint {
+        Checkpoint { hash: hash.to_string(), epoch }
+    }
+
+    fn vote(validator: &str, source: Checkpoint, target: Checkpoint) -> CheckpointVote {
+        CheckpointVote { validator: validator.to_string(), source, target }
+    }
+
+    #[test]
+    fn test_justify_and_finalize() {
+        let (tree, mut gadget) = setup();
+
+        gadget.process_vote(&tree, vote("v1", cp("genesis", 0), cp("a1", 1))).unwrap();
+        assert!(!gadget.is_justified(&cp("a1", 1)));
+        gadget.process_vote(&tree, vote("v2", cp("genesis", 0), cp("a1", 1))).unwrap();
+        assert!(gadget.is_justified(&cp("a1", 1)));
+        // Justifying epoch 1 straight from genesis re-finalizes genesis, nothing newer.
+        assert_eq!(gadget.finalized(), &cp("genesis", 0));
+
+        gadget.process_vote(&tree, vote("v1", cp("a1", 1), cp("a2", 2))).unwrap();
+        gadget.process_vote(&tree, vote("v3", cp("a1", 1), cp("a2", 2))).unwrap();
+        assert_eq!(gadget.latest_justified(), &cp("a2", 2));
+        assert_eq!(gadget.finalized(), &cp("a1", 1));
+    }
+
+    #[test]
+    fn test_fork_choice_respects_finality() {
+        let (tree, mut gadget) = setup();
+        assert_eq!(gadget.choose_head(&HeaviestChain, &tree), "b4");
+
+        for v in ["v1", "v2"] {
+            gadget.process_vote(&tree, vote(v, cp("genesis", 0), cp("a1", 1))).unwrap();
+        }
+        for v in ["v1", "v2"] {
+            gadget.process_vote(&tree, vote(v, cp("a1", 1), cp("a2", 2))).unwrap();
+        }
+        assert_eq!(gadget.finalized(), &cp("a1", 1));
+
+        // The b-branch is heavier but would revert a1.
+        assert_eq!(HeaviestChain.head(&tree), "b4");
+        assert_eq!(gadget.choose_head(&HeaviestChain, &tree), "a3");
+        assert!(!gadget.allows_head(&tree, "b4"));
+        assert!(gadget.allows_head(&tree, "a3"));
+    }
+
+    #[test]
+    fn test_finality_status() {
+        let (tree, mut gadget) = setup();
+        for v in ["v1", "v2"] {
+            gadget.process_vote(&tree, vote(v, cp("genesis", 0), cp("a1", 1))).unwrap();
+        }
+        for v in ["v1", "v2"] {
+            gadget.process_vote(&tree, vote(v, cp("a1", 1), cp("a2", 2))).unwrap();
+        }
+
+        assert_eq!(gadget.status(&tree, "genesis"), Some(FinalityStatus::Finalized));
+        assert_eq!(gadget.status(&tree, "a1"), Some(FinalityStatus::Finalized));
+        assert_eq!(gadget.status(&tree, "a2"), Some(FinalityStatus::Justified));
+        assert_eq!(gadget.status(&tree, "a3"), Some(FinalityStatus::Pending));
+        assert_eq!(gadget.status(&tree, "b2"), Some(FinalityStatus::Orphaned));
+        assert_eq!(gadget.status(&tree, "nope"), None);
+    }
+
+    #[test]
+    fn test_rejects_bad_votes() {
+        let (tree, mut gadget) = setup();
+
+        let err = gadget.process_vote(&tree, vote("mallory", cp("genesis", 0), cp("a1", 1)));
+        assert_eq!(err, Err(VoteError::UnknownValidator("mallory".into())));
+
+        let err = gadget.process_vote(&tree, vote("v1", cp("a1", 1), cp("a2", 2)));
+        assert_eq!(err, Err(VoteError::UnjustifiedSource("a1".into())));
+
+        let err = gadget.process_vote(&tree, vote("v1", cp("genesis", 0), cp("b1", 0)));
+        assert!(matches!(err, Err(VoteError::InvalidLink(_))));
+
+        gadget.process_vote(&tree, vote("v1", cp("genesis", 0), cp("a1", 1))).unwrap();
+        let err = gadget.process_vote(&tree, vote("v1", cp("genesis", 0), cp("b1", 1)));
+        assert_eq!(err, Err(VoteError::DoubleVote("v1".into())));
+    }
+
+    #[test]
+    fn test_rejects_surround_vote() {
+        let (tree, mut gadget) = setup();
+        for v in ["v1", "v2"] {
+            gadget.process_vote(&tree, vote(v, cp("genesis", 0), cp("a1", 1))).unwrap();
+        }
+        gadget.process_vote(&tree, vote("v3", cp("a1", 1), cp("a2", 2))).unwrap();
+
+        let err = gadget.process_vote(&tree, vote("v3", cp("genesis", 0), cp("a3", 3)));
+        assert_eq!(err, Err(VoteError::SurroundVote("v3".into())));
+    }
+
+    #[test]
+    fn test_conflicting_supermajority_cannot_justify() {
+        let (tree, mut gadget) = setup();
+        // Skipping epoch 1 leaves every validator free to vote for it later.
+        for v in ["v1", "v2"] {
+            gadget.process_vote(&tree, vote(v, cp("genesis", 0), cp("a2", 2))).unwrap();
+        }
+        for v in ["v1", "v2"] {
+            gadget.process_vote(&tree, vote(v, cp("a2", 2), cp("a3", 3))).unwrap();
+        }
+        assert_eq!(gadget.finalized(), &cp("a2", 2));
+
+        // All the stake links genesis to b1. None of it is slashable, but
+        // genesis is no longer a valid source.
+        for v in ["v1", "v2", "v3"] {
+            let err = gadget.process_vote(&tree, vote(v, cp("genesis", 0), cp("b1", 1)));
+            assert_eq!(err, Err(VoteError::ConflictsWithFinalized("genesis".into())));
+        }
+        assert!(!gadget.is_justified(&cp("b1", 1)));
+        assert_eq!(gadget.finalized(), &cp("a2", 2));
+        assert_eq!(gadget.status(&tree, "b1"), Some(FinalityStatus::Orphaned));
+    }
+}
@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+
+use crate::{Block, compute_hash};
+
+/// A block as seen by the fork-choice rules: its place in the tree and the work it adds.
+#[derive(Debug, Clone)]
+pub struct TreeNode {
+    pub hash: String,
+    pub parent: Option<String>,
+    pub height: u64,
+    pub work: u128,
+    pub cumulative_work: u128,
+}
+
+/// Every known block, including side branches, indexed by hash.
+#[derive(Debug, Clone)]
+pub struct BlockTree {
+    genesis: String,
+    nodes: HashMap<String, TreeNode>,
+    children: HashMap<String, Vec<String>>,
+}
+
+impl BlockTree {
+    /// Starts a tree rooted at the genesis block.
+    pub fn new(genesis: &str, work: u128) -> Self {
+        let node = TreeNode {
+            hash: genesis.to_string(),
+            parent: None,
+            height: 0,
+            work,
+            cumulative_work: work,
+        };
+        BlockTree {
+            genesis: genesis.to_string(),
+            nodes: HashMap::from([(genesis.to_string(), node)]),
+            children: HashMap::new(),
+        }
+    }
+
+    /// Adds a block under an already known parent.
+    pub fn insert(&mut self, hash: &str, parent: &str, work: u128) -> Result<(), String> {
+        if self.nodes.contains_key(hash) {
+            return Err(format!("Block {} already in tree", hash));
+        }
+        let parent_node = self
+            .nodes
+            .get(parent)
+            .ok_or_else(|| format!("Unknown parent {}", parent))?;
+
+        let node = TreeNode {
+            hash: hash.to_string(),
+            parent: Some(parent.to_string()),
+            height: parent_node.height + 1,
+            work,
+            cumulative_work: parent_node.cumulative_work.saturating_add(work),
+        };
+        self.nodes.insert(hash.to_string(), node);
+        self.children
+            .entry(parent.to_string())
+            .or_default()
+            .push(hash.to_string());
+        Ok(())
+    }
+
+    /// Adds a block using its hash and `prev_hash` link.
+    pub fn insert_block(&mut self, block: &Block, work: u128) -> Result<String, String> {
+        let hash = compute_hash(block);
+        self.insert(&hash, &block.prev_hash, work)?;
+        Ok(hash)
+    }
+
+    pub fn genesis(&self) -> &str {
+        &self.genesis
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&TreeNode> {
+        self.nodes.get(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.nodes.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn children(&self, hash: &str) -> &[String] {
+        self.children.get(hash).map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns `true` if `ancestor` is `descendant` or lies on its path to genesis.
+    pub fn is_ancestor(&self, ancestor: &str, descendant: &str) -> bool {
+        let Some(target) = self.nodes.get(ancestor) else {
+            return false;
+        };
+        let mut current = self.nodes.get(descendant);
+        while let Some(node) = current {
+            if node.height < target.height {
+                return false;
+            }
+            if node.hash == ancestor {
+                return true;
+            }
+            current = node.parent.as_deref().and_then(|p| self.nodes.get(p));
+        }
+        false
+    }
+
+    /// Blocks with no children that descend from (or are) `root`.
+    pub fn leaves_from(&self, root: &str) -> Vec<&TreeNode> {
+        let mut leaves = Vec::new();
+        let mut stack = vec![root];
+        while let Some(hash) = stack.pop() {
+            let Some(node) = self.nodes.get(hash) else {
+                continue;
+            };
+            let children = self.children(hash);
+            if children.is_empty() {
+                leaves.push(node);
+            }
+            stack.extend(children.iter().map(String::as_str));
+        }
+        leaves
+    }
+
+    /// Hashes from `root` down to `head`, inclusive, or `None` if `head` is not below `root`.
+    pub fn path(&self, root: &str, head: &str) -> Option<Vec<String>> {
+        let mut path = Vec::new();
+        let mut current = self.nodes.get(head)?;
+        loop {
+            path.push(current.hash.clone());
+            if current.hash == root {
+                path.reverse();
+                return Some(path);
+            }
+            current = self.nodes.get(current.parent.as_deref()?)?;
+        }
+    }
+}
+
+/// Expected work for a PoW block whose hash needs `difficulty` leading hex zeros.
+pub fn work_for_difficulty(difficulty: usize) -> u128 {
+    16u128.saturating_pow(difficulty as u32)
+}
+
+/// A rule that picks the canonical head among competing branches.
+pub trait ForkChoice {
+    /// Picks the head among the blocks descending from `root`.
+    fn choose_head(&self, tree: &BlockTree, root: &str) -> String;
+
+    /// Picks the head of the whole tree.
+    fn head(&self, tree: &BlockTree) -> String {
+        self.choose_head(tree, tree.genesis())
+    }
+}
+
+/// Ties are broken towards the lexicographically smaller hash so every node agrees.
+fn better(candidate: (u128, &str), best: (u128, &str)) -> bool {
+    candidate.0 > best.0 || (candidate.0 == best.0 && candidate.1 < best.1)
+}
+
+fn best_leaf(tree: &BlockTree, root: &str, score: impl Fn(&TreeNode) -> u128) -> String {
+    let mut best: Option<&TreeNode> = None;
+    for leaf in tree.leaves_from(root) {
+        best = match best {
+            Some(b) if !better((score(leaf), &leaf.hash), (score(b), &b.hash)) => Some(b),
+            _ => Some(leaf),
+        };
+    }
+    best.map_or_else(|| root.to_string(), |node| node.hash.clone())
+}
+
+/// Follows the branch with the most blocks.
+pub struct LongestChain;
+
+impl ForkChoice for LongestChain {
+    fn choose_head(&self, tree: &BlockTree, root: &str) -> String {
+        best_leaf(tree, root, |node| node.height as u128)
+    }
+}
+
+/// Follows the branch with the most cumulative work.
+pub struct HeaviestChain;
+
+impl ForkChoice for HeaviestChain {
+    fn choose_head(&self, tree: &BlockTree, root: &str) -> String {
+        best_leaf(tree, root, |node| node.cumulative_work)
+    }
+}
+
+/// Greedy Heaviest-Observed Sub-Tree: at each fork, descend into the child whose
+/// subtree has the most weight.
+fn ghost_walk(tree: &BlockTree, root: &str, weight: impl Fn(&str) -> u128) -> String {
+    let mut head = root.to_string();
+    loop {
+        let mut best: Option<(u128, &str)> = None;
+        for child in tree.children(&head) {
+            let candidate = (weight(child), child.as_str());
+            if best.is_none_or(|b| better(candidate, b)) {
+                best = Some(candidate);
+            }
+        }
+        match best {
+            Some((_, child)) => head = child.to_string(),
+            None => return head,
+        }
+    }
+}
+
+/// Weight of every subtree under `root`, in one pass: a block's parent comes
+/// before it in pre-order, so summing in reverse pre-order finishes each
+/// subtree before the block above it.
+fn subtree_weights(
+    tree: &BlockTree,
+    root: &str,
+    own: impl Fn(&str) -> u128,
+) -> HashMap<String, u128> {
+    let mut order = Vec::new();
+    let mut stack = vec![root];
+    while let Some(hash) = stack.pop() {
+        order.push(hash);
+        stack.extend(tree.children(hash).iter().map(String::as_str));
+    }
+    let mut weights = HashMap::with_capacity(order.len());
+    for hash in order.into_iter().rev() {
+        let below: u128 = tree.children(hash).iter().map(|child| weights[child]).sum();
+        weights.insert(hash.to_string(), own(hash) + below);
+    }
+    weights
+}
+
+/// GHOST weighted by the number of blocks in each subtree, so uncle blocks still count.
+pub struct Ghost;
+
+impl ForkChoice for Ghost {
+    fn choose_head(&self, tree: &BlockTree, root: &str) -> String {
+        let weights = subtree_weights(tree, root, |_| 1);
+        ghost_walk(tree, root, |hash| weights[hash])
+    }
+}
+
+/// Latest-Message-Driven GHOST: each validator's most recent vote adds its stake
+/// to the voted block and all of its ancestors.
+#[derive(Debug, Default)]
+pub struct LmdGhost {
+    latest: HashMap<String, Vote>,
+}
+
+#[derive(Debug, Clone)]
+struct Vote {
+    block: String,
+    slot: u64,
+    stake: u64,
+}
+
+impl LmdGhost {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a vote, replacing the validator's previous vote unless it is from a later slot.
+    pub fn on_vote(&mut self, validator: &str, block: &str, slot: u64, stake: u64) {
+        let vote = Vote {
+            block: block.to_string(),
+            slot,
+            stake,
+        };
+        match self.latest.get(validator) {
+            Some(prev) if prev.slot >= slot => {}
+            _ => {
+                self.latest.insert(validator.to_string(), vote);
+            }
+        }
+    }
+
+    fn stake_on(&self) -> HashMap<&str, u128> {
+        let mut stake: HashMap<&str, u128> = HashMap::new();
+        for vote in self.latest.values() {
+            *stake.entry(vote.block.as_str()).or_default() += vote.stake as u128;
+        }
+        stake
+    }
+}
+
+impl ForkChoice for LmdGhost {
+    fn choose_head(&self, tree: &BlockTree, root: &str) -> String {
+        let stake = self.stake_on();
+        let own = |hash: &str| stake.get(hash).copied().unwrap_or(0);
+        let weights = subtree_weights(tree, root, own);
+        ghost_walk(tree, root, |hash| weights[hash])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// genesis ─ a1 ─ a2 ─ a3 ─ a4        (long, light)
+    ///         └ b1 ─ b2                  (short, heavy)
+    ///              └ c2 ─ c3             (bushy: b1 has the largest subtree)
+    fn forked_tree() -> BlockTree {
+        let mut tree = BlockTree::new("genesis", 1);
+        tree.insert("a1", "genesis", 1).unwrap();
+        tree.insert("a2", "a1", 1).unwrap();
+        tree.insert("a3", "a2", 1).unwrap();
+        tree.insert("a4", "a3", 1).unwrap();
+        tree.insert("b1", "genesis", 5).unwrap();
+        tree.insert("b2", "b1", 5).unwrap();
+        tree.insert("c2", "b1", 1).unwrap();
+        tree.insert("c3", "c2", 1).unwrap();
+        tree
+    }
+
+    #[test]
+    fn test_longest_chain() {
+        let tree = forked_tree();
+        assert_eq!(LongestChain.head(&tree), "a4");
+    }
+
+    #[test]
+    fn test_heaviest_chain() {
+        let tree = forked_tree();
+        assert_eq!(HeaviestChain.head(&tree), "b2");
+    }
+
+    #[test]
+    fn test_cumulative_work_saturates() {
+        let mut tree = BlockTree::new("genesis", work_for_difficulty(32));
+        assert_eq!(work_for_difficulty(32), u128::MAX);
+        tree.insert("a1", "genesis", work_for_difficulty(32))
+            .unwrap();
+        assert_eq!(tree.get("a1").unwrap().cumulative_work, u128::MAX);
+        assert_eq!(HeaviestChain.head(&tree), "a1");
+    }
+
+    #[test]
+    fn test_ghost_prefers_largest_subtree() {
+        let tree = forked_tree();
+        // Both subtrees under genesis hold 4 blocks, so the tie goes to "a1".
+        assert_eq!(Ghost.head(&tree), "a4");
+
+        // One more uncle under b1 outweighs the longer a-branch.
+        let mut tree = tree;
+        tree.insert("d2", "b1", 1).unwrap();
+        assert_eq!(Ghost.head(&tree), "c3");
+    }
+
+    #[test]
+    fn test_ghost_handles_deep_chains() {
+        let mut tree = BlockTree::new("genesis", 1);
+        let mut parent = "genesis".to_string();
+        for i in 0..100_000 {
+            let hash = format!("b{}", i);
+            tree.insert(&hash, &parent, 1).unwrap();
+            parent = hash;
+        }
+        assert_eq!(Ghost.head(&tree), parent);
+    }
+
+    #[test]
+    fn test_lmd_ghost_follows_latest_votes() {
+        let tree = forked_tree();
+        let mut rule = LmdGhost::new();
+        rule.on_vote("v1", "a4", 1, 10);
+        rule.on_vote("v2", "b2", 1, 6);
+        rule.on_vote("v3", "c3", 1, 6);
+        // b1 subtree carries 12 stake against a1's 10, and b2 ties c3 on 6.
+        assert_eq!(rule.head(&tree), "b2");
+
+        // v1 switches to c3, stale votes from earlier slots are ignored.
+        rule.on_vote("v1", "c3", 2, 10);
+        rule.on_vote("v1", "a4", 1, 10);
+        assert_eq!(rule.head(&tree), "c3");
+    }
+
+    #[test]
+    fn test_choose_head_from_root() {
+        let tree = forked_tree();
+        assert_eq!(LongestChain.choose_head(&tree, "b1"), "c3");
+        assert_eq!(HeaviestChain.choose_head(&tree, "a2"), "a4");
+    }
+
+    #[test]
+    fn test_tree_from_blocks() {
+        let genesis = Block {
+            id: 0,
+            nonce: 0,
+            data: "genesis".into(),
+            prev_hash: String::new(),
+        };
+        let genesis_hash = compute_hash(&genesis);
+        let mut tree = BlockTree::new(&genesis_hash, work_for_difficulty(1));
+
+        let block = Block {
+            id: 1,
+            nonce: 0,
+            data: "one".into(),
+            prev_hash: genesis_hash.clone(),
+        };
+        let hash = tree.insert_block(&block, work_for_difficulty(1)).unwrap();
+        assert_eq!(tree.get(&hash).unwrap().cumulative_work, 32);
+        assert!(tree.is_ancestor(&genesis_hash, &hash));
+        assert_eq!(
+            tree.path(&genesis_hash, &hash).unwrap(),
+            vec![genesis_hash.clone(), hash.clone()]
+        );
+
+        let orphan = Block {
+            id: 1,
+            nonce: 0,
+            data: "orphan".into(),
+            prev_hash: "missing".into(),
+        };
+        assert!(tree.insert_block(&orphan, 1).is_err());
+    }
+}
@@ -1,11 +1,23 @@
 use serde::{Serialize, Deserialize};
 use sha2::{Digest, Sha256};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+pub mod batch;
+pub mod finality;
+pub mod fork_choice;
+pub mod simulator;
+
+pub use batch::{BatchValidator, BlockVerdict, ContextValidator, LinkageValidator};
+pub use finality::{Checkpoint, CheckpointVote, FinalityGadget, FinalityStatus, VoteError};
+pub use fork_choice::{BlockTree, ForkChoice, Ghost, HeaviestChain, LmdGhost, LongestChain};
+pub use simulator::{SimConfig, SimError, Simulator, TraceEvent};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Block {
     pub id: u32,
     pub nonce: u64,
     pub data: String,
+    #[serde(default)]
+    pub prev_hash: String,
 }
 
 pub trait Validator {
@@ -29,7 +41,7 @@ pub struct PoSValidator {
 
 impl Validator for PoSValidator {
     fn validate(&self, block: &Block) -> bool {
-        block.data.parse::<u64>().map_or(false, |stake| stake >= self.min_stake)
+        block.data.parse::<u64>().is_ok_and(|stake| stake >= self.min_stake)
     }
 }
 
@@ -50,18 +62,20 @@ mod tests {
             id: 1,
             nonce: 10,
             data: String::from("test"),
+            prev_hash: String::new(),
         };
         let validator = PoWValidator { difficulty: 1 };
         let hash = compute_hash(&block);
         assert_eq!(validator.validate(&block), hash.starts_with("0"));
     }
-dfh 
+
     #[test]
     fn test_pos_validator() {
         let block = Block {
             id: 1,
             nonce: 0,
             data: String::from("1000"),
+            prev_hash: String::new(),
         };
         let validator = PoSValidator { min_stake: 500 };
         assert!(validator.validate(&block));
@@ -70,6 +84,7 @@ dfh
             id: 2,
             nonce: 0,
             data: String::from("100"),
+            prev_hash: String::new(),
         };
         assert!(!validator.validate(&invalid_block));
     }
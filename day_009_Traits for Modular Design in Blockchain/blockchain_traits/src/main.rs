@@ -5,6 +5,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         id: 1,
         nonce: 10,
         data: String::from("1000"),
+        prev_hash: String::new(),
     };
 
     let pow_validator = PoWValidator { difficulty: 1 };
@@ -0,0 +1,683 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::batch::{ContextValidator, LinkageValidator};
+use crate::fork_choice::{BlockTree, ForkChoice};
+use crate::{Block, Validator, compute_hash};
+
+/// Gives up sealing a proposal after this many nonces.
+const MAX_SEAL_ATTEMPTS: u64 = 1_000_000;
+
+/// Blocks a node holds while waiting for their parent; the oldest is dropped
+/// to make room, so a peer sending unconnectable blocks cannot grow it.
+const MAX_ORPHANS: usize = 64;
+
+/// Network and timing parameters of a simulation. Times are in simulated ticks.
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub nodes: usize,
+    pub seed: u64,
+    pub min_latency: u64,
+    pub max_latency: u64,
+    /// Probability in `[0, 1]` that any single message is lost.
+    pub drop_rate: f64,
+    /// A random node proposes a block every `block_interval` ticks.
+    pub block_interval: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            nodes: 4,
+            seed: 0,
+            min_latency: 1,
+            max_latency: 10,
+            drop_rate: 0.0,
+            block_interval: 20,
+        }
+    }
+}
+
+impl SimConfig {
+    /// Rejects settings the simulator cannot run with.
+    pub fn validate(&self) -> Result<(), SimError> {
+        if self.nodes == 0 {
+            return Err(SimError::NoNodes);
+        }
+        if self.block_interval == 0 {
+            return Err(SimError::ZeroBlockInterval);
+        }
+        if !(0.0..=1.0).contains(&self.drop_rate) {
+            return Err(SimError::DropRate(self.drop_rate));
+        }
+        Ok(())
+    }
+}
+
+/// A simulation that could not be set up as asked.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimError {
+    NoNodes,
+    /// Proposals would all land on the same tick and never let time advance.
+    ZeroBlockInterval,
+    DropRate(f64),
+    /// A partition names a node the simulation does not have.
+    UnknownNode {
+        node: usize,
+        nodes: usize,
+    },
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SimError::NoNodes => write!(f, "Simulation needs at least one node"),
+            SimError::ZeroBlockInterval => write!(f, "Block interval must be at least one tick"),
+            SimError::DropRate(rate) => write!(f, "Drop rate {} is not in [0, 1]", rate),
+            SimError::UnknownNode { node, nodes } => {
+                write!(f, "Unknown node {} in a network of {}", node, nodes)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SimError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Announces a full block.
+    Block(Block),
+    /// Asks a peer for a block we saw referenced but do not have.
+    GetBlock(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    Random,
+    Partition,
+}
+
+/// Everything observable that happened during a run, in simulated-time order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    Proposed {
+        time: u64,
+        node: usize,
+        hash: String,
+        height: u64,
+    },
+    SealFailed {
+        time: u64,
+        node: usize,
+    },
+    Delivered {
+        time: u64,
+        from: usize,
+        to: usize,
+        message: Message,
+    },
+    Dropped {
+        time: u64,
+        from: usize,
+        to: usize,
+        reason: DropReason,
+    },
+    Rejected {
+        time: u64,
+        node: usize,
+        hash: String,
+    },
+    HeadChanged {
+        time: u64,
+        node: usize,
+        head: String,
+        height: u64,
+    },
+    Partitioned {
+        time: u64,
+        groups: Vec<Vec<usize>>,
+    },
+    Healed {
+        time: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Event {
+    Propose,
+    StopProposals,
+    Deliver {
+        from: usize,
+        to: usize,
+        message: Message,
+    },
+    Partition(Vec<Vec<usize>>),
+    Heal,
+}
+
+/// One virtual node: its view of the block tree and the blocks it is still
+/// missing a parent for.
+struct SimNode {
+    tree: BlockTree,
+    blocks: HashMap<String, Block>,
+    orphans: Vec<Block>,
+    head: String,
+}
+
+/// Discrete-event simulator running N nodes on a shared simulated clock.
+///
+/// Nodes validate incoming blocks with `V` plus prev-hash linkage, pick their head
+/// with `F` and gossip every new block to all peers. All randomness comes from the
+/// seed, so the same config always produces the same trace.
+pub struct Simulator<V, F> {
+    config: SimConfig,
+    validator: V,
+    rule: F,
+    rng: StdRng,
+    now: u64,
+    seq: u64,
+    queue: BTreeMap<(u64, u64), Event>,
+    nodes: Vec<SimNode>,
+    groups: Option<Vec<usize>>,
+    proposing: bool,
+    trace: Vec<TraceEvent>,
+}
+
+impl<V: Validator, F: ForkChoice> Simulator<V, F> {
+    pub fn new(config: SimConfig, validator: V, rule: F) -> Result<Self, SimError> {
+        config.validate()?;
+        let genesis = Block {
+            id: 0,
+            nonce: 0,
+            data: "genesis".into(),
+            prev_hash: String::new(),
+        };
+        let genesis_hash = compute_hash(&genesis);
+        let nodes = (0..config.nodes)
+            .map(|_| SimNode {
+                tree: BlockTree::new(&genesis_hash, 1),
+                blocks: HashMap::from([(genesis_hash.clone(), genesis.clone())]),
+                orphans: Vec::new(),
+                head: genesis_hash.clone(),
+            })
+            .collect();
+
+        let mut sim = Simulator {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            validator,
+            rule,
+            now: 0,
+            seq: 0,
+            queue: BTreeMap::new(),
+            nodes,
+            groups: None,
+            proposing: true,
+            trace: Vec::new(),
+        };
+        let first = sim.config.block_interval;
+        sim.schedule(first, Event::Propose);
+        Ok(sim)
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn trace(&self) -> &[TraceEvent] {
+        &self.trace
+    }
+
+    /// `None` if the simulation has no node `node`; likewise below.
+    pub fn head(&self, node: usize) -> Option<&str> {
+        self.nodes.get(node).map(|n| n.head.as_str())
+    }
+
+    pub fn height(&self, node: usize) -> Option<u64> {
+        let node = self.nodes.get(node)?;
+        Some(node.tree.get(&node.head).map_or(0, |n| n.height))
+    }
+
+    pub fn tree(&self, node: usize) -> Option<&BlockTree> {
+        self.nodes.get(node).map(|n| &n.tree)
+    }
+
+    /// Returns `true` if every node has the same head.
+    pub fn converged(&self) -> bool {
+        self.nodes.windows(2).all(|w| w[0].head == w[1].head)
+    }
+
+    /// Splits the network at time `at`; messages between groups are lost on arrival.
+    /// Nodes not listed in any group are isolated.
+    pub fn schedule_partition(&mut self, at: u64, groups: Vec<Vec<usize>>) -> Result<(), SimError> {
+        let nodes = self.config.nodes;
+        if let Some(&node) = groups.iter().flatten().find(|&&node| node >= nodes) {
+            return Err(SimError::UnknownNode { node, nodes });
+        }
+        self.schedule(at, Event::Partition(groups));
+        Ok(())
+    }
+
+    pub fn schedule_heal(&mut self, at: u64) {
+        self.schedule(at, Event::Heal);
+    }
+
+    /// No new blocks are proposed from time `at` on, letting in-flight gossip settle.
+    pub fn stop_proposals_at(&mut self, at: u64) {
+        self.schedule(at, Event::StopProposals);
+    }
+
+    /// Processes the next event, advancing the clock to it. Returns `false` when idle.
+    pub fn step(&mut self) -> bool {
+        let Some(((time, _), event)) = self.queue.pop_first() else {
+            return false;
+        };
+        self.now = time;
+        self.handle(event);
+        true
+    }
+
+    /// Processes every event up to and including time `until`, then sets the clock there.
+    pub fn run_until(&mut self, until: u64) {
+        while self
+            .queue
+            .first_key_value()
+            .is_some_and(|(&(time, _), _)| time <= until)
+        {
+            self.step();
+        }
+        self.now = self.now.max(until);
+    }
+
+    fn schedule(&mut self, at: u64, event: Event) {
+        self.queue.insert((at, self.seq), event);
+        self.seq += 1;
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Propose => {
+                if self.proposing {
+                    let node = self.rng.gen_range(0..self.config.nodes);
+                    self.propose(node);
+                    let next = self.now + self.config.block_interval;
+                    self.schedule(next, Event::Propose);
+                }
+            }
+            Event::StopProposals => self.proposing = false,
+            Event::Deliver { from, to, message } => {
+                if !self.reachable(from, to) {
+                    let reason = DropReason::Partition;
+                    self.trace.push(TraceEvent::Dropped {
+                        time: self.now,
+                        from,
+                        to,
+                        reason,
+                    });
+                    return;
+                }
+                self.trace.push(TraceEvent::Delivered {
+                    time: self.now,
+                    from,
+                    to,
+                    message: message.clone(),
+                });
+                self.receive(to, from, message);
+            }
+            Event::Partition(groups) => {
+                let mut assignment = vec![usize::MAX; self.config.nodes];
+                for (group, members) in groups.iter().enumerate() {
+                    for &node in members {
+                        assignment[node] = group;
+                    }
+                }
+                self.groups = Some(assignment);
+                self.trace.push(TraceEvent::Partitioned {
+                    time: self.now,
+                    groups,
+                });
+            }
+            Event::Heal => {
+                self.groups = None;
+                self.trace.push(TraceEvent::Healed { time: self.now });
+            }
+        }
+    }
+
+    fn reachable(&self, from: usize, to: usize) -> bool {
+        match &self.groups {
+            Some(groups) => groups[from] != usize::MAX && groups[from] == groups[to],
+            None => true,
+        }
+    }
+
+    fn send(&mut self, from: usize, to: usize, message: Message) {
+        if self.rng.gen_bool(self.config.drop_rate) {
+            let reason = DropReason::Random;
+            self.trace.push(TraceEvent::Dropped {
+                time: self.now,
+                from,
+                to,
+                reason,
+            });
+            return;
+        }
+        let latency = self.rng.gen_range(
+            self.config.min_latency..=self.config.max_latency.max(self.config.min_latency),
+        );
+        self.schedule(self.now + latency, Event::Deliver { from, to, message });
+    }
+
+    fn broadcast(&mut self, from: usize, block: &Block) {
+        for to in 0..self.config.nodes {
+            if to != from {
+                self.send(from, to, Message::Block(block.clone()));
+            }
+        }
+    }
+
+    fn propose(&mut self, node: usize) {
+        let parent = &self.nodes[node].blocks[&self.nodes[node].head];
+        let mut block = Block {
+            id: parent.id + 1,
+            nonce: 0,
+            data: format!("node {} at {}", node, self.now),
+            prev_hash: self.nodes[node].head.clone(),
+        };
+        while !self.validator.validate(&block) {
+            block.nonce += 1;
+            if block.nonce >= MAX_SEAL_ATTEMPTS {
+                self.trace.push(TraceEvent::SealFailed {
+                    time: self.now,
+                    node,
+                });
+                return;
+            }
+        }
+
+        let hash = compute_hash(&block);
+        let height = self.nodes[node]
+            .tree
+            .get(&block.prev_hash)
+            .map_or(0, |n| n.height)
+            + 1;
+        self.trace.push(TraceEvent::Proposed {
+            time: self.now,
+            node,
+            hash,
+            height,
+        });
+        self.accept(node, block.clone());
+        self.broadcast(node, &block);
+    }
+
+    fn receive(&mut self, node: usize, from: usize, message: Message) {
+        match message {
+            Message::Block(block) => {
+                let hash = compute_hash(&block);
+                if self.nodes[node].blocks.contains_key(&hash) {
+                    return;
+                }
+                if !self.validator.validate(&block) {
+                    self.trace.push(TraceEvent::Rejected {
+                        time: self.now,
+                        node,
+                        hash,
+                    });
+                    return;
+                }
+                if !self.nodes[node].blocks.contains_key(&block.prev_hash) {
+                    let missing = block.prev_hash.clone();
+                    let orphans = &mut self.nodes[node].orphans;
+                    if !orphans.iter().any(|o| compute_hash(o) == hash) {
+                        if orphans.len() == MAX_ORPHANS {
+                            orphans.remove(0);
+                        }
+                        orphans.push(block);
+                    }
+                    self.send(node, from, Message::GetBlock(missing));
+                    return;
+                }
+                if self.accept(node, block.clone()) {
+                    self.broadcast(node, &block);
+                }
+            }
+            Message::GetBlock(hash) => {
+                if let Some(block) = self.nodes[node].blocks.get(&hash).cloned() {
+                    self.send(node, from, Message::Block(block));
+                }
+            }
+        }
+    }
+
+    /// Connects `block` (whose parent is known) and any orphans waiting on it, then
+    /// re-runs fork choice. Returns `false` if the block fails contextual checks.
+    fn accept(&mut self, node: usize, block: Block) -> bool {
+        if !self.connect(node, block) {
+            return false;
+        }
+        loop {
+            let state = &mut self.nodes[node];
+            let ready = state
+                .orphans
+                .iter()
+                .position(|o| state.blocks.contains_key(&o.prev_hash));
+            let Some(index) = ready else { break };
+            let orphan = state.orphans.remove(index);
+            self.connect(node, orphan);
+        }
+
+        let state = &mut self.nodes[node];
+        let head = self.rule.head(&state.tree);
+        if head != state.head {
+            let height = state.tree.get(&head).map_or(0, |n| n.height);
+            state.head = head.clone();
+            self.trace.push(TraceEvent::HeadChanged {
+                time: self.now,
+                node,
+                head,
+                height,
+            });
+        }
+        true
+    }
+
+    fn connect(&mut self, node: usize, block: Block) -> bool {
+        let state = &mut self.nodes[node];
+        let hash = compute_hash(&block);
+        let linked =
+            LinkageValidator.validate_in_context(&block, state.blocks.get(&block.prev_hash));
+
+        // Every block counts as one unit of work; the validator decides what a valid seal is.
+        if !linked || state.tree.insert(&hash, &block.prev_hash, 1).is_err() {
+            self.trace.push(TraceEvent::Rejected {
+                time: self.now,
+                node,
+                hash,
+            });
+            return false;
+        }
+        state.blocks.insert(hash, block);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PoWValidator;
+    use crate::fork_choice::LongestChain;
+
+    fn simulator(config: SimConfig) -> Simulator<PoWValidator, LongestChain> {
+        Simulator::new(config, PoWValidator { difficulty: 1 }, LongestChain).unwrap()
+    }
+
+    #[test]
+    fn test_same_seed_same_trace() {
+        let config = SimConfig {
+            seed: 7,
+            drop_rate: 0.2,
+            ..SimConfig::default()
+        };
+        let mut a = simulator(config.clone());
+        let mut b = simulator(config);
+        a.run_until(500);
+        b.run_until(500);
+        assert_eq!(a.trace(), b.trace());
+
+        let mut c = simulator(SimConfig {
+            seed: 8,
+            drop_rate: 0.2,
+            ..SimConfig::default()
+        });
+        c.run_until(500);
+        assert_ne!(a.trace(), c.trace());
+    }
+
+    #[test]
+    fn test_converges_without_faults() {
+        let mut sim = simulator(SimConfig {
+            seed: 1,
+            ..SimConfig::default()
+        });
+        sim.stop_proposals_at(400);
+        sim.run_until(1000);
+
+        assert!(sim.converged());
+        assert!(sim.height(0).unwrap() >= 15);
+        let proposed = sim
+            .trace()
+            .iter()
+            .filter(|e| matches!(e, TraceEvent::Proposed { .. }))
+            .count();
+        assert_eq!(proposed, 19);
+    }
+
+    #[test]
+    fn test_partition_then_heal() {
+        let mut sim = simulator(SimConfig {
+            nodes: 6,
+            seed: 3,
+            ..SimConfig::default()
+        });
+        sim.schedule_partition(100, vec![vec![0, 1, 2], vec![3, 4, 5]])
+            .unwrap();
+        sim.run_until(600);
+
+        assert_ne!(sim.head(0).unwrap(), sim.head(3).unwrap());
+        assert!(sim.trace().iter().any(|e| matches!(
+            e,
+            TraceEvent::Dropped {
+                reason: DropReason::Partition,
+                ..
+            }
+        )));
+        let left_fork = sim.head(0).unwrap().to_string();
+
+        sim.schedule_heal(600);
+        sim.stop_proposals_at(800);
+        sim.run_until(2000);
+
+        assert!(sim.converged());
+        // The losing side still keeps its fork as a side branch.
+        assert!(sim.tree(3).unwrap().contains(&left_fork));
+    }
+
+    #[test]
+    fn test_lossy_network() {
+        let mut sim = simulator(SimConfig {
+            seed: 5,
+            drop_rate: 1.0,
+            ..SimConfig::default()
+        });
+        sim.stop_proposals_at(100);
+        sim.run_until(200);
+
+        assert!(
+            !sim.trace()
+                .iter()
+                .any(|e| matches!(e, TraceEvent::Delivered { .. }))
+        );
+        // Every node only knows genesis plus the blocks it proposed itself.
+        let proposed = sim
+            .trace()
+            .iter()
+            .filter(|e| matches!(e, TraceEvent::Proposed { .. }))
+            .count();
+        let known: usize = (0..4).map(|node| sim.tree(node).unwrap().len() - 1).sum();
+        assert_eq!(known, proposed);
+    }
+
+    #[test]
+    fn test_rejects_bad_config() {
+        let new = |config| Simulator::new(config, PoWValidator { difficulty: 1 }, LongestChain);
+        let err = |config| new(config).err().unwrap();
+        assert_eq!(
+            err(SimConfig {
+                nodes: 0,
+                ..SimConfig::default()
+            }),
+            SimError::NoNodes
+        );
+        assert_eq!(
+            err(SimConfig {
+                block_interval: 0,
+                ..SimConfig::default()
+            }),
+            SimError::ZeroBlockInterval
+        );
+        assert_eq!(
+            err(SimConfig {
+                drop_rate: 1.5,
+                ..SimConfig::default()
+            }),
+            SimError::DropRate(1.5)
+        );
+        assert!(
+            new(SimConfig {
+                drop_rate: f64::NAN,
+                ..SimConfig::default()
+            })
+            .is_err()
+        );
+
+        let mut sim = simulator(SimConfig::default());
+        assert_eq!(sim.head(4), None);
+        assert_eq!(sim.height(4), None);
+        assert!(sim.tree(4).is_none());
+        assert_eq!(
+            sim.schedule_partition(10, vec![vec![0, 1], vec![2, 4]]),
+            Err(SimError::UnknownNode { node: 4, nodes: 4 })
+        );
+        sim.run_until(100);
+        assert!(
+            !sim.trace()
+                .iter()
+                .any(|e| matches!(e, TraceEvent::Partitioned { .. }))
+        );
+    }
+
+    #[test]
+    fn test_orphans_are_bounded() {
+        let mut sim = simulator(SimConfig::default());
+        let validator = PoWValidator { difficulty: 1 };
+        for i in 0..MAX_ORPHANS as u32 + 10 {
+            let mut block = Block {
+                id: 1,
+                nonce: 0,
+                data: format!("orphan {}", i),
+                prev_hash: format!("missing {}", i),
+            };
+            while !validator.validate(&block) {
+                block.nonce += 1;
+            }
+            sim.receive(1, 0, Message::Block(block));
+        }
+        let orphans = &sim.nodes[1].orphans;
+        assert_eq!(orphans.len(), MAX_ORPHANS);
+        assert_eq!(orphans[0].data, "orphan 10");
+    }
+}